@@ -0,0 +1,265 @@
+use crate::mtimes::{record_tree, set_modified, FileMtime};
+use anyhow::{Context, Error, Result};
+use cargo_ci_precache::{temp_path, DiskUsage};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+const VERSION: u32 = 1;
+
+/// The name of the entry listing everything in an archive. It's always the first entry.
+pub const MANIFEST_NAME: &str = "ci-precache-manifest.json";
+
+// Everything in an archive, so unpacking can check nothing is missing.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    created: String,
+    /// The names of the directories in the archive.
+    roots: Vec<String>,
+    /// path in the archive -> file map.
+    files: BTreeMap<String, FileMtime>,
+}
+
+// Splits a path in an archive into the name of its root and the path relative to that root.
+// Anything which could be unpacked outside of the root is rejected.
+fn split_archive_path(path: &Path) -> Option<(&str, PathBuf)> {
+    let mut components = path.components();
+    let root = match components.next()? {
+        Component::Normal(root) => root.to_str()?,
+        _ => return None,
+    };
+    let mut relative = PathBuf::new();
+    for c in components {
+        match c {
+            Component::Normal(c) => relative.push(c),
+            _ => return None,
+        }
+    }
+    Some((root, relative))
+}
+
+/// Writes every file in the given directories to a zstd compressed tar archive, except anything
+/// `skip` returns true for. Each directory is stored under its name, and the archive starts with a
+/// manifest listing every file along with its length and modification time. Only regular files
+/// are included.
+pub fn pack(
+    roots: &[(&str, &Path)],
+    skip: &dyn Fn(&Path) -> bool,
+    output: &Path,
+    level: i32,
+) -> Result<DiskUsage> {
+    let mut manifest = Manifest {
+        version: VERSION,
+        created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        roots: Vec::new(),
+        files: BTreeMap::new(),
+    };
+    let mut sources = Vec::new();
+    for &(name, root) in roots {
+        if !root.is_dir() {
+            continue;
+        }
+        let mut files = BTreeMap::new();
+        record_tree(root, root, skip, &mut files)
+            .with_context(|| format!("error reading {}", root.display()))?;
+        for (relative, file) in files {
+            let path = relative
+                .split('/')
+                .fold(root.to_path_buf(), |p, c| p.join(c));
+            let name = format!("{}/{}", name, relative);
+            sources.push((path, name.clone()));
+            manifest.files.insert(name, file);
+        }
+        manifest.roots.push(name.into());
+    }
+
+    let tmp = temp_path(output);
+    let write = || -> Result<()> {
+        let file = File::create(&tmp)?;
+        let mut builder = tar::Builder::new(zstd::Encoder::new(file, level)?);
+        let data = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        );
+        header.set_cksum();
+        builder.append_data(&mut header, MANIFEST_NAME, &*data)?;
+        for (path, name) in &sources {
+            builder
+                .append_path_with_name(path, name)
+                .with_context(|| format!("error adding {}", path.display()))?;
+        }
+        builder.into_inner()?.finish()?.sync_all()?;
+        fs::rename(&tmp, output)?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.context(format!("error writing archive {}", output.display())));
+    }
+
+    Ok(DiskUsage {
+        files: manifest.files.len() as u64,
+        bytes: manifest.files.values().map(|f| f.len).sum(),
+    })
+}
+
+/// Unpacks an archive written by `pack` into the given directories, restoring the modification
+/// time of every file. Directories which aren't given are skipped. Fails if anything listed in the
+/// manifest is missing from the archive, e.g. because it was truncated.
+pub fn unpack(archive: &Path, roots: &[(&str, &Path)]) -> Result<DiskUsage> {
+    let context = || format!("error unpacking archive {}", archive.display());
+    let file = File::open(archive).with_context(context)?;
+    let mut tar = tar::Archive::new(zstd::Decoder::new(file).with_context(context)?);
+    let mut entries = tar.entries().with_context(context)?;
+
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry.with_context(context)?;
+            if entry.path().with_context(context)?.as_ref() != Path::new(MANIFEST_NAME) {
+                return Err(Error::msg(format!(
+                    "archive {} doesn't start with a manifest",
+                    archive.display()
+                )));
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).with_context(context)?;
+            serde_json::from_slice(&data)
+                .with_context(|| format!("error parsing the manifest of {}", archive.display()))?
+        }
+        None => {
+            return Err(Error::msg(format!(
+                "archive {} is empty",
+                archive.display()
+            )))
+        }
+    };
+    if manifest.version != VERSION {
+        return Err(Error::msg(format!(
+            "unsupported archive version {} in {}",
+            manifest.version,
+            archive.display()
+        )));
+    }
+
+    let mut unpacked = HashSet::new();
+    let mut usage = DiskUsage::default();
+    for entry in entries {
+        let mut entry = entry.with_context(context)?;
+        let name = entry.path().with_context(context)?.into_owned();
+        let (root, relative) = split_archive_path(&name)
+            .ok_or_else(|| Error::msg(format!("invalid path {} in archive", name.display())))?;
+        let dir = match roots.iter().find(|&&(n, _)| n == root) {
+            Some(&(_, dir)) => dir,
+            None => continue,
+        };
+        let file = name
+            .to_str()
+            .and_then(|name| manifest.files.get_key_value(name))
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "{} isn't listed in the manifest of {}",
+                    name.display(),
+                    archive.display()
+                ))
+            })?;
+        let path = dir.join(relative);
+        let mut extract = || -> Result<()> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            entry.unpack(&path)?;
+            let time = humantime::parse_rfc3339(&file.1.modified)?;
+            set_modified(&path, time)?;
+            Ok(())
+        };
+        extract().with_context(|| format!("error unpacking {}", path.display()))?;
+        unpacked.insert(file.0);
+        usage += DiskUsage {
+            files: 1,
+            bytes: file.1.len,
+        };
+    }
+
+    let missing: Vec<_> = manifest
+        .files
+        .keys()
+        .filter(|name| {
+            let root = split_archive_path(Path::new(name)).map(|(root, _)| root);
+            roots.iter().any(|&(r, _)| Some(r) == root) && !unpacked.contains(name)
+        })
+        .collect();
+    if let Some(first) = missing.first() {
+        return Err(Error::msg(format!(
+            "archive {} is incomplete, {} files are missing including {}",
+            archive.display(),
+            missing.len(),
+            first
+        )));
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pack, unpack};
+    use cargo_ci_precache::test_util::scratch_dir;
+    use std::fs;
+
+    #[test]
+    fn pack_and_unpack() {
+        let dir = scratch_dir("archive_test");
+        let target = dir.join("target");
+        let registry = dir.join("home/registry");
+        fs::create_dir_all(target.join("debug/deps")).unwrap();
+        fs::create_dir_all(registry.join("cache/example.com-0123456789abcdef")).unwrap();
+        fs::write(target.join("debug/deps/live"), "live").unwrap();
+        fs::write(target.join("debug/deps/outdated"), "outdated").unwrap();
+        fs::write(
+            registry.join("cache/example.com-0123456789abcdef/foo-1.0.0.crate"),
+            "crate",
+        )
+        .unwrap();
+
+        let archive = dir.join("cache.tar.zst");
+        let outdated = target.join("debug/deps/outdated");
+        let usage = pack(
+            &[("target", &target), ("registry", &registry)],
+            &|p| p == outdated,
+            &archive,
+            3,
+        )
+        .unwrap();
+        assert_eq!((usage.files, usage.bytes), (2, 9));
+
+        // Unpack somewhere else, skipping the registry.
+        let unpacked = dir.join("unpacked");
+        let usage = unpack(&archive, &[("target", &unpacked)]).unwrap();
+        assert_eq!(usage.files, 1);
+        let live = unpacked.join("debug/deps/live");
+        assert_eq!(fs::read_to_string(&live).unwrap(), "live");
+        assert_eq!(
+            fs::metadata(&live).unwrap().modified().unwrap(),
+            fs::metadata(target.join("debug/deps/live"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+        assert!(!unpacked.join("debug/deps/outdated").exists());
+
+        // A truncated archive is rejected.
+        let data = fs::read(&archive).unwrap();
+        fs::write(&archive, &data[..data.len() - 16]).unwrap();
+        assert!(unpack(&archive, &[("target", &unpacked), ("registry", &registry)]).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Parsing the names cargo gives to items in a target directory, `{name}-{metadata hash}` followed
+//! by any extensions.
+use std::ffi::OsStr;
+
+// Cargo writes metadata hashes as 16 lowercase hex digits.
+const META_HASH_LEN: usize = 16;
+
+/// What an item in a target directory is, from its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    /// A library, e.g. `.rlib`, `.rmeta`, `.so`, `.dylib`, `.dll`, `.a` or `.lib`.
+    Library,
+    /// A dep-info file, `.d`.
+    DepInfo,
+    /// Anything without an extension, or with `.exe`. These are binaries, including build
+    /// scripts, and the unit directories in `.fingerprint` and `build`.
+    Binary,
+    /// Any other extension, e.g. `.pdb` debug info or the `.txt` files rustc writes long type
+    /// names to.
+    Other,
+}
+
+/// The parts of the name of an item in a target directory, e.g.
+/// `libserde_json-0123456789abcdef.rlib`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactName {
+    /// The crate or package name, with `-` replaced by `_` since libraries use `_` while the
+    /// directories in `.fingerprint` and `build` use the package name as is.
+    pub crate_name: String,
+    /// The unit's metadata hash. `None` for items without one, e.g. binaries copied to the
+    /// profile directory.
+    pub meta_hash: Option<String>,
+    pub kind: ArtifactKind,
+}
+
+/// Parses the file name of an item in a target directory. Everything after the first `.` is
+/// treated as extensions, since neither crate names nor hashes can contain one.
+///
+/// The `lib` prefix is only removed from libraries other than windows `.dll` and `.lib` files,
+/// and only the last `-` separated part is taken as the hash if it's 16 lowercase hex digits.
+/// Returns `None` for names which aren't UTF-8 or don't have a crate name.
+///
+/// ```
+/// use cargo_ci_precache::artifact::{parse_artifact_stem, ArtifactKind};
+/// use std::ffi::OsStr;
+///
+/// let name = parse_artifact_stem(OsStr::new("libproc_macro2-0123456789abcdef.rmeta")).unwrap();
+/// assert_eq!(name.crate_name, "proc_macro2");
+/// assert_eq!(name.meta_hash.as_deref(), Some("0123456789abcdef"));
+/// assert_eq!(name.kind, ArtifactKind::Library);
+///
+/// let name = parse_artifact_stem(OsStr::new("cargo-ci-precache")).unwrap();
+/// assert_eq!(name.crate_name, "cargo_ci_precache");
+/// assert_eq!(name.meta_hash, None);
+/// ```
+pub fn parse_artifact_stem(name: &OsStr) -> Option<ArtifactName> {
+    let name = name.to_str()?;
+    let (stem, extension) = match name.split_once('.') {
+        Some((stem, extensions)) => (stem, extensions.rsplit('.').next()),
+        None => (name, None),
+    };
+    let kind = match extension {
+        Some("rlib" | "rmeta" | "so" | "dylib" | "dll" | "a" | "lib") => ArtifactKind::Library,
+        Some("d") => ArtifactKind::DepInfo,
+        None | Some("exe") => ArtifactKind::Binary,
+        Some(_) => ArtifactKind::Other,
+    };
+    let (crate_name, meta_hash) = match stem.rsplit_once('-') {
+        Some((name, hash)) if is_meta_hash(hash) => (name, Some(hash)),
+        _ => (stem, None),
+    };
+    // Libraries are prefixed with `lib`, except for windows `.dll` and `.lib` files, but binaries
+    // and dep-info files aren't.
+    let crate_name = match (kind, extension) {
+        (ArtifactKind::Library, Some("dll" | "lib")) => crate_name,
+        (ArtifactKind::Library, _) => crate_name.strip_prefix("lib").unwrap_or(crate_name),
+        _ => crate_name,
+    };
+    if crate_name.is_empty() {
+        return None;
+    }
+    Some(ArtifactName {
+        crate_name: crate_name.replace('-', "_"),
+        meta_hash: meta_hash.map(String::from),
+        kind,
+    })
+}
+
+/// Gets the metadata hash from the file name of an item in a target directory.
+pub fn artifact_meta_hash(name: &OsStr) -> Option<String> {
+    parse_artifact_stem(name)?.meta_hash
+}
+
+fn is_meta_hash(s: &str) -> bool {
+    s.len() == META_HASH_LEN && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_artifact_stem, ArtifactKind};
+    use std::ffi::OsStr;
+
+    const HASH: &str = "0123456789abcdef";
+
+    fn parse(name: &str) -> Option<(String, Option<String>, ArtifactKind)> {
+        parse_artifact_stem(OsStr::new(name)).map(|a| (a.crate_name, a.meta_hash, a.kind))
+    }
+
+    #[test]
+    fn artifact_names() {
+        // Every crate name is parsed the same with each kind of artifact.
+        for (name, expected) in &[
+            ("cfg_if", "cfg_if"),
+            ("cfg-if", "cfg_if"),
+            ("crossbeam-utils-sys", "crossbeam_utils_sys"),
+            ("a", "a"),
+            ("libc", "libc"),
+            ("x-1", "x_1"),
+        ] {
+            let hash = Some(String::from(HASH));
+            let kinds = &[
+                ("lib", ".rlib", ArtifactKind::Library),
+                ("lib", ".rmeta", ArtifactKind::Library),
+                ("lib", ".so", ArtifactKind::Library),
+                ("", ".dll", ArtifactKind::Library),
+                ("", ".lib", ArtifactKind::Library),
+                ("", ".d", ArtifactKind::DepInfo),
+                ("", "", ArtifactKind::Binary),
+                ("", ".exe", ArtifactKind::Binary),
+                ("", ".pdb", ArtifactKind::Other),
+                ("", ".long-type-1234.txt", ArtifactKind::Other),
+            ];
+            for &(prefix, extension, kind) in kinds {
+                let file = format!("{}{}-{}{}", prefix, name, HASH, extension);
+                assert_eq!(
+                    parse(&file),
+                    Some((String::from(*expected), hash.clone(), kind)),
+                    "{}",
+                    file
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn artifact_hashes() {
+        let binary = |name: &str, hash: Option<&str>| {
+            Some((
+                String::from(name),
+                hash.map(String::from),
+                ArtifactKind::Binary,
+            ))
+        };
+        // Binaries copied to the profile directory don't have a hash.
+        assert_eq!(
+            parse("cargo-ci-precache"),
+            binary("cargo_ci_precache", None)
+        );
+        assert_eq!(
+            parse("build-script-build"),
+            binary("build_script_build", None)
+        );
+        // Only 16 lowercase hex digits are a hash.
+        assert_eq!(
+            parse("tool-0123456789ABCDEF"),
+            binary("tool_0123456789ABCDEF", None)
+        );
+        assert_eq!(
+            parse("tool-0123456789abcde"),
+            binary("tool_0123456789abcde", None)
+        );
+        assert_eq!(
+            parse("tool-0123456789abcdef0"),
+            binary("tool_0123456789abcdef0", None)
+        );
+        assert_eq!(
+            parse("tool-0123456789abcdeg"),
+            binary("tool_0123456789abcdeg", None)
+        );
+        // Only the last part can be the hash.
+        assert_eq!(
+            parse(&format!("my-tool-{}-{}", HASH, HASH)),
+            binary(&format!("my_tool_{}", HASH), Some(HASH))
+        );
+        // The `lib` prefix is only removed from libraries.
+        assert_eq!(
+            parse(&format!("libfoo-{}", HASH)),
+            binary("libfoo", Some(HASH))
+        );
+        assert_eq!(
+            parse(&format!("liblib-{}.rlib", HASH)),
+            Some(("lib".into(), Some(HASH.into()), ArtifactKind::Library))
+        );
+
+        assert_eq!(parse(&format!("-{}", HASH)), None);
+        assert_eq!(parse(&format!("lib-{}.rlib", HASH)), None);
+        assert_eq!(parse(".d"), None);
+        assert_eq!(parse(""), None);
+    }
+}
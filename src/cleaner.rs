@@ -0,0 +1,499 @@
+use crate::{
+    artifact::artifact_meta_hash, clear_registry_dir, clear_registry_src, crate_file_package,
+    describe_item, find_outdated_units, meta::Metadata, paths::PrefixMatcher, skip_recently_used,
+    CacheOptions, ItemKind, Plan, PlanEnvironment, PlanReason,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+// Crate names are compared with `-` and `_` treated the same, since artifacts use `_`.
+fn normalize_crate_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Decides what to delete from the target directory or the global cargo cache. The defaults match
+/// `clear_target` and `clear_cargo_cache`.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let meta = cargo_ci_precache::MetadataCommand::new().exec()?;
+/// cargo_ci_precache::Cleaner::new(meta)
+///     .profile("release")
+///     .keep_crates(["openssl-sys"])
+///     .lenient(true)
+///     .run_target(&mut |path| println!("{}", path.display()))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Cleaner {
+    meta: Metadata,
+    options: CacheOptions,
+    profile: String,
+    keep_crates: HashSet<String>,
+    lenient: bool,
+    include_src: bool,
+    measure: bool,
+}
+impl Cleaner {
+    pub fn new(meta: Metadata) -> Self {
+        Self {
+            meta,
+            options: CacheOptions::default(),
+            profile: "debug".into(),
+            keep_crates: HashSet::new(),
+            lenient: false,
+            include_src: false,
+            measure: true,
+        }
+    }
+
+    /// The directory in the target directory to clean. Defaults to `debug`.
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    /// Never delete anything built from or containing the given crates, whether or not they're
+    /// used. `-` and `_` are treated the same. Git repositories are matched by their name.
+    pub fn keep_crates<I>(mut self, crates: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.keep_crates
+            .extend(crates.into_iter().map(|c| normalize_crate_name(c.as_ref())));
+        self
+    }
+
+    /// Skip units in the target directory whose fingerprint or dep-info files can't be parsed,
+    /// keeping them, rather than failing. Defaults to false.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// The cargo home to clean, and to recognize dependencies from in the target directory.
+    /// Defaults to `home::cargo_home()`, which honors `CARGO_HOME`.
+    pub fn cargo_home(mut self, cargo_home: impl Into<PathBuf>) -> Self {
+        self.options.cargo_home = Some(cargo_home.into());
+        self
+    }
+
+    /// Sets every option for cleaning the global cargo cache, replacing any cargo home already
+    /// set.
+    pub fn cache_options(mut self, options: CacheOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Also clean unpacked sources in ~/.cargo/registry/src, like `clear_registry_src`. Defaults
+    /// to false.
+    pub fn include_src(mut self, include_src: bool) -> Self {
+        self.include_src = include_src;
+        self
+    }
+
+    /// Record the size and modification time of each item in a plan. Without them a plan can't
+    /// be applied with `PlanItem::changed` checks, but planning is faster. Defaults to true.
+    pub fn measure(mut self, measure: bool) -> Self {
+        self.measure = measure;
+        self
+    }
+
+    fn keeps(&self, name: Option<&str>) -> bool {
+        match name {
+            Some(name) => self.keep_crates.contains(&normalize_crate_name(name)),
+            None => false,
+        }
+    }
+
+    /// Finds every item `run_target` would delete, along with why and its size, without deleting
+    /// anything. The plan can be applied with `execute`.
+    pub fn plan_target(&self) -> Result<Plan> {
+        let environment = PlanEnvironment::new(
+            self.options.cargo_home()?,
+            Some(self.meta.target_directory.clone()),
+        );
+        self.plan("target", environment, |found| self.find_target(found))
+    }
+
+    /// Finds every item `run_cargo_cache` would delete, along with its size, without deleting
+    /// anything. The plan can be applied with `execute`.
+    pub fn plan_cargo_cache(&self) -> Result<Plan> {
+        let environment = PlanEnvironment::new(self.options.cargo_home()?, None);
+        // Everything in the cargo cache is deleted because nothing uses it.
+        self.plan("cargo-cache", environment, |found| {
+            self.run_cargo_cache(&mut |path| found(path, PlanReason::Unused))
+        })
+    }
+
+    // The mode is named the same as on the command line so the plan can be applied by it.
+    fn plan(
+        &self,
+        mode: &str,
+        environment: PlanEnvironment,
+        run: impl FnOnce(&mut dyn FnMut(&Path, PlanReason)) -> Result<()>,
+    ) -> Result<Plan> {
+        let mut items = Vec::new();
+        run(&mut |path, reason| items.push((PathBuf::from(path), reason)))?;
+        let mut plan = Plan::new(mode, environment);
+        for (path, reason) in &items {
+            if self.measure {
+                plan.add(path, *reason)?;
+            } else {
+                plan.add_unmeasured(path, *reason);
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Calls delete for every item in the target directory which is no longer used.
+    pub fn run_target(&self, delete: &mut dyn FnMut(&Path)) -> Result<()> {
+        self.find_target(&mut |path, _| delete(path))
+    }
+
+    // Calls found for every item `run_target` deletes, along with why.
+    fn find_target(&self, found: &mut dyn FnMut(&Path, PlanReason)) -> Result<()> {
+        let meta = &self.meta;
+        let mut cargo_home = PrefixMatcher::new(self.options.cargo_home()?);
+
+        let target_dir = meta.target_directory.join(&self.profile);
+        let build_dir = target_dir.join("build");
+        let deps_dir = target_dir.join("deps");
+        let fingerprint_dir = target_dir.join(".fingerprint");
+
+        match target_dir.read_dir() {
+            Ok(iter) => {
+                for item in iter {
+                    let item = item
+                        .with_context(|| format!("error reading dir: {}", target_dir.display()))?;
+                    let path = item.path();
+                    let name = path.file_name().unwrap_or_default();
+                    if !(name == ".cargo-lock"
+                        || name == ".fingerprint"
+                        || name == "build"
+                        || name == "deps")
+                    {
+                        found(&path, PlanReason::ProfileOutput)
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("error reading dir: {}", target_dir.display()))
+            }
+        }
+
+        let (_, mut meta_hashes_to_remove) =
+            find_outdated_units(meta, &mut cargo_home, &target_dir, self.lenient)?;
+        meta_hashes_to_remove.retain(|hash, unit| {
+            let name = unit.package.as_deref().and_then(|p| p.split(' ').next());
+            !meta.live_meta_hashes.contains(hash) && !self.keeps(name)
+        });
+
+        let dirs = [&build_dir, &deps_dir, &fingerprint_dir];
+        for dir in &dirs {
+            for e in dir
+                .read_dir()
+                .with_context(|| format!("error reading dir: {}", dir.display()))?
+            {
+                let path = e
+                    .with_context(|| format!("error reading dir: {}", dir.display()))?
+                    .path();
+                if let Some(unit) = artifact_meta_hash(path.file_name().unwrap_or_default())
+                    .and_then(|hash| meta_hashes_to_remove.get(&hash))
+                {
+                    found(&path, unit.reason.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Calls delete for every item in the global cargo cache not referenced by the metadata.
+    ///
+    /// Notes: Only items in ~/.cargo/registry/cache, ~/.cargo/git/db and ~/.cargo/git/checkouts
+    /// are considered, along with ~/.cargo/registry/src when `include_src` is set.
+    pub fn run_cargo_cache(&self, delete: &mut dyn FnMut(&Path)) -> Result<()> {
+        let meta = &self.meta;
+        let options = &self.options;
+        let cargo_home = options.cargo_home()?;
+        let git_db_dir = cargo_home.join("git").join("db");
+        let git_checkout_dir = cargo_home.join("git").join("checkouts");
+        let registry_cache_dir = cargo_home.join("registry").join("cache");
+
+        // Whole registries are split into their items so only the kept crates are skipped.
+        let mut delete = |path: &Path| {
+            if self.keep_crates.is_empty() {
+                return delete(path);
+            }
+            let info = describe_item(&cargo_home, path);
+            if info.kind == ItemKind::Registry {
+                if let Ok(iter) = path.read_dir() {
+                    for e in iter.filter_map(|e| e.ok()) {
+                        let path = e.path();
+                        if !self.keeps(describe_item(&cargo_home, &path).name.as_deref()) {
+                            delete(&path);
+                        }
+                    }
+                    return;
+                }
+            }
+            if !self.keeps(info.name.as_deref()) {
+                delete(path);
+            }
+        };
+        if self.include_src {
+            clear_registry_src(meta, options, &mut delete)?;
+        }
+        let delete = &mut *skip_recently_used(&cargo_home, options, &mut delete);
+
+        match git_db_dir.read_dir() {
+            Ok(iter) => {
+                for e in iter.filter_map(|e| e.ok()) {
+                    if !meta.packages.uses_git_repo(&e.file_name()) {
+                        delete(&e.path());
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("error reading dir: {}", git_db_dir.display()))
+            }
+        }
+
+        match git_checkout_dir.read_dir() {
+            Ok(iter) => {
+                for e in iter.filter_map(|e| e.ok()) {
+                    let path = e.path();
+                    let repo = e.file_name();
+                    if !meta.packages.uses_git_repo(&repo) {
+                        delete(&path);
+                        continue;
+                    }
+                    for e in path
+                        .read_dir()
+                        .with_context(|| format!("error reading directory {}", path.display()))?
+                        .filter_map(|e| e.ok())
+                    {
+                        if !meta.packages.uses_checkout(&repo, &e.file_name()) {
+                            delete(&e.path());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("error reading dir: {}", git_checkout_dir.display()))
+            }
+        }
+
+        clear_registry_dir(
+            meta,
+            &registry_cache_dir,
+            crate_file_package,
+            options,
+            delete,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cleaner;
+    use crate::test_util::scratch_dir;
+    use crate::{fingerprint::Fingerprint, meta::Metadata, PlanReason};
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    fn keep_crates() {
+        let home = scratch_dir("cleaner_test");
+        let registry = home.join("registry/cache/example.com-0123456789abcdef");
+        fs::create_dir_all(&registry).unwrap();
+        for name in &["openssl-sys-0.9.0", "serde-1.0.0"] {
+            fs::write(registry.join(format!("{}.crate", name)), "").unwrap();
+        }
+
+        let gather = |cleaner: Cleaner| {
+            let mut items = Vec::new();
+            cleaner
+                .cargo_home(&home)
+                .run_cargo_cache(&mut |p| items.push(PathBuf::from(p)))
+                .unwrap();
+            items
+        };
+        // Nothing uses the registry, so it's deleted as a whole.
+        assert_eq!(
+            gather(Cleaner::new(Metadata::default())),
+            [registry.as_path()]
+        );
+        assert_eq!(
+            gather(Cleaner::new(Metadata::default()).keep_crates(["openssl_sys"])),
+            [registry.join("serde-1.0.0.crate")]
+        );
+
+        let plan = Cleaner::new(Metadata::default())
+            .cargo_home(&home)
+            .keep_crates(["openssl-sys"])
+            .plan_cargo_cache()
+            .unwrap();
+        assert_eq!(plan.mode, "cargo-cache");
+        assert_eq!(plan.items.len(), 1);
+        assert_eq!(plan.items[0].path, registry.join("serde-1.0.0.crate"));
+        assert_eq!(plan.items[0].reason, PlanReason::Unused);
+        assert_eq!(plan.items[0].info.name.as_deref(), Some("serde"));
+        // Planning doesn't delete anything.
+        assert!(registry.join("serde-1.0.0.crate").exists());
+
+        // Unpacked sources are unused along with everything else.
+        let src = home.join("registry/src/example.com-0123456789abcdef/serde-1.0.0");
+        fs::create_dir_all(&src).unwrap();
+        let plan = Cleaner::new(Metadata::default())
+            .cargo_home(&home)
+            .keep_crates(["openssl-sys"])
+            .include_src(true)
+            .plan_cargo_cache()
+            .unwrap();
+        let reasons: Vec<_> = plan
+            .items
+            .iter()
+            .map(|item| (item.path.as_path(), item.reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (src.as_path(), PlanReason::Unused),
+                (
+                    registry.join("serde-1.0.0.crate").as_path(),
+                    PlanReason::Unused
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn target_plan_reasons() {
+        let dir = scratch_dir("cleaner_target_test");
+        let home = dir.join("home");
+        let registry = "example.com-0123456789abcdef";
+        let src = home.join("registry/src").join(registry);
+        let debug = dir.join("target/debug");
+        for sub in &["incremental", "examples", "deps", "build", ".fingerprint"] {
+            fs::create_dir_all(debug.join(sub)).unwrap();
+        }
+
+        let mut meta = Metadata {
+            target_directory: dir.join("target"),
+            ..Default::default()
+        };
+        for package in &["serde-1.0.0", "log-0.4.0", "cfg-if-1.0.0"] {
+            let id = format!("{} (registry+https://example.com/index)", package);
+            meta.packages
+                .registry
+                .entry(registry.into())
+                .or_default()
+                .insert(package.into(), id.clone());
+            meta.package_features
+                .insert(id, Some("[]".to_owned()).into_iter().collect());
+        }
+
+        // Each unit has a fingerprint and a `.d` file naming its root source file. The hash is
+        // used as the path so every fingerprint is different.
+        let unit = |name: &str, hash: &str, root: PathBuf, features: &str, deps: &[u64]| {
+            let deps: Vec<_> = deps
+                .iter()
+                .map(|d| format!("[0, \"dep\", false, {}]", d))
+                .collect();
+            let json = format!(
+                "{{\"rustc\": 0, \"features\": {:?}, \"target\": 0, \"profile\": 0, \
+                 \"path\": {}, \"deps\": [{}], \"local\": [], \"rustflags\": [], \
+                 \"metadata\": 0, \"config\": 0}}",
+                features,
+                hash.trim_start_matches('0'),
+                deps.join(", ")
+            );
+            let unit_dir = debug
+                .join(".fingerprint")
+                .join(format!("{}-{}", name, hash));
+            fs::create_dir_all(&unit_dir).unwrap();
+            fs::write(unit_dir.join(format!("lib-{}.json", name)), &json).unwrap();
+            let rlib = debug
+                .join("deps")
+                .join(format!("lib{}-{}.rlib", name, hash));
+            fs::write(&rlib, "").unwrap();
+            fs::write(
+                debug.join("deps").join(format!("{}-{}.d", name, hash)),
+                format!("{}: {}\n", rlib.display(), root.display()),
+            )
+            .unwrap();
+            serde_json::from_str::<Fingerprint>(&json)
+                .unwrap()
+                .get_hash()
+        };
+        let old = unit(
+            "serde",
+            "0000000000000001",
+            src.join("serde-0.9.0/src/lib.rs"),
+            "[]",
+            &[],
+        );
+        unit(
+            "member",
+            "0000000000000002",
+            dir.join("member/src/lib.rs"),
+            "[]",
+            &[],
+        );
+        unit(
+            "log",
+            "0000000000000003",
+            src.join("log-0.4.0/src/lib.rs"),
+            "[\"std\"]",
+            &[],
+        );
+        unit(
+            "serde_json",
+            "0000000000000004",
+            src.join("serde-1.0.0/src/lib.rs"),
+            "[]",
+            &[old],
+        );
+        unit(
+            "cfg_if",
+            "0000000000000005",
+            src.join("cfg-if-1.0.0/src/lib.rs"),
+            "[]",
+            &[],
+        );
+
+        let plan = Cleaner::new(meta).cargo_home(&home).plan_target().unwrap();
+        let reasons = |pattern: &str| -> Vec<_> {
+            plan.items
+                .iter()
+                .filter(|item| item.path.to_string_lossy().contains(pattern))
+                .map(|item| item.reason)
+                .collect()
+        };
+        assert_eq!(reasons("incremental"), [PlanReason::ProfileOutput]);
+        assert_eq!(reasons("examples"), [PlanReason::ProfileOutput]);
+        // The fingerprint, the rlib and the `.d` file of each unit.
+        assert_eq!(reasons("0000000000000001"), [PlanReason::Unused; 3]);
+        assert_eq!(reasons("0000000000000002"), [PlanReason::Local; 3]);
+        assert_eq!(reasons("0000000000000003"), [PlanReason::FeatureChange; 3]);
+        assert_eq!(
+            reasons("0000000000000004"),
+            [PlanReason::DependencyChanged; 3]
+        );
+        assert!(reasons("0000000000000005").is_empty());
+        assert_eq!(plan.items.len(), 14);
+    }
+}
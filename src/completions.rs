@@ -0,0 +1,49 @@
+use clap::{App, Clap};
+use clap_generate::generators::{Bash, Fish, PowerShell, Zsh};
+
+#[derive(Clap, Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+/// Generates a completion script for the given shell from the clap definitions of the app.
+pub fn generate(shell: Shell, mut app: App, bin: &str) -> String {
+    let mut script = Vec::new();
+    let generate = match shell {
+        Shell::Bash => clap_generate::generate::<Bash, _>,
+        Shell::Zsh => clap_generate::generate::<Zsh, _>,
+        Shell::Fish => clap_generate::generate::<Fish, _>,
+        Shell::Powershell => clap_generate::generate::<PowerShell, _>,
+    };
+    generate(&mut app, bin, &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{generate, Shell};
+    use crate::Args;
+    use clap::IntoApp;
+
+    #[test]
+    fn generate_all_shells() {
+        for &shell in &[Shell::Bash, Shell::Zsh, Shell::Fish, Shell::Powershell] {
+            let script = generate(shell, Args::into_app(), "cargo-ci-precache");
+            for expected in &["no-default-features", "filter-platform", "summary-json"] {
+                assert!(
+                    script.contains(expected),
+                    "{:?} completions are missing {}",
+                    shell,
+                    expected
+                );
+            }
+        }
+        // The zsh script describes each subcommand, including the nested ones.
+        let script = generate(Shell::Zsh, Args::into_app(), "cargo-ci-precache");
+        assert!(script.contains("\"cargo-cache:Clears the global cargo cache\""));
+        assert!(script.contains("\"cargo-cache:Plans clearing the global cargo cache\""));
+    }
+}
@@ -0,0 +1,496 @@
+use anyhow::{Context, Error, Result};
+use clap::{App, ArgMatches, ArgSettings, ValueHint};
+use std::{
+    env,
+    ffi::OsString,
+    fmt, fs, mem,
+    path::{Path, PathBuf},
+};
+use toml::Value;
+
+/// The name of the config file looked for next to `Cargo.toml`.
+pub const CONFIG_FILE_NAME: &str = "ci-precache.toml";
+
+/// The prefix of the environment variables which set options, e.g. `CARGO_CI_PRECACHE_MAX_AGE`.
+pub const ENV_PREFIX: &str = "CARGO_CI_PRECACHE_";
+
+// Options which only make sense on the command line.
+const COMMAND_LINE_ONLY: &[&str] = &["config", "show-config", "help", "version"];
+
+/// Where the value of an option came from.
+pub enum Source {
+    CommandLine,
+    Environment(String),
+    File(PathBuf),
+}
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommandLine => f.write_str("command line"),
+            Self::Environment(var) => write!(f, "environment variable {}", var),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// The value of a single option after merging.
+pub struct Setting {
+    pub key: String,
+    pub value: Value,
+    pub source: Source,
+}
+
+/// The command line after merging in the environment and the config file.
+pub struct Merged {
+    pub args: Vec<OsString>,
+    pub settings: Vec<Setting>,
+}
+impl Merged {
+    /// Renders the merged settings as a config file, noting where each value came from.
+    pub fn display(&self) -> String {
+        self.settings
+            .iter()
+            .map(|s| format!("{} = {} # {}\n", s.key, s.value, s.source))
+            .collect()
+    }
+}
+
+// An argument which can be set by the config file or the environment.
+struct Opt<'a> {
+    /// The name used by `ArgMatches`.
+    id: &'a str,
+    /// The name used in the config file, and the long name on the command line.
+    key: &'a str,
+    takes_value: bool,
+    multiple: bool,
+    /// Whether the value is a path, which is relative to the config file when set there.
+    path: bool,
+}
+impl Opt<'_> {
+    fn env_var(&self) -> String {
+        format!(
+            "{}{}",
+            ENV_PREFIX,
+            self.key.replace('-', "_").to_uppercase()
+        )
+    }
+
+    // Converts a value to command line arguments. Relative paths are joined to `base`, if given.
+    fn to_args(&self, value: &Value, base: Option<&Path>, args: &mut Vec<OsString>) -> Result<()> {
+        let value = match (value, self.takes_value) {
+            (Value::Boolean(true), false) => {
+                args.push(format!("--{}", self.key).into());
+                return Ok(());
+            }
+            (Value::Boolean(false), false) => return Ok(()),
+            (_, false) => return Err(Error::msg(format!("`{}` must be a boolean", self.key))),
+            (Value::Array(values), true) if self.multiple => {
+                for value in values {
+                    self.to_args(value, base, args)?;
+                }
+                return Ok(());
+            }
+            (Value::String(s), true) => s.clone(),
+            (Value::Integer(i), true) => i.to_string(),
+            (Value::Float(f), true) => f.to_string(),
+            (_, true) if self.multiple => {
+                return Err(Error::msg(format!(
+                    "`{}` must be a string, a number or an array",
+                    self.key
+                )))
+            }
+            (_, true) => {
+                return Err(Error::msg(format!(
+                    "`{}` must be a string or a number",
+                    self.key
+                )))
+            }
+        };
+        // `-` is stdin, not a file.
+        let value = match base {
+            Some(base) if self.path && value != "-" => base.join(value).into_os_string(),
+            _ => value.into(),
+        };
+        let mut arg = OsString::from(format!("--{}=", self.key));
+        arg.push(value);
+        args.push(arg);
+        Ok(())
+    }
+
+    // Parses the value of an environment variable.
+    fn parse_env(&self, value: &str) -> Result<Value> {
+        if !self.takes_value {
+            return match value {
+                "1" | "true" => Ok(Value::Boolean(true)),
+                "" | "0" | "false" => Ok(Value::Boolean(false)),
+                _ => Err(Error::msg(format!(
+                    "{} must be `true` or `false`",
+                    self.env_var()
+                ))),
+            };
+        }
+        if self.multiple {
+            Ok(Value::Array(
+                value.split(',').map(|v| Value::String(v.into())).collect(),
+            ))
+        } else {
+            Ok(Value::String(value.into()))
+        }
+    }
+
+    // Gets the value given on the command line.
+    fn cli_value(&self, matches: &ArgMatches) -> Option<Value> {
+        if matches.occurrences_of(self.id) == 0 {
+            return None;
+        }
+        if !self.takes_value {
+            return Some(Value::Boolean(true));
+        }
+        let mut values: Vec<_> = matches
+            .values_of_lossy(self.id)?
+            .into_iter()
+            .map(Value::String)
+            .collect();
+        if self.multiple || values.len() > 1 {
+            Some(Value::Array(values))
+        } else {
+            values.pop()
+        }
+    }
+}
+
+// Finds the config file next to `Cargo.toml`, searching upwards from the current directory like
+// cargo does when no manifest path is given.
+fn find_config(manifest_path: Option<&Path>) -> Result<Option<PathBuf>> {
+    let dir = match manifest_path {
+        Some(path) => path.parent().map(PathBuf::from).unwrap_or_default(),
+        None => {
+            let cwd = env::current_dir()?;
+            match cwd.ancestors().find(|dir| dir.join("Cargo.toml").is_file()) {
+                Some(dir) => dir.into(),
+                None => return Ok(None),
+            }
+        }
+    };
+    let path = dir.join(CONFIG_FILE_NAME);
+    Ok(if path.is_file() { Some(path) } else { None })
+}
+
+fn read_config(path: &Path) -> Result<toml::value::Table> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("error reading config file {}", path.display()))?;
+    toml::from_str(&contents)
+        .with_context(|| format!("error parsing config file {}", path.display()))
+}
+
+// Makes every argument of the app and its subcommands optional.
+fn optional_args(mut app: App<'static>) -> App<'static> {
+    // clap only borrows the names from the original app, but needs them to outlive the new one.
+    // There are only a handful, and the arguments are merged once.
+    let required: Vec<&'static str> = app
+        .get_arguments()
+        .filter(|arg| arg.is_set(ArgSettings::Required))
+        .map(|arg| &*Box::leak(Box::<str>::from(arg.get_name())))
+        .collect();
+    for id in required {
+        app = app.mut_arg(id, |a| a.required(false));
+    }
+    for sub in app.get_subcommands_mut() {
+        *sub = optional_args(mem::take(sub));
+    }
+    app
+}
+
+// Lists the config keys of the app's options, and those of its subcommands.
+fn option_keys<'a>(app: &'a App, keys: &mut Vec<&'a str>) {
+    keys.extend(app.get_arguments().filter_map(|arg| arg.get_long()));
+    for sub in app.get_subcommands() {
+        option_keys(sub, keys);
+    }
+}
+
+/// Merges the command line with options set by environment variables and the config file, in
+/// that order of precedence. The config file is given by `--config`, or found next to
+/// `Cargo.toml`. Only options are merged, the subcommand and its positional arguments are always
+/// given on the command line. The config file can set the options of any subcommand, but only
+/// those of the subcommand being run are used.
+pub fn merge(
+    app: App<'static>,
+    cli: Vec<OsString>,
+    env: &dyn Fn(&str) -> Option<OsString>,
+) -> Result<Merged> {
+    // Required options may be set by the config file, so they can't be required yet.
+    let app = optional_args(app);
+    let matches = app
+        .clone()
+        .try_get_matches_from(&cli)
+        .unwrap_or_else(|e| e.exit());
+    // The app and each subcommand down to the one being run, along with their matches.
+    let mut commands = vec![(&app, &matches)];
+    while let Some((name, matches)) = commands[commands.len() - 1].1.subcommand() {
+        match commands[commands.len() - 1].0.find_subcommand(name) {
+            Some(app) => commands.push((app, matches)),
+            None => break,
+        }
+    }
+    let opts: Vec<_> = commands
+        .iter()
+        .flat_map(|&(app, matches)| {
+            app.get_arguments().filter_map(move |arg| {
+                Some((
+                    Opt {
+                        id: arg.get_name(),
+                        key: arg.get_long()?,
+                        takes_value: arg.is_set(ArgSettings::TakesValue),
+                        multiple: arg.is_set(ArgSettings::MultipleOccurrences)
+                            || arg.is_set(ArgSettings::MultipleValues),
+                        path: matches!(
+                            arg.get_value_hint(),
+                            ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+                        ),
+                    },
+                    matches,
+                ))
+            })
+        })
+        .filter(|(opt, _)| !COMMAND_LINE_ONLY.contains(&opt.key))
+        .collect();
+    let env_value = |opt: &Opt| -> Result<Option<Value>> {
+        match env(&opt.env_var()) {
+            Some(value) => match value.into_string() {
+                Ok(value) => opt.parse_env(&value).map(Some),
+                Err(_) => Err(Error::msg(format!("{} isn't valid unicode", opt.env_var()))),
+            },
+            None => Ok(None),
+        }
+    };
+
+    let config_path = match matches.value_of_os("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => match env(&format!("{}CONFIG", ENV_PREFIX)) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => {
+                let manifest_path = match commands
+                    .iter()
+                    .find_map(|(_, matches)| matches.value_of_os("manifest-path"))
+                {
+                    Some(path) => Some(PathBuf::from(path)),
+                    None => env(&format!("{}MANIFEST_PATH", ENV_PREFIX)).map(PathBuf::from),
+                };
+                find_config(manifest_path.as_deref())?
+            }
+        },
+    };
+    let mut config = match &config_path {
+        Some(path) => read_config(path)?,
+        None => toml::value::Table::new(),
+    };
+    let mut keys = Vec::new();
+    option_keys(&app, &mut keys);
+    if let Some(key) = config
+        .keys()
+        .find(|key| !keys.contains(&key.as_str()) || COMMAND_LINE_ONLY.contains(&key.as_str()))
+    {
+        return Err(Error::msg(format!(
+            "unknown key `{}` in config file {}",
+            key,
+            config_path.unwrap_or_default().display()
+        )));
+    }
+
+    let mut args = Vec::new();
+    let mut settings = Vec::new();
+    let config_dir = config_path.as_deref().and_then(Path::parent);
+    for (opt, matches) in &opts {
+        let (value, source) = if let Some(value) = opt.cli_value(matches) {
+            (value, Source::CommandLine)
+        } else if let Some(value) = env_value(opt)? {
+            opt.to_args(&value, None, &mut args)?;
+            (value, Source::Environment(opt.env_var()))
+        } else if let Some(value) = config.remove(opt.key) {
+            let path = config_path.clone().unwrap_or_default();
+            opt.to_args(&value, config_dir, &mut args)
+                .with_context(|| format!("invalid value in config file {}", path.display()))?;
+            (value, Source::File(path))
+        } else {
+            continue;
+        };
+        settings.push(Setting {
+            key: opt.key.into(),
+            value,
+            source,
+        });
+    }
+    // The merged options go after the subcommand, so they're inserted at the end of the command
+    // line, but before any `--`. Only the last subcommand has options which aren't global.
+    let mut cli = cli;
+    let end = cli
+        .iter()
+        .skip(1)
+        .position(|arg| arg == "--")
+        .map_or(cli.len(), |i| i + 1);
+    cli.splice(end..end, args);
+    Ok(Merged {
+        args: cli,
+        settings,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::merge;
+    use crate::{Args, Command, PlanCommand};
+    use cargo_ci_precache::test_util::scratch_dir;
+    use clap::{FromArgMatches, IntoApp};
+    use std::{ffi::OsString, fs, path::PathBuf};
+
+    #[test]
+    fn merge_sources() {
+        let dir = scratch_dir("config_test");
+        let config = dir.join("ci-precache.toml");
+        fs::write(
+            &config,
+            "dry-run = true\n\
+             max-age = \"30days\"\n\
+             top = 5\n\
+             lockfiles = [\"a\", \"b\"]\n\
+             output-format = \"json\"\n",
+        )
+        .unwrap();
+
+        let cli = |args: &[&str]| -> Vec<OsString> {
+            ["cargo-ci-precache", "--config", config.to_str().unwrap()]
+                .iter()
+                .chain(args)
+                .map(OsString::from)
+                .collect()
+        };
+        let env = |var: &str| match var {
+            "CARGO_CI_PRECACHE_MAX_AGE" => Some("1day".into()),
+            "CARGO_CI_PRECACHE_OUTPUT_FORMAT" => Some("json-lines".into()),
+            _ => None,
+        };
+        let merged = merge(
+            Args::into_app(),
+            cli(&["cargo-cache", "--output-format", "text"]),
+            &env,
+        )
+        .unwrap();
+        let args = Args::from_arg_matches(&Args::into_app().get_matches_from(&merged.args));
+        let (args, delete) = match args.command {
+            Command::CargoCache { args, delete } => (args, delete),
+            _ => panic!("expected the cargo-cache subcommand"),
+        };
+        assert!(delete.dry_run);
+        assert_eq!(
+            args.retention.max_age,
+            Some(humantime::parse_duration("1day").unwrap())
+        );
+        assert_eq!(
+            args.keep.lockfiles,
+            [
+                dir.join("a").to_str().unwrap(),
+                dir.join("b").to_str().unwrap()
+            ]
+        );
+        assert!(args.output.output_format == crate::OutputFormat::Text);
+        let display = merged.display();
+        assert!(display.contains("output-format = \"text\" # command line\n"));
+        assert!(display
+            .contains("max-age = \"1day\" # environment variable CARGO_CI_PRECACHE_MAX_AGE\n"));
+        // Only the options of the subcommand being run are used.
+        assert!(!display.contains("top = "));
+
+        let merged = merge(Args::into_app(), cli(&["report"]), &env).unwrap();
+        let args = Args::from_arg_matches(&Args::into_app().get_matches_from(&merged.args));
+        match args.command {
+            Command::Report(args) => assert_eq!(args.top, 5),
+            _ => panic!("expected the report subcommand"),
+        }
+        assert!(merged.display().contains("top = 5 # "));
+
+        fs::write(&config, "dry-run = true\nmax-agee = \"1day\"\n").unwrap();
+        let e = merge(Args::into_app(), cli(&["target"]), &|_| None)
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("unknown key `max-agee`"));
+
+        fs::write(&config, "dry-run = \"yes\"\n").unwrap();
+        assert!(merge(Args::into_app(), cli(&["target"]), &|_| None).is_err());
+    }
+
+    #[test]
+    fn merge_subcommands_and_paths() {
+        let dir = scratch_dir("config_paths_test");
+        let config = dir.join("ci-precache.toml");
+        fs::write(
+            &config,
+            "output = \"plan.json\"\n\
+             lockfiles = [\"locks\", \"/abs\"]\n",
+        )
+        .unwrap();
+        let cli = |args: &[&str]| -> Vec<OsString> {
+            [
+                "cargo-ci-precache".as_ref(),
+                "--config".as_ref(),
+                config.as_os_str(),
+            ]
+            .iter()
+            .map(OsString::from)
+            .chain(args.iter().map(OsString::from))
+            .collect()
+        };
+
+        // The plan's output is required, but can be set by the config file.
+        let merged = merge(
+            Args::into_app(),
+            cli(&["plan", "cargo-cache", "--summary-json", "summary.json"]),
+            &|_| None,
+        )
+        .unwrap();
+        let args = Args::from_arg_matches(&Args::into_app().get_matches_from(&merged.args));
+        // Relative paths in the config file are relative to it, but not on the command line.
+        assert_eq!(
+            args.global.summary_json,
+            Some(PathBuf::from("summary.json"))
+        );
+        match args.command {
+            Command::Plan(PlanCommand::CargoCache { args, plan }) => {
+                assert_eq!(plan.output, dir.join("plan.json"));
+                assert_eq!(
+                    args.keep.lockfiles,
+                    [dir.join("locks").to_str().unwrap(), "/abs"]
+                );
+            }
+            _ => panic!("expected a plan for the cargo-cache subcommand"),
+        }
+
+        fs::write(
+            &config,
+            "metadata-json = \"-\"\n\
+             summary-json = \"summary.json\"\n",
+        )
+        .unwrap();
+        // `-` is stdin, not a file next to the config file.
+        let merged = merge(Args::into_app(), cli(&["target"]), &|_| None).unwrap();
+        assert!(merged.args.contains(&"--metadata-json=-".into()));
+        // Options are added before the `--`, and only those of the subcommand being run.
+        let merged = merge(
+            Args::into_app(),
+            cli(&["completions", "--", "bash"]),
+            &|_| None,
+        )
+        .unwrap();
+        let mut summary_json = OsString::from("--summary-json=");
+        summary_json.push(dir.join("summary.json"));
+        assert_eq!(
+            merged.args[3..],
+            [
+                "completions".into(),
+                summary_json,
+                "--".into(),
+                "bash".into()
+            ][..]
+        );
+    }
+}
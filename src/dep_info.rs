@@ -0,0 +1,229 @@
+use std::path::{Path, PathBuf};
+
+// from cargo/core/compiler/fingerprint/dep_info.rs
+//
+// Cargo has used three encodings for this file:
+//  * cargo < 1.48: a list of files.
+//  * cargo < 1.83: a list of files followed by a list of environment variables.
+//  * cargo >= 1.83: a marker and a version number, followed by a list of files with optional
+//    checksums, and a list of environment variables.
+//
+// All integers are little-endian. Lengths are stored as u32.
+
+/// The encoding version written after the marker. Only version 1 exists so far.
+const CURRENT_ENCODING_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepInfoPathType {
+    /// Relative to the package root. Absolute paths are also stored with this type.
+    PackageRootRelative,
+    /// Relative to the target directory.
+    TargetRootRelative,
+}
+
+/// The binary dep-info file stored in each unit's fingerprint directory. Environment variables
+/// are validated, but not stored.
+#[derive(Debug, Default)]
+pub struct EncodedDepInfo {
+    pub files: Vec<(DepInfoPathType, PathBuf)>,
+}
+impl EncodedDepInfo {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut r = Reader(bytes);
+        let versioned = is_versioned(bytes);
+        if versioned {
+            r.u32()?;
+            r.u8()?;
+            if r.u8()? != CURRENT_ENCODING_VERSION {
+                return None;
+            }
+        }
+
+        let nfiles = r.u32()?;
+        let mut files = Vec::new();
+        for _ in 0..nfiles {
+            let ty = match r.u8()? {
+                0 => DepInfoPathType::PackageRootRelative,
+                1 => DepInfoPathType::TargetRootRelative,
+                _ => return None,
+            };
+            let path = bytes_to_path(r.bytes()?)?;
+            if versioned && r.u8()? != 0 {
+                // file length and checksum
+                r.u64()?;
+                r.bytes()?;
+            }
+            files.push((ty, path));
+        }
+
+        // The oldest encoding ends after the file list.
+        if versioned || !r.0.is_empty() {
+            let nenv = r.u32()?;
+            for _ in 0..nenv {
+                r.bytes()?;
+                if r.u8()? != 0 {
+                    r.bytes()?;
+                }
+            }
+        }
+
+        if r.0.is_empty() {
+            Some(Self { files })
+        } else {
+            None
+        }
+    }
+
+    /// Gets the absolute path of a source file from the unit's package, which identifies the
+    /// package it was built from. e.g. lib.rs
+    ///
+    /// Files aren't stored in any particular order, and generated files relative to the target
+    /// directory don't say which package the unit belongs to, so the first file relative to the
+    /// package root is used. This needs the root of the package, as it isn't stored in the file.
+    /// Without one, or without any such file, the first absolute path is used instead.
+    pub fn root_path(&self, package_root: Option<&Path>) -> Option<PathBuf> {
+        let mut package_files = self.files.iter().filter_map(|(ty, p)| match ty {
+            DepInfoPathType::PackageRootRelative => Some(p),
+            DepInfoPathType::TargetRootRelative => None,
+        });
+        let relative = package_files.clone().find(|p| p.is_relative());
+        match (package_root, relative) {
+            (Some(root), Some(p)) => Some(root.join(p)),
+            _ => package_files.find(|p| p.is_absolute()).cloned(),
+        }
+    }
+}
+
+// The versioned encoding starts with what would otherwise be a single file with an invalid path
+// type, so older cargo versions will reject it.
+fn is_versioned(bytes: &[u8]) -> bool {
+    bytes.len() >= 5 && bytes[..4] == 1u32.to_le_bytes() && bytes[4] == u8::MAX
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: &[u8]) -> Option<PathBuf> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    Some(OsStr::from_bytes(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: &[u8]) -> Option<PathBuf> {
+    std::str::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (x, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(x)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut x = [0; 4];
+        x.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(x))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut x = [0; 8];
+        x.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(x))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DepInfoPathType, EncodedDepInfo};
+    use std::path::{Path, PathBuf};
+
+    // cargo < 1.48, a single absolute path to a registry crate.
+    static NO_ENV: &[u8] = b"\x01\x00\x00\x00\
+        \x00\x20\x00\x00\x00/cargo/registry/src/a-1.0/lib.rs";
+
+    // cargo < 1.83, a package relative path and an environment variable.
+    static UNVERSIONED: &[u8] = b"\x01\x00\x00\x00\
+        \x00\x0a\x00\x00\x00src/lib.rs\
+        \x01\x00\x00\x00\
+        \x03\x00\x00\x00FOO\x01\x03\x00\x00\x00bar";
+
+    // cargo >= 1.83, a target relative path with a checksum and an unset environment variable.
+    static VERSIONED: &[u8] = b"\x01\x00\x00\x00\xff\x01\
+        \x01\x00\x00\x00\
+        \x01\x0c\x00\x00\x00debug/out.rs\
+        \x01\x10\x00\x00\x00\x00\x00\x00\x00\x0d\x00\x00\x00sha256=abcdef\
+        \x01\x00\x00\x00\
+        \x03\x00\x00\x00FOO\x00";
+
+    // cargo >= 1.83, a registry crate with no tracked files.
+    static VERSIONED_EMPTY: &[u8] = b"\x01\x00\x00\x00\xff\x01\x00\x00\x00\x00\x00\x00\x00\x00";
+
+    #[test]
+    fn parse_no_env() {
+        let info = EncodedDepInfo::parse(NO_ENV).unwrap();
+        assert_eq!(
+            info.files,
+            [(
+                DepInfoPathType::PackageRootRelative,
+                PathBuf::from("/cargo/registry/src/a-1.0/lib.rs")
+            )]
+        );
+    }
+
+    #[test]
+    fn parse_unversioned() {
+        let info = EncodedDepInfo::parse(UNVERSIONED).unwrap();
+        assert_eq!(
+            info.files,
+            [(
+                DepInfoPathType::PackageRootRelative,
+                PathBuf::from("src/lib.rs")
+            )]
+        );
+        assert_eq!(info.root_path(None), None);
+        assert_eq!(
+            info.root_path(Some(Path::new("/pkg"))),
+            Some(Path::new("/pkg").join("src/lib.rs"))
+        );
+    }
+
+    #[test]
+    fn parse_versioned() {
+        let info = EncodedDepInfo::parse(VERSIONED).unwrap();
+        assert_eq!(
+            info.files,
+            [(
+                DepInfoPathType::TargetRootRelative,
+                PathBuf::from("debug/out.rs")
+            )]
+        );
+        // A generated root file doesn't belong to any package.
+        assert_eq!(info.root_path(Some(Path::new("/pkg"))), None);
+
+        let info = EncodedDepInfo::parse(VERSIONED_EMPTY).unwrap();
+        assert!(info.files.is_empty());
+        assert_eq!(info.root_path(Some(Path::new("/pkg"))), None);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        // Unknown version.
+        assert!(EncodedDepInfo::parse(b"\x01\x00\x00\x00\xff\x02\x00\x00\x00\x00").is_none());
+        // Truncated.
+        assert!(EncodedDepInfo::parse(&UNVERSIONED[..UNVERSIONED.len() - 1]).is_none());
+        // Trailing data.
+        assert!(EncodedDepInfo::parse(&[VERSIONED_EMPTY, b"\x00"].concat()).is_none());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::usage::{disk_usage, format_size, last_modified};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsStr,
+    io,
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The number of items and bytes in a category.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    pub count: u64,
+    pub bytes: u64,
+}
+impl AddAssign for Totals {
+    fn add_assign(&mut self, other: Self) {
+        self.count += other.count;
+        self.bytes += other.bytes;
+    }
+}
+
+/// How much of a cache restored before the run, e.g. by a CI cache action, was used.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Effectiveness {
+    /// Units or packages which are used, and were already restored.
+    pub hits: u64,
+    /// Units or packages which are used, but had to be built or downloaded by this run.
+    pub misses: u64,
+    /// Every item restored before the run.
+    pub restored: Totals,
+    /// Restored items which are deleted because nothing used them.
+    pub dead: Totals,
+}
+impl Effectiveness {
+    /// The fraction of used units or packages which were already restored.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        match self.hits + self.misses {
+            0 => None,
+            total => Some(self.hits as f64 / total as f64),
+        }
+    }
+
+    /// The fraction of restored bytes which weren't used.
+    pub fn dead_ratio(&self) -> Option<f64> {
+        match self.restored.bytes {
+            0 => None,
+            total => Some(self.dead.bytes as f64 / total as f64),
+        }
+    }
+
+    /// Describes the statistics on a single line.
+    pub fn describe(&self) -> String {
+        let percent = |ratio: Option<f64>| match ratio {
+            Some(ratio) => format!("{:.0}%", ratio * 100.0),
+            None => "n/a".into(),
+        };
+        format!(
+            "{} hits, {} misses ({} hit ratio), {} of {} restored is unused ({})",
+            self.hits,
+            self.misses,
+            percent(self.hit_ratio()),
+            format_size(self.dead.bytes),
+            format_size(self.restored.bytes),
+            percent(self.dead_ratio())
+        )
+    }
+}
+
+// Lists the entries of a directory. A missing directory has no entries.
+pub(crate) fn list_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    match dir.read_dir() {
+        Ok(iter) => iter
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<_>>()
+            .with_context(|| format!("error reading dir: {}", dir.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("error reading dir: {}", dir.display())),
+    }
+}
+
+// Whether an item was last modified before the run started. Items which no longer exist weren't
+// restored.
+fn modified_before(path: &Path, since: SystemTime) -> Result<bool> {
+    let time = last_modified(path).with_context(|| format!("error reading {}", path.display()))?;
+    Ok(matches!(time, Some(time) if time < since))
+}
+
+// Adds every restored item to the totals, along with the ones which are deleted.
+fn add_items(
+    stats: &mut Effectiveness,
+    items: &[PathBuf],
+    since: SystemTime,
+    deleted: &dyn Fn(&Path) -> bool,
+) -> Result<()> {
+    for path in items {
+        if !modified_before(path, since)? {
+            continue;
+        }
+        let usage =
+            disk_usage(path).with_context(|| format!("error reading {}", path.display()))?;
+        let totals = Totals {
+            count: 1,
+            bytes: usage.bytes,
+        };
+        stats.restored += totals;
+        if deleted(path) {
+            stats.dead += totals;
+        }
+    }
+    Ok(())
+}
+
+/// Measures how much of a target directory restored before `since`, e.g. from a CI cache, is used
+/// by the build. `deleted` returns whether an item is being deleted as unused.
+///
+/// Every unit which is kept counts as a hit, unless it was invoked at or after `since`, in which
+/// case it was built by this run. Only the `debug` directory is measured.
+pub fn target_effectiveness(
+    target_dir: &Path,
+    since: SystemTime,
+    deleted: &dyn Fn(&Path) -> bool,
+) -> Result<Effectiveness> {
+    let mut stats = Effectiveness::default();
+    let debug_dir = target_dir.join("debug");
+    let mut items = Vec::new();
+    for path in list_dir(&debug_dir)? {
+        let name = path.file_name().unwrap_or_default();
+        if name == "build" || name == "deps" || name == ".fingerprint" {
+            items.extend(list_dir(&path)?);
+        } else if name != ".cargo-lock" {
+            items.push(path);
+        }
+    }
+    add_items(&mut stats, &items, since, deleted)?;
+
+    for unit in list_dir(&debug_dir.join(".fingerprint"))? {
+        if deleted(&unit) {
+            continue;
+        }
+        // Cargo writes `invoked.timestamp` just before running the compiler for a unit.
+        let invoked = unit.join("invoked.timestamp");
+        let restored = if invoked.exists() {
+            modified_before(&invoked, since)?
+        } else {
+            modified_before(&unit, since)?
+        };
+        if restored {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+    }
+    Ok(stats)
+}
+
+/// Measures how much of the global cargo cache restored before `since`, e.g. from a CI cache, is
+/// used by the build. `deleted` returns whether an item is being deleted as unused.
+///
+/// Every `.crate` file and git checkout which is kept counts as a hit, unless it was downloaded at
+/// or after `since`. Only the directories which can be cleaned are measured, so the registry
+/// index isn't included.
+pub fn cache_effectiveness(
+    cargo_home: &Path,
+    since: SystemTime,
+    deleted: &dyn Fn(&Path) -> bool,
+) -> Result<Effectiveness> {
+    let mut stats = Effectiveness::default();
+    let nested = |dir: &Path| -> Result<Vec<PathBuf>> {
+        let mut items = Vec::new();
+        for path in list_dir(dir)? {
+            items.extend(list_dir(&path)?);
+        }
+        Ok(items)
+    };
+    let crates: Vec<_> = nested(&cargo_home.join("registry").join("cache"))?
+        .into_iter()
+        .filter(|p| p.extension() == Some(OsStr::new("crate")))
+        .collect();
+    let checkouts = nested(&cargo_home.join("git").join("checkouts"))?;
+    let mut items = list_dir(&cargo_home.join("git").join("db"))?;
+    items.extend(nested(&cargo_home.join("registry").join("src"))?);
+    items.extend(crates.iter().cloned());
+    items.extend(checkouts.iter().cloned());
+    add_items(&mut stats, &items, since, deleted)?;
+
+    // A whole repository may be deleted rather than each of its checkouts.
+    let is_deleted = |path: &Path| path.ancestors().any(deleted);
+    for path in crates.iter().chain(&checkouts) {
+        if is_deleted(path) {
+            continue;
+        }
+        if modified_before(path, since)? {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::target_effectiveness;
+    use crate::test_util::scratch_dir;
+    use crate::usage::set_modified;
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn target_hits_and_misses() {
+        let dir = scratch_dir("effectiveness_test");
+        let debug = dir.join("debug");
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        let since = SystemTime::now() - Duration::from_secs(60);
+        for (name, restored) in &[("live-1", true), ("dead-2", true), ("built-3", false)] {
+            let unit = debug.join(".fingerprint").join(name);
+            fs::create_dir_all(&unit).unwrap();
+            fs::write(unit.join("invoked.timestamp"), "").unwrap();
+            let artifact = debug.join("deps").join(format!("lib{}.rlib", name));
+            fs::create_dir_all(artifact.parent().unwrap()).unwrap();
+            fs::write(&artifact, "rlib").unwrap();
+            if *restored {
+                set_modified(&unit.join("invoked.timestamp"), old).unwrap();
+                set_modified(&artifact, old).unwrap();
+                set_modified(&unit, old).unwrap();
+            }
+        }
+
+        let dead = |p: &std::path::Path| p.to_string_lossy().contains("dead");
+        let stats = target_effectiveness(&dir, since, &dead).unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!((stats.restored.count, stats.restored.bytes), (4, 8));
+        assert_eq!((stats.dead.count, stats.dead.bytes), (2, 4));
+        assert_eq!(stats.hit_ratio(), Some(0.5));
+        assert_eq!(stats.dead_ratio(), Some(0.5));
+    }
+}
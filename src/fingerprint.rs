@@ -51,6 +51,23 @@ impl Hash for Fingerprint {
     }
 }
 
+/// Only the local fingerprints of a unit, which can be read even when the rest of the fingerprint
+/// is in a format this doesn't understand.
+#[derive(Debug, Deserialize)]
+pub struct LocalFingerprints {
+    pub local: Vec<LocalFingerprint>,
+}
+impl LocalFingerprints {
+    /// The precalculated fingerprint, which is the version of packages from a registry, or the
+    /// commit of packages from a git repository.
+    pub fn precalculated(&self) -> Option<&str> {
+        self.local.iter().find_map(|l| match l {
+            LocalFingerprint::Precalculated(s) => Some(s.as_str()),
+            _ => None,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct DepFingerprint {
     pub pkg_id: u64,
@@ -0,0 +1,225 @@
+use anyhow::Result;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, ToSql};
+use std::{
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+// The kind of cache entry a path refers to, along with the names used in the database.
+enum Entry<'a> {
+    RegistryCacheDir(&'a str),
+    RegistryCrate(&'a str, &'a str),
+    RegistrySrcDir(&'a str),
+    RegistrySrc(&'a str, &'a str),
+    GitDb(&'a str),
+    GitCheckoutDir(&'a str),
+    GitCheckout(&'a str, &'a str),
+}
+
+/// Cargo's database of when each item in the global cache was last used, stored in
+/// `~/.cargo/.global-cache`. Used by cargo since 1.78.
+pub struct GlobalCache {
+    conn: Connection,
+    cargo_home: PathBuf,
+}
+impl GlobalCache {
+    /// Opens the database in the given cargo home. Returns `None` if it doesn't exist.
+    pub fn open(cargo_home: &Path) -> Result<Option<Self>> {
+        let path = cargo_home.join(".global-cache");
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let conn = Connection::open_with_flags(
+            &path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(Duration::from_secs(1))?;
+        Ok(Some(Self {
+            conn,
+            cargo_home: cargo_home.into(),
+        }))
+    }
+
+    fn entry<'a>(&self, path: &'a Path) -> Option<Entry<'a>> {
+        let mut components = path.strip_prefix(&self.cargo_home).ok()?.components();
+        let mut next = || match components.next() {
+            Some(Component::Normal(c)) => Some(c),
+            _ => None,
+        };
+        let names = (next(), next(), next(), next(), next());
+        let str = |s: &'a OsStr| s.to_str();
+        Some(match names {
+            (Some(a), Some(b), Some(c), d, None) => {
+                let (a, b, c) = (str(a)?, str(b)?, str(c)?);
+                match (a, b, d) {
+                    ("registry", "cache", None) => Entry::RegistryCacheDir(c),
+                    ("registry", "cache", Some(d)) => Entry::RegistryCrate(c, str(d)?),
+                    ("registry", "src", None) => Entry::RegistrySrcDir(c),
+                    ("registry", "src", Some(d)) => Entry::RegistrySrc(c, str(d)?),
+                    ("git", "db", None) => Entry::GitDb(c),
+                    ("git", "checkouts", None) => Entry::GitCheckoutDir(c),
+                    ("git", "checkouts", Some(d)) => Entry::GitCheckout(c, str(d)?),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    /// Gets when the item at the given path was last used by cargo. For directories containing
+    /// multiple items this is the most recent use of any of them.
+    pub fn last_use(&self, path: &Path) -> Result<Option<SystemTime>> {
+        let query = |sql: &str, params: &[&dyn ToSql]| -> Result<Option<i64>> {
+            Ok(self
+                .conn
+                .query_row(sql, params, |row| row.get(0))
+                .optional()?
+                .flatten())
+        };
+        let timestamp = match self.entry(path) {
+            Some(Entry::RegistryCacheDir(registry)) => query(
+                "SELECT max(c.timestamp) FROM registry_crate c JOIN registry_index i \
+                 ON c.registry_id = i.id WHERE i.name = ?1",
+                params![registry],
+            )?,
+            Some(Entry::RegistryCrate(registry, name)) => query(
+                "SELECT max(c.timestamp) FROM registry_crate c JOIN registry_index i \
+                 ON c.registry_id = i.id WHERE i.name = ?1 AND c.name = ?2",
+                params![registry, name],
+            )?,
+            Some(Entry::RegistrySrcDir(registry)) => query(
+                "SELECT max(s.timestamp) FROM registry_src s JOIN registry_index i \
+                 ON s.registry_id = i.id WHERE i.name = ?1",
+                params![registry],
+            )?,
+            Some(Entry::RegistrySrc(registry, name)) => query(
+                "SELECT max(s.timestamp) FROM registry_src s JOIN registry_index i \
+                 ON s.registry_id = i.id WHERE i.name = ?1 AND s.name = ?2",
+                params![registry, name],
+            )?,
+            Some(Entry::GitDb(repo)) | Some(Entry::GitCheckoutDir(repo)) => query(
+                "SELECT max(timestamp) FROM git_db WHERE name = ?1",
+                params![repo],
+            )?,
+            Some(Entry::GitCheckout(repo, rev)) => query(
+                "SELECT max(c.timestamp) FROM git_checkout c JOIN git_db d \
+                 ON c.git_id = d.id WHERE d.name = ?1 AND c.name = ?2",
+                params![repo, rev],
+            )?,
+            None => None,
+        };
+        Ok(timestamp.map(|t| SystemTime::UNIX_EPOCH + Duration::from_secs(t.max(0) as u64)))
+    }
+
+    /// Removes the rows for an item which has been deleted. Cargo will add rows back for anything
+    /// which still exists the next time it runs its own gc.
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        match self.entry(path) {
+            Some(Entry::RegistryCacheDir(registry)) => self.conn.execute(
+                "DELETE FROM registry_crate WHERE registry_id IN \
+                 (SELECT id FROM registry_index WHERE name = ?1)",
+                params![registry],
+            ),
+            Some(Entry::RegistryCrate(registry, name)) => self.conn.execute(
+                "DELETE FROM registry_crate WHERE name = ?2 AND registry_id IN \
+                 (SELECT id FROM registry_index WHERE name = ?1)",
+                params![registry, name],
+            ),
+            Some(Entry::RegistrySrcDir(registry)) => self.conn.execute(
+                "DELETE FROM registry_src WHERE registry_id IN \
+                 (SELECT id FROM registry_index WHERE name = ?1)",
+                params![registry],
+            ),
+            Some(Entry::RegistrySrc(registry, name)) => self.conn.execute(
+                "DELETE FROM registry_src WHERE name = ?2 AND registry_id IN \
+                 (SELECT id FROM registry_index WHERE name = ?1)",
+                params![registry, name],
+            ),
+            Some(Entry::GitDb(repo)) => self
+                .conn
+                .execute(
+                    "DELETE FROM git_checkout WHERE git_id IN \
+                     (SELECT id FROM git_db WHERE name = ?1)",
+                    params![repo],
+                )
+                .and_then(|_| {
+                    self.conn
+                        .execute("DELETE FROM git_db WHERE name = ?1", params![repo])
+                }),
+            Some(Entry::GitCheckoutDir(repo)) => self.conn.execute(
+                "DELETE FROM git_checkout WHERE git_id IN (SELECT id FROM git_db WHERE name = ?1)",
+                params![repo],
+            ),
+            Some(Entry::GitCheckout(repo, rev)) => self.conn.execute(
+                "DELETE FROM git_checkout WHERE name = ?2 AND git_id IN \
+                 (SELECT id FROM git_db WHERE name = ?1)",
+                params![repo, rev],
+            ),
+            None => return Ok(()),
+        }?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::GlobalCache;
+    use crate::test_util::scratch_dir;
+    use rusqlite::Connection;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn last_use_and_remove() {
+        let home = scratch_dir("global_cache_test");
+        Connection::open(home.join(".global-cache"))
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE registry_index (id INTEGER PRIMARY KEY, name TEXT, timestamp INTEGER);
+                CREATE TABLE registry_crate (registry_id INTEGER, name TEXT, timestamp INTEGER);
+                CREATE TABLE registry_src (registry_id INTEGER, name TEXT, timestamp INTEGER);
+                CREATE TABLE git_db (id INTEGER PRIMARY KEY, name TEXT, timestamp INTEGER);
+                CREATE TABLE git_checkout (git_id INTEGER, name TEXT, timestamp INTEGER);
+                INSERT INTO registry_index VALUES (1, 'example.com-0123456789abcdef', 300);
+                INSERT INTO registry_crate VALUES (1, 'foo-0.1.0.crate', 100);
+                INSERT INTO registry_crate VALUES (1, 'bar-0.1.0.crate', 200);
+                INSERT INTO git_db VALUES (1, 'repo-0123456789abcdef', 50);
+                INSERT INTO git_checkout VALUES (1, '0123456', 50);",
+            )
+            .unwrap();
+
+        let time = |t| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(t));
+        let cache = GlobalCache::open(&home).unwrap().unwrap();
+        let registry = home.join("registry/cache/example.com-0123456789abcdef");
+        assert_eq!(
+            cache.last_use(&registry.join("foo-0.1.0.crate")).unwrap(),
+            time(100)
+        );
+        assert_eq!(cache.last_use(&registry).unwrap(), time(200));
+        assert_eq!(
+            cache.last_use(&registry.join("baz-0.1.0.crate")).unwrap(),
+            None
+        );
+        assert_eq!(
+            cache
+                .last_use(&home.join("git/checkouts/repo-0123456789abcdef/0123456"))
+                .unwrap(),
+            time(50)
+        );
+        assert_eq!(cache.last_use(&home.join("other")).unwrap(), None);
+
+        cache.remove(&registry.join("bar-0.1.0.crate")).unwrap();
+        assert_eq!(cache.last_use(&registry).unwrap(), time(100));
+        cache
+            .remove(&home.join("git/db/repo-0123456789abcdef"))
+            .unwrap();
+        assert_eq!(
+            cache
+                .last_use(&home.join("git/checkouts/repo-0123456789abcdef/0123456"))
+                .unwrap(),
+            None
+        );
+
+        assert!(GlobalCache::open(&home.join("missing")).unwrap().is_none());
+    }
+}
@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+// Gets the path of a crate's file relative to the root of the index. Crate names are ASCII, so
+// anything else can't be in the index.
+fn index_path(name: &str) -> Option<PathBuf> {
+    if !name.is_ascii() {
+        return None;
+    }
+    let name = name.to_ascii_lowercase();
+    Some(match name.len() {
+        0 => return None,
+        1 => ["1", &name].iter().collect(),
+        2 => ["2", &name].iter().collect(),
+        3 => ["3", &name[..1], &name].iter().collect(),
+        _ => [&name[..2], &name[2..4], &name].iter().collect(),
+    })
+}
+
+// Reads the json entries from cargo's index cache file. The format is a cache version byte, an
+// index version (since cache version 2), then a nul terminated header followed by nul terminated
+// pairs of version and json entry.
+fn parse_cache_file(data: &[u8]) -> Option<Vec<&[u8]>> {
+    let rest = match *data.first()? {
+        1 => &data[1..],
+        2 | 3 => data.get(5..)?,
+        _ => return None,
+    };
+    let mut items = rest.split(|&b| b == 0);
+    items.next()?;
+    let mut entries = Vec::new();
+    while let (Some(_), Some(entry)) = (items.next(), items.next()) {
+        entries.push(entry);
+    }
+    Some(entries)
+}
+
+/// Gets the yank status of each version of a crate from the locally available index data for a
+/// registry. Both cargo's index cache, and a checkout of a git index are used. Returns `None` if
+/// there is no local data for the crate.
+pub fn yanked_versions(index_dir: &Path, name: &str) -> io::Result<Option<HashMap<String, bool>>> {
+    let path = match index_path(name) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let read = |path: PathBuf| match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+
+    let cache = read(index_dir.join(".cache").join(&path))?;
+    let checkout = read(index_dir.join(&path))?;
+    let entries = match (&cache, &checkout) {
+        (Some(data), _) => match parse_cache_file(data) {
+            Some(entries) => entries,
+            None => return Ok(None),
+        },
+        (None, Some(data)) => data.split(|&b| b == b'\n').collect(),
+        (None, None) => return Ok(None),
+    };
+
+    Ok(Some(
+        entries
+            .into_iter()
+            .filter_map(|entry| serde_json::from_slice::<IndexEntry>(entry).ok())
+            .map(|entry| (entry.vers, entry.yanked))
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{index_path, parse_cache_file};
+    use std::path::Path;
+
+    #[test]
+    fn index_paths() {
+        let path = |name| index_path(name).unwrap();
+        assert_eq!(path("a"), Path::new("1/a"));
+        assert_eq!(path("ab"), Path::new("2/ab"));
+        assert_eq!(path("abc"), Path::new("3/a/abc"));
+        assert_eq!(path("Cfg-If"), Path::new("cf/g-/cfg-if"));
+        assert_eq!(index_path(""), None);
+        assert_eq!(index_path("é"), None);
+        assert_eq!(index_path("aé"), None);
+        assert_eq!(index_path("über"), None);
+    }
+
+    #[test]
+    fn cache_file() {
+        let data = b"\x03\x02\x00\x00\x00etag\x000.1.0\x00{\"vers\":\"0.1.0\"}\x000.2.0\x00{\"vers\":\"0.2.0\",\"yanked\":true}\x00";
+        let entries = parse_cache_file(data).unwrap();
+        assert_eq!(
+            entries,
+            [
+                &b"{\"vers\":\"0.1.0\"}"[..],
+                &b"{\"vers\":\"0.2.0\",\"yanked\":true}"[..]
+            ]
+        );
+
+        let data = b"\x01etag\x000.1.0\x00{\"vers\":\"0.1.0\"}\x00";
+        assert_eq!(parse_cache_file(data).unwrap().len(), 1);
+        assert_eq!(parse_cache_file(b"\x09"), None);
+    }
+}
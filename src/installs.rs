@@ -0,0 +1,258 @@
+use crate::paths::write_atomic;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    env::consts::EXE_SUFFIX,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Binaries rustup installs into ~/.cargo/bin. These are never in cargo's install records.
+const RUSTUP_PROXIES: &[&str] = &[
+    "cargo",
+    "cargo-clippy",
+    "cargo-fmt",
+    "cargo-miri",
+    "clippy-driver",
+    "rls",
+    "rust-analyzer",
+    "rust-gdb",
+    "rust-gdbgui",
+    "rust-lldb",
+    "rustc",
+    "rustdoc",
+    "rustfmt",
+    "rustup",
+];
+
+/// The contents of `~/.cargo/.crates.toml`. Maps each package id, in the form
+/// `{name} {version} ({source})`, to the binaries installed from it.
+#[derive(Serialize, Deserialize, Default)]
+struct CratesToml {
+    #[serde(default)]
+    v1: BTreeMap<String, Vec<String>>,
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("error reading {}", path.display())),
+    }
+}
+
+fn read_crates_toml(path: &Path) -> Result<Option<CratesToml>> {
+    read_optional(path)?
+        .map(|contents| {
+            toml::from_str(&contents).with_context(|| format!("error parsing {}", path.display()))
+        })
+        .transpose()
+}
+
+// `.crates2.json` has more information than the v1 format, all of which needs to be preserved when
+// it's rewritten.
+fn read_crates_json(path: &Path) -> Result<Option<Value>> {
+    read_optional(path)?
+        .map(|contents| {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("error parsing {}", path.display()))
+        })
+        .transpose()
+}
+
+fn json_bins(json: &Value) -> BTreeMap<String, Vec<String>> {
+    let installs = match json.get("installs").and_then(Value::as_object) {
+        Some(installs) => installs,
+        None => return BTreeMap::new(),
+    };
+    installs
+        .iter()
+        .map(|(package, info)| {
+            let bins = info
+                .get("bins")
+                .and_then(Value::as_array)
+                .map(|bins| {
+                    bins.iter()
+                        .filter_map(|b| b.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default();
+            (package.clone(), bins)
+        })
+        .collect()
+}
+
+fn is_kept(keep: &[String], file_name: &str) -> bool {
+    let name = file_name.strip_suffix(EXE_SUFFIX).unwrap_or(file_name);
+    keep.iter().any(|k| k == file_name || k == name)
+}
+
+/// Inconsistencies between the binaries in `~/.cargo/bin` and cargo's records of installed
+/// packages in `.crates.toml` and `.crates2.json`.
+#[derive(Default, Debug)]
+pub struct InstallReport {
+    /// Binaries which aren't listed by any install record.
+    pub untracked: Vec<PathBuf>,
+    /// (package id, binary name) for each listed binary which doesn't exist.
+    pub missing: Vec<(String, String)>,
+    /// Packages which are only listed in one of the two record files.
+    pub mismatched: Vec<String>,
+}
+
+/// Compares the binaries in the cargo home's `bin` directory with cargo's install records.
+/// Binaries installed by rustup, and any binary in `keep`, with or without the executable suffix,
+/// are ignored.
+pub fn check_installed_bins(cargo_home: &Path, keep: &[String]) -> Result<InstallReport> {
+    let toml = read_crates_toml(&cargo_home.join(".crates.toml"))?;
+    let json = read_crates_json(&cargo_home.join(".crates2.json"))?;
+    let toml_bins = toml.map(|t| t.v1);
+    let json_bins = json.as_ref().map(json_bins);
+
+    let mut report = InstallReport::default();
+    if let (Some(toml_bins), Some(json_bins)) = (&toml_bins, &json_bins) {
+        let toml_packages: BTreeSet<_> = toml_bins.keys().collect();
+        let json_packages: BTreeSet<_> = json_bins.keys().collect();
+        report.mismatched = toml_packages
+            .symmetric_difference(&json_packages)
+            .map(|&p| p.clone())
+            .collect();
+    }
+
+    // Both files should list the same binaries, but either may have been edited by hand.
+    let mut records = BTreeMap::<&str, BTreeSet<&str>>::new();
+    for bins in toml_bins.iter().chain(json_bins.iter()) {
+        for (package, bins) in bins {
+            records
+                .entry(package)
+                .or_default()
+                .extend(bins.iter().map(String::as_str));
+        }
+    }
+
+    let bin_dir = cargo_home.join("bin");
+    for (&package, bins) in &records {
+        for &bin in bins {
+            if !is_kept(keep, bin) && !bin_dir.join(bin).exists() {
+                report.missing.push((package.into(), bin.into()));
+            }
+        }
+    }
+
+    let tracked: HashSet<&str> = records.values().flatten().copied().collect();
+    let entries = match fs::read_dir(&bin_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(e).with_context(|| format!("error reading {}", bin_dir.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let name = file_name.strip_suffix(EXE_SUFFIX).unwrap_or(file_name);
+        if !tracked.contains(file_name)
+            && !RUSTUP_PROXIES.contains(&name)
+            && !is_kept(keep, file_name)
+        {
+            report.untracked.push(entry.path());
+        }
+    }
+    report.untracked.sort();
+    Ok(report)
+}
+
+/// Removes the given (package id, binary name) pairs from cargo's install records. Packages left
+/// with no binaries are removed entirely.
+pub fn remove_install_records(cargo_home: &Path, bins: &[(String, String)]) -> Result<()> {
+    let is_removed = |package: &str, bin: &str| bins.iter().any(|(p, b)| p == package && b == bin);
+
+    let toml_path = cargo_home.join(".crates.toml");
+    if let Some(mut toml) = read_crates_toml(&toml_path)? {
+        for (package, bins) in &mut toml.v1 {
+            bins.retain(|b| !is_removed(package, b));
+        }
+        toml.v1.retain(|_, bins| !bins.is_empty());
+        write_atomic(&toml_path, toml::to_string(&toml)?.as_bytes())
+            .with_context(|| format!("error writing {}", toml_path.display()))?;
+    }
+
+    let json_path = cargo_home.join(".crates2.json");
+    if let Some(mut json) = read_crates_json(&json_path)? {
+        if let Some(installs) = json.get_mut("installs").and_then(Value::as_object_mut) {
+            *installs = std::mem::take(installs)
+                .into_iter()
+                .filter_map(|(package, mut info)| {
+                    if let Some(bins) = info.get_mut("bins").and_then(Value::as_array_mut) {
+                        bins.retain(|b| match b.as_str() {
+                            Some(b) => !is_removed(&package, b),
+                            None => true,
+                        });
+                        if bins.is_empty() {
+                            return None;
+                        }
+                    }
+                    Some((package, info))
+                })
+                .collect();
+        }
+        write_atomic(&json_path, serde_json::to_string(&json)?.as_bytes())
+            .with_context(|| format!("error writing {}", json_path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_installed_bins, remove_install_records};
+    use crate::test_util::scratch_dir;
+    use std::{env::consts::EXE_SUFFIX, fs};
+
+    #[test]
+    fn installed_bins() {
+        let home = scratch_dir("installs_test");
+        fs::create_dir_all(home.join("bin")).unwrap();
+        let bin = |name: &str| format!("{}{}", name, EXE_SUFFIX);
+        for name in &["foo", "untracked", "kept", "cargo"] {
+            fs::write(home.join("bin").join(bin(name)), b"").unwrap();
+        }
+        fs::write(
+            home.join(".crates.toml"),
+            format!(
+                "[v1]\n\"foo 0.1.0 (registry+https://example.com/)\" = [\"{}\"]\n\
+                 \"bar 0.1.0 (registry+https://example.com/)\" = [\"{}\"]\n",
+                bin("foo"),
+                bin("bar")
+            ),
+        )
+        .unwrap();
+        fs::write(
+            home.join(".crates2.json"),
+            format!(
+                "{{\"installs\":{{\"foo 0.1.0 (registry+https://example.com/)\":\
+                 {{\"bins\":[\"{}\"],\"profile\":\"release\"}}}}}}",
+                bin("foo")
+            ),
+        )
+        .unwrap();
+
+        let report = check_installed_bins(&home, &["kept".into()]).unwrap();
+        assert_eq!(report.untracked, [home.join("bin").join(bin("untracked"))]);
+        let bar = "bar 0.1.0 (registry+https://example.com/)".to_owned();
+        assert_eq!(report.missing, [(bar.clone(), bin("bar"))]);
+        assert_eq!(report.mismatched, [bar]);
+
+        remove_install_records(&home, &report.missing).unwrap();
+        let report = check_installed_bins(&home, &["kept".into()]).unwrap();
+        assert!(report.missing.is_empty());
+        assert!(report.mismatched.is_empty());
+        let json = fs::read_to_string(home.join(".crates2.json")).unwrap();
+        assert!(json.contains("\"profile\":\"release\""));
+    }
+}
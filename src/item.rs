@@ -0,0 +1,264 @@
+use crate::{artifact::parse_artifact_stem, split_package_version};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsStr,
+    path::{Component, Path},
+};
+
+/// The kind of item found by one of the cleaning functions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemKind {
+    /// A whole registry in `registry/cache` or `registry/src`.
+    Registry,
+    /// A `.crate` file in `registry/cache`.
+    RegistryCrate,
+    /// An unpacked crate in `registry/src`.
+    RegistrySrc,
+    /// A repository in `git/db`.
+    GitDb,
+    /// A checkout, or every checkout of a repository, in `git/checkouts`.
+    GitCheckout,
+    /// A binary in `bin`.
+    InstalledBin,
+    /// An item in a target directory's `deps` directory.
+    DepArtifact,
+    /// A unit's directory in a target directory's `.fingerprint` directory.
+    Fingerprint,
+    /// A build script's directory in a target directory's `build` directory.
+    BuildDir,
+    Other,
+}
+
+/// What an item is, along with the crate name and hash when they can be determined from its path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ItemInfo {
+    pub kind: ItemKind,
+    pub name: Option<String>,
+    pub hash: Option<String>,
+}
+impl ItemInfo {
+    fn new(kind: ItemKind, name: Option<&str>, hash: Option<&str>) -> Self {
+        Self {
+            kind,
+            name: name.map(String::from),
+            hash: hash.map(String::from),
+        }
+    }
+}
+
+// Splits a directory name of the form `{name}-{hash}`.
+fn split_name_hash(name: &str) -> (Option<&str>, Option<&str>) {
+    match name.rsplit_once('-') {
+        Some((name, hash)) => (Some(name), Some(hash)),
+        None => (Some(name), None),
+    }
+}
+
+fn target_item(kind: ItemKind, path: &Path) -> ItemInfo {
+    let file_name = path.file_name().unwrap_or_default();
+    let artifact = match parse_artifact_stem(file_name) {
+        Some(artifact) => artifact,
+        None => return ItemInfo::new(kind, None, None),
+    };
+    let hash = artifact.meta_hash.as_deref();
+    // Unit directories are named after the package, so the name is kept as is rather than using
+    // the crate name.
+    let name = match (kind, file_name.to_str(), hash) {
+        (ItemKind::Fingerprint | ItemKind::BuildDir, Some(dir), Some(hash)) => {
+            dir.strip_suffix(hash).and_then(|dir| dir.strip_suffix('-'))
+        }
+        (ItemKind::Fingerprint | ItemKind::BuildDir, dir, None) => dir,
+        _ => Some(artifact.crate_name.as_str()),
+    };
+    ItemInfo::new(kind, name, hash)
+}
+
+/// Determines what the item at the given path is from its location.
+pub fn describe_item(cargo_home: &Path, path: &Path) -> ItemInfo {
+    if let Ok(rel) = path.strip_prefix(cargo_home) {
+        let names: Vec<_> = rel
+            .components()
+            .map(|c| match c {
+                Component::Normal(c) => c.to_str(),
+                _ => None,
+            })
+            .collect();
+        return match names[..] {
+            [Some("registry"), Some("cache" | "src"), Some(registry)] => {
+                let (host, hash) = split_name_hash(registry);
+                ItemInfo::new(ItemKind::Registry, host, hash)
+            }
+            [Some("registry"), Some("cache"), Some(_), Some(file)] => {
+                let name = file
+                    .strip_suffix(".crate")
+                    .and_then(split_package_version)
+                    .map(|(name, _)| name);
+                ItemInfo::new(ItemKind::RegistryCrate, name, None)
+            }
+            [Some("registry"), Some("src"), Some(_), Some(dir)] => {
+                let name = split_package_version(dir).map(|(name, _)| name);
+                ItemInfo::new(ItemKind::RegistrySrc, name, None)
+            }
+            [Some("git"), Some("db"), Some(repo)] => {
+                let (name, hash) = split_name_hash(repo);
+                ItemInfo::new(ItemKind::GitDb, name, hash)
+            }
+            [Some("git"), Some("checkouts"), Some(repo)] => {
+                let (name, hash) = split_name_hash(repo);
+                ItemInfo::new(ItemKind::GitCheckout, name, hash)
+            }
+            [Some("git"), Some("checkouts"), Some(repo), Some(rev)] => {
+                let (name, _) = split_name_hash(repo);
+                ItemInfo::new(ItemKind::GitCheckout, name, Some(rev))
+            }
+            [Some("bin"), Some(bin)] => ItemInfo::new(ItemKind::InstalledBin, Some(bin), None),
+            _ => ItemInfo::new(ItemKind::Other, None, None),
+        };
+    }
+
+    match path
+        .parent()
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str)
+    {
+        Some("deps") => target_item(ItemKind::DepArtifact, path),
+        Some(".fingerprint") => target_item(ItemKind::Fingerprint, path),
+        Some("build") => target_item(ItemKind::BuildDir, path),
+        _ => ItemInfo::new(ItemKind::Other, None, None),
+    }
+}
+
+/// What happened, or would happen, to an item.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ItemAction {
+    Delete,
+    WouldDelete,
+    Kept,
+}
+
+/// A single record of the machine-readable output.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ItemRecord {
+    pub path: String,
+    pub action: ItemAction,
+    #[serde(flatten)]
+    pub info: ItemInfo,
+    /// The size of the item, including everything inside it.
+    pub bytes: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe_item, ItemAction, ItemInfo, ItemKind, ItemRecord};
+    use std::path::Path;
+
+    #[test]
+    fn describe_items() {
+        let home = Path::new("/home/.cargo");
+        let describe = |path: &str| {
+            let info = describe_item(home, Path::new(path));
+            (info.kind, info.name, info.hash)
+        };
+        let some = |s: &str| Some(s.to_owned());
+
+        assert_eq!(
+            describe("/home/.cargo/registry/cache/example.com-0123456789abcdef"),
+            (
+                ItemKind::Registry,
+                some("example.com"),
+                some("0123456789abcdef")
+            )
+        );
+        assert_eq!(
+            describe(
+                "/home/.cargo/registry/cache/example.com-0123456789abcdef/foo-bar-1.0.0.crate"
+            ),
+            (ItemKind::RegistryCrate, some("foo-bar"), None)
+        );
+        assert_eq!(
+            describe("/home/.cargo/registry/src/example.com-0123456789abcdef/foo-1.0.0-rc.1"),
+            (ItemKind::RegistrySrc, some("foo"), None)
+        );
+        assert_eq!(
+            describe("/home/.cargo/git/db/repo-0123456789abcdef"),
+            (ItemKind::GitDb, some("repo"), some("0123456789abcdef"))
+        );
+        assert_eq!(
+            describe("/home/.cargo/git/checkouts/repo-0123456789abcdef/0123456"),
+            (ItemKind::GitCheckout, some("repo"), some("0123456"))
+        );
+        assert_eq!(
+            describe("/target/debug/deps/libfoo_bar-0123456789abcdef.rlib"),
+            (
+                ItemKind::DepArtifact,
+                some("foo_bar"),
+                some("0123456789abcdef")
+            )
+        );
+        assert_eq!(
+            describe("/target/debug/deps/foo-0123456789abcdef.d"),
+            (ItemKind::DepArtifact, some("foo"), some("0123456789abcdef"))
+        );
+        assert_eq!(
+            describe("/target/debug/.fingerprint/foo-bar-0123456789abcdef"),
+            (
+                ItemKind::Fingerprint,
+                some("foo-bar"),
+                some("0123456789abcdef")
+            )
+        );
+        assert_eq!(
+            describe("/target/debug/build/foo-0123456789abcdef"),
+            (ItemKind::BuildDir, some("foo"), some("0123456789abcdef"))
+        );
+        // Only 16 lowercase hex digits are a metadata hash.
+        assert_eq!(
+            describe("/target/debug/deps/cargo-ci-precache"),
+            (ItemKind::DepArtifact, some("cargo_ci_precache"), None)
+        );
+        assert_eq!(
+            describe("/target/debug/build/foo-bar-0123456789ABCDEF"),
+            (ItemKind::BuildDir, some("foo-bar-0123456789ABCDEF"), None)
+        );
+        assert_eq!(
+            describe("/target/debug/incremental"),
+            (ItemKind::Other, None, None)
+        );
+    }
+
+    // The output format is relied on by other tools, so any change here is a breaking change.
+    #[test]
+    fn record_schema() {
+        let record = ItemRecord {
+            path: "/target/debug/deps/libfoo-0123456789abcdef.rlib".into(),
+            action: ItemAction::WouldDelete,
+            info: ItemInfo {
+                kind: ItemKind::DepArtifact,
+                name: Some("foo".into()),
+                hash: Some("0123456789abcdef".into()),
+            },
+            bytes: 1024,
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"path":"/target/debug/deps/libfoo-0123456789abcdef.rlib","action":"would-delete","kind":"dep-artifact","name":"foo","hash":"0123456789abcdef","bytes":1024}"#
+        );
+
+        let record = ItemRecord {
+            path: "/home/.cargo/git/db/repo-0123456789abcdef".into(),
+            action: ItemAction::Kept,
+            info: ItemInfo {
+                kind: ItemKind::GitDb,
+                name: None,
+                hash: None,
+            },
+            bytes: 0,
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"path":"/home/.cargo/git/db/repo-0123456789abcdef","action":"kept","kind":"git-db","name":null,"hash":null,"bytes":0}"#
+        );
+    }
+}
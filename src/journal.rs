@@ -0,0 +1,201 @@
+use crate::{effectiveness::list_dir, meta::Metadata, paths::write_atomic};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const VERSION: u32 = 1;
+
+/// A record of when each item in the global cargo cache was last referenced by the metadata given
+/// to any run. Unlike file modification times, this survives the cache being archived and
+/// restored.
+#[derive(Serialize, Deserialize)]
+pub struct Journal {
+    version: u32,
+    /// Path relative to the cargo home, with `/` separators -> seconds since the unix epoch.
+    entries: HashMap<String, u64>,
+}
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            entries: HashMap::new(),
+        }
+    }
+}
+impl Journal {
+    /// The default location of the journal in the given cargo home.
+    pub fn default_path(cargo_home: &Path) -> PathBuf {
+        cargo_home.join("ci-precache-journal")
+    }
+
+    /// Loads the journal. A missing journal is treated as empty.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("error reading journal {}", path.display()))
+            }
+        };
+        let journal: Self = serde_json::from_slice(&data)
+            .with_context(|| format!("error parsing journal {}", path.display()))?;
+        if journal.version != VERSION {
+            return Err(anyhow::Error::msg(format!(
+                "unsupported journal version {} in {}",
+                journal.version,
+                path.display()
+            )));
+        }
+        Ok(journal)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // Write to a temporary file first so an interrupted run can't leave a corrupt journal.
+        write_atomic(path, &serde_json::to_vec(self)?)
+            .with_context(|| format!("error writing journal {}", path.display()))
+    }
+
+    fn key(cargo_home: &Path, path: &Path) -> Option<String> {
+        let parts = path
+            .strip_prefix(cargo_home)
+            .ok()?
+            .iter()
+            .map(OsStr::to_str)
+            .collect::<Option<Vec<_>>>()?;
+        Some(parts.join("/"))
+    }
+
+    /// Records every existing cache item referenced by the metadata as used at the given time.
+    pub fn record(&mut self, cargo_home: &Path, meta: &Metadata, time: SystemTime) {
+        let time = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let registry_dir = cargo_home.join("registry");
+        let git_dir = cargo_home.join("git");
+        let mut paths = Vec::new();
+        for (registry, packages) in &meta.packages.registry {
+            for package in packages.keys() {
+                let mut crate_file = package.clone();
+                crate_file.push(".crate");
+                paths.push(registry_dir.join("cache").join(registry).join(crate_file));
+                paths.push(registry_dir.join("src").join(registry).join(package));
+            }
+        }
+        for repo in meta.packages.git.keys() {
+            paths.push(git_dir.join("db").join(repo));
+            // Packages read from a lockfile have the full commit hash, while checkouts are named
+            // with an abbreviated one.
+            let checkouts = list_dir(&git_dir.join("checkouts").join(repo)).unwrap_or_default();
+            paths.extend(checkouts.into_iter().filter(|checkout| {
+                let rev = checkout.file_name().unwrap_or_default();
+                meta.packages.uses_checkout(repo, rev)
+            }));
+        }
+
+        for path in paths.iter().filter(|p| p.exists()) {
+            if let Some(key) = Self::key(cargo_home, path) {
+                self.entries.insert(key, time);
+            }
+        }
+    }
+
+    /// Gets when the item at the given path was last referenced. For directories containing
+    /// multiple items this is the most recent reference to any of them.
+    pub fn last_use(&self, cargo_home: &Path, path: &Path) -> Option<SystemTime> {
+        let key = Self::key(cargo_home, path)?;
+        let prefix = format!("{}/", key);
+        self.entries
+            .iter()
+            .filter(|(k, _)| **k == key || k.starts_with(&prefix))
+            .map(|(_, &t)| SystemTime::UNIX_EPOCH + Duration::from_secs(t))
+            .max()
+    }
+
+    /// Removes entries for items which no longer exist.
+    pub fn compact(&mut self, cargo_home: &Path) {
+        self.entries.retain(|key, _| cargo_home.join(key).exists());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Journal;
+    use crate::meta::Metadata;
+    use crate::test_util::scratch_dir;
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn record_and_compact() {
+        let home = scratch_dir("journal_test");
+        let registry = home.join("registry/cache/example.com-0123456789abcdef");
+        fs::create_dir_all(&registry).unwrap();
+        fs::write(registry.join("foo-0.1.0.crate"), b"").unwrap();
+
+        let mut meta = Metadata::default();
+        let packages = meta
+            .packages
+            .registry
+            .entry("example.com-0123456789abcdef".into())
+            .or_default();
+        packages.insert("foo-0.1.0".into(), "foo".into());
+        packages.insert("bar-0.1.0".into(), "bar".into());
+        // Read from a lockfile, with the full commit hash.
+        let checkouts = home.join("git/checkouts/repo-0123456789abcdef");
+        fs::create_dir_all(checkouts.join("1234567")).unwrap();
+        fs::create_dir_all(checkouts.join("89abcde")).unwrap();
+        meta.packages
+            .git
+            .entry("repo-0123456789abcdef".into())
+            .or_default()
+            .insert(
+                "1234567890abcdef1234567890abcdef12345678".into(),
+                "baz".into(),
+            );
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut journal = Journal::default();
+        journal.record(&home, &meta, time);
+        assert_eq!(journal.entries.len(), 2);
+        assert_eq!(
+            journal.last_use(&home, &checkouts.join("1234567")),
+            Some(time)
+        );
+        assert_eq!(journal.last_use(&home, &checkouts.join("89abcde")), None);
+        assert_eq!(
+            journal.last_use(&home, &registry.join("foo-0.1.0.crate")),
+            Some(time)
+        );
+        assert_eq!(journal.last_use(&home, &registry), Some(time));
+        assert_eq!(
+            journal.last_use(&home, &registry.join("bar-0.1.0.crate")),
+            None
+        );
+
+        let path = Journal::default_path(&home);
+        journal.save(&path).unwrap();
+        let mut journal = Journal::load(&path).unwrap();
+        assert_eq!(journal.last_use(&home, &registry), Some(time));
+
+        fs::remove_file(registry.join("foo-0.1.0.crate")).unwrap();
+        fs::remove_dir_all(&checkouts).unwrap();
+        journal.compact(&home);
+        assert!(journal.entries.is_empty());
+
+        fs::write(&path, b"{ corrupt").unwrap();
+        assert!(Journal::load(&path).is_err());
+        assert!(Journal::load(&home.join("missing"))
+            .unwrap()
+            .entries
+            .is_empty());
+    }
+}
@@ -1,17 +1,69 @@
 use anyhow::{Context, Error, Result};
+#[cfg(feature = "checksums")]
+use rayon::prelude::*;
+#[cfg(feature = "checksums")]
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
     env,
-    ffi::OsStr,
-    fs, io, iter,
+    ffi::{OsStr, OsString},
+    fmt, fs, io, iter,
     path::{self, Path, PathBuf},
     process::{Command, Stdio},
+    time::{Duration, SystemTime},
 };
 
+pub mod artifact;
+use crate::artifact::{artifact_meta_hash, parse_artifact_stem, ArtifactKind, ArtifactName};
 mod meta;
-use crate::meta::Metadata;
+use crate::meta::{package_id_name_version, package_id_source};
+pub use crate::meta::{Metadata, PackageRef};
+mod cleaner;
+pub use crate::cleaner::Cleaner;
+mod dep_info;
+use crate::dep_info::EncodedDepInfo;
+mod effectiveness;
+pub use crate::effectiveness::{cache_effectiveness, target_effectiveness, Effectiveness, Totals};
+#[cfg(feature = "global-cache")]
+mod global_cache;
+#[cfg(feature = "global-cache")]
+pub use crate::global_cache::GlobalCache;
+#[cfg(feature = "lockfiles")]
+mod index;
+#[cfg(feature = "installs")]
+mod installs;
+#[cfg(feature = "installs")]
+pub use crate::installs::{check_installed_bins, remove_install_records, InstallReport};
+mod item;
+pub use crate::item::{describe_item, ItemAction, ItemInfo, ItemKind, ItemRecord};
+mod journal;
+pub use crate::journal::Journal;
+mod live;
+pub use crate::live::{live_cache_paths, LiveSet};
+#[cfg(feature = "lockfiles")]
+mod lockfile;
+#[cfg(feature = "lockfiles")]
+use crate::lockfile::Lockfile;
 mod fingerprint;
-use crate::fingerprint::Fingerprint;
+use crate::fingerprint::{Fingerprint, LocalFingerprints};
+mod paths;
+use crate::paths::PrefixMatcher;
+pub use crate::paths::{temp_path, write_atomic};
+mod plan;
+pub use crate::plan::{execute, Plan, PlanEnvironment, PlanItem, PlanReason};
+mod remove;
+pub use crate::remove::remove_tree;
+mod report;
+pub use crate::report::{cargo_home_report, CargoHomeReport, ReportEntry, REPORT_COMPONENTS};
+mod safety;
+pub use crate::safety::{check_cargo_cache_safety, check_target_safety};
+mod source;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod usage;
+pub use crate::usage::{disk_usage, format_size, last_modified, DiskUsage};
+mod version;
+use crate::version::Version;
 
 macro_rules! path {
     ($($c:expr),*) => {{
@@ -21,7 +73,59 @@ macro_rules! path {
     }};
 }
 
-pub struct MetadataCommand(Command);
+// How many lines of cargo's output are included when `cargo metadata` fails.
+const STDERR_LINES: usize = 20;
+
+/// The error returned by `MetadataCommand::exec` when cargo couldn't be run or failed. Other
+/// errors, e.g. unparseable output, are returned as is.
+#[derive(Debug)]
+pub enum MetadataError {
+    /// The cargo executable couldn't be found.
+    CargoNotFound { program: PathBuf },
+    /// Cargo ran, but exited unsuccessfully. `code` is `None` when it was killed by a signal.
+    Failed { code: Option<i32>, stderr: String },
+}
+impl MetadataError {
+    /// Everything cargo printed to stderr, if it ran.
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            Self::CargoNotFound { .. } => None,
+            Self::Failed { stderr, .. } => Some(stderr),
+        }
+    }
+}
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CargoNotFound { program } => write!(
+                f,
+                "error running cargo metadata: `{}` wasn't found, check that cargo is installed \
+                 and on the PATH, or set CARGO",
+                program.display()
+            ),
+            Self::Failed { code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "cargo metadata failed: exit code {}", code)?,
+                    None => write!(f, "cargo metadata failed: killed by a signal")?,
+                }
+                // Cargo prints the cause last, so earlier lines are dropped.
+                let lines: Vec<_> = stderr.trim_end().lines().collect();
+                let skipped = lines.len().saturating_sub(STDERR_LINES);
+                if skipped != 0 {
+                    write!(f, "\n... {} earlier lines", skipped)?;
+                }
+                for line in &lines[skipped..] {
+                    write!(f, "\n{}", line)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for MetadataError {}
+
+/// Runs `cargo metadata`. The second field is the rustup toolchain to run it with, if any.
+pub struct MetadataCommand(Command, Option<String>);
 impl MetadataCommand {
     #[allow(clippy::clippy::new_without_default)]
     pub fn new() -> Self {
@@ -30,8 +134,9 @@ impl MetadataCommand {
             .arg("--format-version")
             .arg("1")
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .stdin(Stdio::null());
-        Self(c)
+        Self(c, None)
     }
 
     pub fn current_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
@@ -74,109 +179,538 @@ impl MetadataCommand {
         self
     }
 
+    /// Sets `CARGO_HOME` for cargo.
+    pub fn cargo_home<P: AsRef<Path>>(&mut self, path: Option<P>) -> &mut Self {
+        if let Some(path) = path {
+            self.0.env("CARGO_HOME", path.as_ref());
+        }
+        self
+    }
+
+    /// Runs cargo from the given rustup toolchain, as with `cargo +toolchain`.
+    pub fn toolchain<S: Into<String>>(&mut self, toolchain: Option<S>) -> &mut Self {
+        self.1 = toolchain.map(Into::into);
+        self
+    }
+
     pub fn exec(&mut self) -> Result<Metadata> {
-        let output = self.0.output().context("error running cargo metadata")?;
+        let output = match &self.1 {
+            Some(toolchain) => {
+                let mut c = rustup_run(toolchain, "cargo")?;
+                c.args(self.0.get_args())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .stdin(Stdio::null());
+                for (key, value) in self.0.get_envs() {
+                    match value {
+                        Some(value) => c.env(key, value),
+                        None => c.env_remove(key),
+                    };
+                }
+                if let Some(dir) = self.0.get_current_dir() {
+                    c.current_dir(dir);
+                }
+                c.output()
+            }
+            None => self.0.output(),
+        };
+        let output = match output {
+            Ok(output) => output,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.1.is_none() => {
+                return Err(MetadataError::CargoNotFound {
+                    program: self.0.get_program().into(),
+                }
+                .into())
+            }
+            Err(e) => return Err(e).context("error running cargo metadata"),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
         if !output.status.success() {
-            return Err(Error::msg(format!(
-                "cargo metadata failed: exit code {:?}",
-                output.status.code()
-            )));
+            return Err(MetadataError::Failed {
+                code: output.status.code(),
+                stderr: stderr.into_owned(),
+            }
+            .into());
         }
+        // Pass on any warnings.
+        eprint!("{}", stderr);
 
-        serde_json::from_slice(&output.stdout).context("error parsing cargo metadata")
+        Metadata::from_slice(&output.stdout)
     }
 }
 
-fn extract_meta_hash(p: &OsStr) -> Option<&str> {
-    p.to_str()?.rsplitn(2, '-').next()
+/// Creates a command which runs the given tool, e.g. `cargo` or `rustc`, from a rustup toolchain.
+/// Fails if rustup isn't installed, or if the cargo running this isn't managed by rustup.
+fn rustup_run(toolchain: &str, tool: &str) -> Result<Command> {
+    // Rustup's proxies set `RUSTUP_HOME` for everything they run, so a cargo subcommand run by any
+    // other cargo won't have it.
+    if let (Some(cargo), None) = (env::var_os("CARGO"), env::var_os("RUSTUP_HOME")) {
+        return Err(Error::msg(format!(
+            "can't use toolchain `{}`, the cargo in use isn't managed by rustup: {}",
+            toolchain,
+            Path::new(&cargo).display()
+        )));
+    }
+    match Command::new("rustup")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::msg(format!(
+                "can't use toolchain `{}`, rustup isn't installed",
+                toolchain
+            )))
+        }
+        Err(e) => return Err(e).context("error running rustup"),
+    }
+    let mut c = Command::new("rustup");
+    c.arg("run").arg(toolchain).arg(tool);
+    Ok(c)
 }
 
-/// Calls delete for every item in the global cargo cache not referenced by the given metadata.
-///
-/// Notes: Only items in ~/.cargo/registry/cache and ~/.cargo/git/db are considered.
-/// Items in ~/.cargo/registry/src and ~/.cargo/git/checkouts are not deleted.
-pub fn clear_cargo_cache(meta: Metadata, delete: &mut dyn FnMut(&Path)) -> Result<()> {
-    let cargo_home = home::cargo_home()?;
-    let git_db_dir = path!(&cargo_home, "git", "db");
-    let git_checkout_dir = path!(&cargo_home, "git", "checkouts");
-    let registry_cache_dir = path!(&cargo_home, "registry", "cache");
+// Gets the package directory name, `{name}-{version}`, from the path to a `.crate` file. Anything
+// other than a `.crate` file isn't used by cargo.
+fn crate_file_package(path: &Path) -> Option<&OsStr> {
+    if path.extension() == Some(OsStr::new("crate")) {
+        path.file_stem()
+    } else {
+        None
+    }
+}
 
-    match git_db_dir.read_dir() {
-        Ok(iter) => {
-            for e in iter.filter_map(|e| e.ok()) {
-                let path = e.path();
-                match meta.packages.git.get(path.file_name().unwrap_or_default()) {
-                    Some(_) => (),
-                    None => delete(&path),
-                }
-            }
+fn src_dir_package(path: &Path) -> Option<&OsStr> {
+    path.file_name()
+}
+
+/// Options for clearing the global cargo cache.
+#[derive(Default, Clone)]
+pub struct CacheOptions {
+    /// The number of versions of each crate to keep in the registry, in addition to the versions
+    /// referenced by the metadata. The newest versions are kept.
+    pub keep_versions: usize,
+    /// Only delete unused items which cargo hasn't used within this duration, according to
+    /// cargo's global cache database. Items which aren't in the database are deleted as usual.
+    /// The database is only read with the `global-cache` feature.
+    pub max_age: Option<Duration>,
+    /// The journal used along with cargo's database with `max_age`. See `Journal`.
+    pub journal_path: Option<PathBuf>,
+    /// Registries to leave untouched. See `CacheOptions::skips_registry` for how these are matched.
+    pub exclude_registries: Vec<String>,
+    /// If not empty, only these registries are cleaned.
+    pub only_registries: Vec<String>,
+    /// The cargo home to clean. Defaults to `home::cargo_home()`, which honors `CARGO_HOME`.
+    pub cargo_home: Option<PathBuf>,
+}
+impl CacheOptions {
+    /// Gets the cargo home to clean.
+    pub fn cargo_home(&self) -> Result<PathBuf> {
+        match &self.cargo_home {
+            Some(path) => Ok(path.clone()),
+            None => Ok(home::cargo_home()?),
         }
-        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
-        Err(e) => {
-            return Err(e).with_context(|| format!("error reading dir: {}", git_db_dir.display()))
+    }
+
+    /// Checks whether the registry stored in the given directory, e.g.
+    /// `index.crates.io-1949cf8c6b5b557f`, should be skipped. Registries can be given either by
+    /// their directory name, the host part of the directory name, or their url.
+    pub fn skips_registry(&self, dir_name: &OsStr) -> bool {
+        let matches = |pattern: &String| registry_matches(pattern, dir_name);
+        (!self.only_registries.is_empty() && !self.only_registries.iter().any(matches))
+            || self.exclude_registries.iter().any(matches)
+    }
+}
+
+fn registry_matches(pattern: &str, dir_name: &OsStr) -> bool {
+    let dir_name = match dir_name.to_str() {
+        Some(name) => name,
+        None => return false,
+    };
+    if pattern == dir_name {
+        return true;
+    }
+    if let Some((host, hash)) = dir_name.rsplit_once('-') {
+        if pattern == host && hash.len() == 16 {
+            return true;
         }
     }
+    let sources = if pattern.starts_with("registry+") || pattern.starts_with("sparse+") {
+        vec![pattern.to_owned()]
+    } else if pattern.contains("://") {
+        vec![
+            format!("registry+{}", pattern),
+            format!("sparse+{}", pattern),
+        ]
+    } else {
+        Vec::new()
+    };
+    sources.iter().any(|source| {
+        source::registry_dir_names(source)
+            .iter()
+            .any(|n| n == dir_name)
+    })
+}
+
+// Gets when cargo last used an item according to its global cache database, if it can be read.
+#[cfg(feature = "global-cache")]
+fn global_cache_last_use(cargo_home: &Path) -> Option<impl Fn(&Path) -> Option<SystemTime>> {
+    let cache = GlobalCache::open(cargo_home).ok().flatten()?;
+    Some(move |path: &Path| cache.last_use(path).ok().flatten())
+}
 
-    match git_checkout_dir.read_dir() {
+// Without the `global-cache` feature only the journal is checked.
+#[cfg(not(feature = "global-cache"))]
+fn global_cache_last_use(_: &Path) -> Option<fn(&Path) -> Option<SystemTime>> {
+    None
+}
+
+// Wraps delete to skip items used more recently than `max_age`, according to either cargo's global
+// cache database or the journal. If neither can be read, nothing is skipped.
+fn skip_recently_used<'a>(
+    cargo_home: &'a Path,
+    options: &CacheOptions,
+    delete: &'a mut dyn FnMut(&Path),
+) -> Box<dyn FnMut(&Path) + 'a> {
+    let max_age = match options.max_age {
+        Some(max_age) => max_age,
+        None => return Box::new(delete),
+    };
+    // A duration the clock can't go back by keeps everything.
+    let cutoff = match SystemTime::now().checked_sub(max_age) {
+        Some(cutoff) => cutoff,
+        None => return Box::new(|_| ()),
+    };
+    let cache = global_cache_last_use(cargo_home);
+    let journal = options
+        .journal_path
+        .as_ref()
+        .and_then(|path| Journal::load(path).ok());
+    match (cache, journal) {
+        (None, None) => Box::new(delete),
+        (cache, journal) => Box::new(move |path| {
+            let cache_use = cache.as_ref().and_then(|last_use| last_use(path));
+            let journal_use = journal.as_ref().and_then(|j| j.last_use(cargo_home, path));
+            match cache_use.max(journal_use) {
+                Some(last_use) if last_use > cutoff => (),
+                _ => delete(path),
+            }
+        }),
+    }
+}
+
+// Splits `{name}-{version}` into the crate name and its version.
+fn split_package_version(package: &str) -> Option<(&str, Version)> {
+    // Both the name and any pre-release part of the version may contain `-`.
+    package.match_indices('-').find_map(|(i, _)| {
+        let version = Version::parse(&package[i + 1..])?;
+        Some((&package[..i], version))
+    })
+}
+
+// Calls delete for every item in a registry directory, either `registry/cache` or `registry/src`,
+// not referenced by the given metadata. `package` gets the `{name}-{version}` of a package from
+// an item in one of the registries.
+fn clear_registry_dir(
+    meta: &Metadata,
+    dir: &Path,
+    package: fn(&Path) -> Option<&OsStr>,
+    options: &CacheOptions,
+    delete: &mut dyn FnMut(&Path),
+) -> Result<()> {
+    match dir.read_dir() {
         Ok(iter) => {
             for e in iter.filter_map(|e| e.ok()) {
+                if options.skips_registry(&e.file_name()) {
+                    continue;
+                }
+                let registry = e.file_name();
                 let path = e.path();
-                match meta.packages.git.get(path.file_name().unwrap_or_default()) {
-                    Some(checkouts) => {
-                        for e in e
-                            .path()
-                            .read_dir()
-                            .with_context(|| format!("error reading directory {}", path.display()))?
-                            .filter_map(|e| e.ok())
+                if !meta.packages.uses_registry(&registry)
+                    && options.keep_versions == 0
+                    && options.max_age.is_none()
+                {
+                    delete(&path);
+                    continue;
+                }
+
+                // Unreferenced versions of each crate. The newest ones are kept.
+                let mut unused = BTreeMap::<String, Vec<(Version, PathBuf)>>::new();
+                for e in e
+                    .path()
+                    .read_dir()
+                    .with_context(|| format!("error reading directory {}", path.display()))?
+                    .filter_map(|e| e.ok())
+                {
+                    let path = e.path();
+                    let package = package(&path);
+                    match package {
+                        Some(package)
+                            if meta.packages.uses_registry_package(&registry, package) =>
                         {
-                            if !checkouts.contains_key(&e.file_name()) {
-                                delete(&e.path());
-                            }
+                            continue
                         }
+                        _ => (),
+                    }
+                    match package
+                        .and_then(OsStr::to_str)
+                        .and_then(split_package_version)
+                    {
+                        Some((name, version)) if options.keep_versions != 0 => {
+                            unused.entry(name.into()).or_default().push((version, path))
+                        }
+                        _ => delete(&path),
+                    }
+                }
+                for mut versions in unused.into_values() {
+                    versions.sort_by(|(x, _), (y, _)| y.cmp(x));
+                    for (_, path) in versions.iter().skip(options.keep_versions) {
+                        delete(path);
                     }
-                    None => delete(&path),
                 }
             }
+            Ok(())
         }
-        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
-        Err(e) => {
-            return Err(e)
-                .with_context(|| format!("error reading dir: {}", git_checkout_dir.display()))
-        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("error reading dir: {}", dir.display())),
     }
+}
 
-    match registry_cache_dir.read_dir() {
-        Ok(iter) => {
-            for e in iter.filter_map(|e| e.ok()) {
-                let path = e.path();
-                match meta
-                    .packages
-                    .registry
-                    .get(path.file_name().unwrap_or_default())
-                {
-                    Some(packages) => {
-                        for e in e
-                            .path()
-                            .read_dir()
-                            .with_context(|| format!("error reading directory {}", path.display()))?
-                            .filter_map(|e| e.ok())
-                        {
-                            if !packages.contains_key(&e.file_name()) {
-                                delete(&e.path());
-                            }
-                        }
-                    }
-                    None => delete(&path),
+#[cfg(feature = "checksums")]
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Calls delete for every `.crate` file in ~/.cargo/registry/cache which would be kept by
+/// `clear_cargo_cache`, but doesn't match the checksum in the workspace's `Cargo.lock`. Files are
+/// hashed in parallel.
+///
+/// Notes: Packages which aren't in the lockfile, or don't have a checksum, aren't checked.
+#[cfg(feature = "checksums")]
+pub fn verify_crate_checksums(
+    meta: &Metadata,
+    options: &CacheOptions,
+    delete: &mut dyn FnMut(&Path),
+) -> Result<()> {
+    let lockfile = Lockfile::read(&meta.workspace_root.join("Cargo.lock"))?;
+    let registry_cache_dir = path!(&options.cargo_home()?, "registry", "cache");
+
+    let mut files = Vec::new();
+    for (registry, packages) in &meta.packages.registry {
+        if options.skips_registry(registry) {
+            continue;
+        }
+        for (package, id) in packages {
+            let checksum = match (package_id_source(id), package.to_str()) {
+                (Some(source), Some(package)) => lockfile.checksum(source, package),
+                _ => None,
+            };
+            if let Some(checksum) = checksum {
+                let mut file_name = package.clone();
+                file_name.push(".crate");
+                let path = path!(&registry_cache_dir, registry, file_name);
+                if path.is_file() {
+                    files.push((path, checksum));
                 }
             }
         }
-        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+    }
+
+    let mut mismatched = files
+        .into_par_iter()
+        .filter_map(|(path, checksum)| match sha256_file(&path) {
+            Ok(digest) if digest == checksum => None,
+            Ok(_) => Some(Ok(path)),
+            Err(e) => Some(Err(e).with_context(|| format!("error reading {}", path.display()))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    mismatched.sort();
+    for path in &mismatched {
+        delete(path);
+    }
+    Ok(())
+}
+
+/// Calls delete for every `.crate` file in ~/.cargo/registry/cache which has been yanked, and
+/// isn't in the workspace's `Cargo.lock`. Only the index data available locally is used to check
+/// whether a version is yanked.
+///
+/// Notes: Versions whose yank status can't be determined are never deleted.
+#[cfg(feature = "lockfiles")]
+pub fn remove_yanked(
+    meta: &Metadata,
+    options: &CacheOptions,
+    delete: &mut dyn FnMut(&Path),
+) -> Result<()> {
+    let lockfile = Lockfile::read(&meta.workspace_root.join("Cargo.lock"))?;
+    let cargo_home = options.cargo_home()?;
+    let registry_cache_dir = path!(&cargo_home, "registry", "cache");
+    let registry_index_dir = path!(&cargo_home, "registry", "index");
+
+    let registries = match registry_cache_dir.read_dir() {
+        Ok(iter) => iter,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
         Err(e) => {
             return Err(e)
                 .with_context(|| format!("error reading dir: {}", registry_cache_dir.display()))
         }
+    };
+    for e in registries.filter_map(|e| e.ok()) {
+        if options.skips_registry(&e.file_name()) {
+            continue;
+        }
+        let path = e.path();
+        let index_dir = registry_index_dir.join(e.file_name());
+        let mut yanked = HashMap::new();
+
+        for e in path
+            .read_dir()
+            .with_context(|| format!("error reading directory {}", path.display()))?
+            .filter_map(|e| e.ok())
+        {
+            let path = e.path();
+            let package = match crate_file_package(&path).and_then(OsStr::to_str) {
+                Some(package) if !lockfile.contains_registry_package(package) => package,
+                _ => continue,
+            };
+            let name = match split_package_version(package) {
+                Some((name, _)) => name,
+                None => continue,
+            };
+            if !yanked.contains_key(name) {
+                let versions = index::yanked_versions(&index_dir, name)
+                    .with_context(|| format!("error reading the index for {}", name))?;
+                yanked.insert(name.to_owned(), versions);
+            }
+            let version = &package[name.len() + 1..];
+            let is_yanked = yanked[name].as_ref().and_then(|v| v.get(version)).copied();
+            if is_yanked == Some(true) {
+                delete(&path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets the repositories in ~/.cargo/git/db which are referenced by the given metadata, and
+/// won't be deleted by `clear_cargo_cache`.
+pub fn retained_git_dbs(meta: &Metadata, options: &CacheOptions) -> Result<Vec<PathBuf>> {
+    let git_db_dir = path!(&options.cargo_home()?, "git", "db");
+    match git_db_dir.read_dir() {
+        Ok(iter) => Ok(iter
+            .filter_map(|e| e.ok())
+            .filter(|e| meta.packages.uses_git_repo(&e.file_name()))
+            .map(|e| e.path())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("error reading dir: {}", git_db_dir.display())),
     }
+}
 
+/// Finds the lockfiles to use with `metadata_from_lockfiles`. If the pattern is a directory every
+/// `Cargo.lock` inside it is found, otherwise it's used as a glob pattern.
+#[cfg(feature = "lockfiles")]
+pub fn find_lockfiles(pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = if Path::new(pattern).is_dir() {
+        let dir = glob::Pattern::escape(pattern);
+        format!("{}/**/Cargo.lock", dir.trim_end_matches(&['/', '\\'][..]))
+    } else {
+        pattern.into()
+    };
+    let paths = glob::glob(&pattern).with_context(|| format!("invalid pattern: {}", pattern))?;
+    Ok(paths
+        .filter_map(|p| p.ok())
+        .filter(|p| p.is_file())
+        .collect())
+}
+
+/// Creates metadata containing every registry and git package from the given lockfiles, for use
+/// with `clear_cargo_cache`. Lockfiles which can't be read are passed to `error` and skipped.
+#[cfg(feature = "lockfiles")]
+pub fn metadata_from_lockfiles(paths: &[PathBuf], error: &mut dyn FnMut(&Path, Error)) -> Metadata {
+    let mut meta = Metadata::default();
+    for path in paths {
+        match Lockfile::read(path) {
+            Ok(lockfile) => meta.packages.add_lockfile(&lockfile),
+            Err(e) => error(path, e),
+        }
+    }
+    meta
+}
+
+/// Calls delete for every item in the global cargo cache not referenced by the given metadata.
+///
+/// Notes: Only items in ~/.cargo/registry/cache, ~/.cargo/git/db and ~/.cargo/git/checkouts are
+/// considered. Items in ~/.cargo/registry/src are handled by `clear_registry_src`.
+///
+/// This is the same as `Cleaner::new(meta).cache_options(options.clone()).run_cargo_cache(delete)`.
+pub fn clear_cargo_cache(
+    meta: Metadata,
+    options: &CacheOptions,
+    delete: &mut dyn FnMut(&Path),
+) -> Result<()> {
+    Cleaner::new(meta)
+        .cache_options(options.clone())
+        .run_cargo_cache(delete)
+}
+
+/// Calls delete for every unpacked package in ~/.cargo/registry/src not referenced by the given
+/// metadata.
+///
+/// Unpacked packages which are still referenced are kept, as cargo would otherwise have to unpack
+/// them again.
+pub fn clear_registry_src(
+    meta: &Metadata,
+    options: &CacheOptions,
+    delete: &mut dyn FnMut(&Path),
+) -> Result<()> {
+    let cargo_home = options.cargo_home()?;
+    let registry_src_dir = path!(&cargo_home, "registry", "src");
+    let delete = &mut *skip_recently_used(&cargo_home, options, delete);
+    clear_registry_dir(meta, &registry_src_dir, src_dir_package, options, delete)
+}
+
+/// Calls delete for every unpacked source in ~/.cargo/registry/src which no longer has a `.crate`
+/// file in ~/.cargo/registry/cache. Unlike the other functions this doesn't use any metadata.
+pub fn clear_orphaned_src(options: &CacheOptions, delete: &mut dyn FnMut(&Path)) -> Result<()> {
+    let cargo_home = options.cargo_home()?;
+    let registry_cache_dir = path!(&cargo_home, "registry", "cache");
+    let registry_src_dir = path!(&cargo_home, "registry", "src");
+
+    let registries = match registry_src_dir.read_dir() {
+        Ok(iter) => iter,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("error reading dir: {}", registry_src_dir.display()))
+        }
+    };
+    for e in registries.filter_map(|e| e.ok()) {
+        if options.skips_registry(&e.file_name()) {
+            continue;
+        }
+        let path = e.path();
+        let cache_dir = registry_cache_dir.join(e.file_name());
+        if !cache_dir.is_dir() {
+            delete(&path);
+            continue;
+        }
+
+        for e in path
+            .read_dir()
+            .with_context(|| format!("error reading directory {}", path.display()))?
+            .filter_map(|e| e.ok())
+        {
+            let mut file_name = e.file_name();
+            file_name.push(".crate");
+            if !cache_dir.join(file_name).is_file() {
+                delete(&e.path());
+            }
+        }
+    }
     Ok(())
 }
 
@@ -200,8 +734,12 @@ fn read_first_dep(file: &str) -> Option<PathBuf> {
     Some(path.into())
 }
 
-fn get_dep_features<'a>(cargo_home: &Path, meta: &'a Metadata, dep: &Path) -> Option<&'a str> {
-    if let Ok(dep) = dep.strip_prefix(cargo_home) {
+fn get_dep_features<'a>(
+    cargo_home: &mut PrefixMatcher,
+    meta: &'a Metadata,
+    dep: &Path,
+) -> Option<&'a BTreeSet<String>> {
+    if let Some(dep) = cargo_home.strip_prefix(dep) {
         let mut c = dep.components();
         match c.next() {
             Some(path::Component::Normal(x)) if x == "git" => {
@@ -215,7 +753,7 @@ fn get_dep_features<'a>(cargo_home: &Path, meta: &'a Metadata, dep: &Path) -> Op
                         .git
                         .get(repo)
                         .and_then(|x| x.get(rev))
-                        .and_then(|id| meta.package_features.get(id).map(String::as_str)),
+                        .and_then(|id| meta.package_features.get(id)),
                     _ => None,
                 }
             }
@@ -230,7 +768,7 @@ fn get_dep_features<'a>(cargo_home: &Path, meta: &'a Metadata, dep: &Path) -> Op
                         .registry
                         .get(registry)
                         .and_then(|x| x.get(package))
-                        .and_then(|id| meta.package_features.get(id).map(String::as_str)),
+                        .and_then(|id| meta.package_features.get(id)),
                     _ => None,
                 }
             }
@@ -241,62 +779,118 @@ fn get_dep_features<'a>(cargo_home: &Path, meta: &'a Metadata, dep: &Path) -> Op
     }
 }
 
-fn read_dep_file<'a>(
-    path: &Path,
-    cargo_home: &Path,
-    meta: &'a Metadata,
-) -> Result<(String, Option<&'a str>)> {
+fn read_dep_file(path: &Path) -> Result<PathBuf> {
     let s = fs::read_to_string(&path)
         .with_context(|| format!("error reading file: {}", path.display()))?;
 
-    let dep = read_first_dep(&s)
-        .ok_or_else(|| Error::msg(format!("error parsing file: {}", path.display())))?;
+    read_first_dep(&s).ok_or_else(|| Error::msg(format!("error parsing file: {}", path.display())))
+}
 
-    let hash: String = extract_meta_hash(path.file_stem().unwrap_or_default())
-        .ok_or_else(|| {
-            Error::msg(format!(
-                "error extracting metadata hash from: {}",
-                path.display()
-            ))
-        })?
-        .into();
-    Ok((hash, get_dep_features(cargo_home, meta, &dep)))
+// Gets the root of the package a unit was built from, if the metadata identifies exactly one.
+// Local packages are matched by name. Packages from a registry or git repository also have to
+// match the unit's precalculated fingerprint, so a unit built from a previous version is never
+// resolved to the current one.
+fn unit_package_root<'a>(
+    meta: &'a Metadata,
+    package: &str,
+    precalculated: Option<&str>,
+) -> Option<&'a Path> {
+    let mut roots = meta.packages.roots.iter().filter(|(id, _)| {
+        let (name, version) = match package_id_name_version(id) {
+            Some(x) => x,
+            None => return false,
+        };
+        if name.replace('-', "_") != package {
+            return false;
+        }
+        if meta.packages.local.contains_key(id.as_str()) {
+            return true;
+        }
+        let rev = package_id_source(id)
+            .and_then(|s| s.rsplit_once('#'))
+            .map(|(_, rev)| rev);
+        matches!(precalculated, Some(p) if p == version || Some(p) == rev)
+    });
+    match (roots.next(), roots.next()) {
+        (Some((_, root)), None) => Some(root),
+        _ => None,
+    }
 }
 
-pub fn clear_target(meta: Metadata, delete: &mut dyn FnMut(&Path)) -> Result<()> {
-    let cargo_home = home::cargo_home()?;
+// Gets the root source file from the binary dep-info file in a unit's fingerprint directory.
+// Returns `None` if there is no dep-info file, or the root source file can't be determined from
+// it.
+fn read_encoded_dep_file(meta: &Metadata, unit_path: &Path) -> Result<Option<PathBuf>> {
+    // A unit's files are named after it, e.g. `lib-foo.json` and `dep-lib-foo`.
+    let mut names = Vec::new();
+    for e in unit_path
+        .read_dir()
+        .with_context(|| format!("error reading dir: {}", unit_path.display()))?
+    {
+        let path = e
+            .with_context(|| format!("error reading dir: {}", unit_path.display()))?
+            .path();
+        if path.extension() == Some(OsStr::new("json")) {
+            names.extend(path.file_stem().map(OsStr::to_os_string));
+        }
+    }
+    let name = match &names[..] {
+        [name] => name,
+        _ => return Ok(None),
+    };
+    let mut dep_name = OsString::from("dep-");
+    dep_name.push(name);
+    let dep_path = unit_path.join(dep_name);
+    let info = match fs::read(&dep_path) {
+        Ok(s) => EncodedDepInfo::parse(&s),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("error reading file: {}", dep_path.display()))
+        }
+    };
+    let info = match info {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+
+    let mut json_name = name.clone();
+    json_name.push(".json");
+    let local = fs::read(unit_path.join(json_name))
+        .ok()
+        .and_then(|s| serde_json::from_slice::<LocalFingerprints>(&s).ok());
+    let precalculated = local.as_ref().and_then(LocalFingerprints::precalculated);
+    let package_root = parse_artifact_stem(unit_path.file_name().unwrap_or_default())
+        .and_then(|unit| unit_package_root(meta, &unit.crate_name, precalculated));
+    Ok(info.root_path(package_root))
+}
 
-    let target_dir = path!(&meta.target_directory, "debug");
-    let build_dir = path!(&target_dir, "build");
-    let deps_dir = path!(&target_dir, "deps");
-    let fingerprint_dir = path!(&target_dir, ".fingerprint");
+// Gets the root source file for each unit, keyed by metadata hash. Cargo's own dep-info file in
+// the fingerprint directory is preferred, with the `.d` files in the build and deps directories
+// used when its root can't be resolved to a package. With `lenient`, `.d` files which can't be
+// read are skipped.
+fn read_unit_roots(
+    meta: &Metadata,
+    target_dir: &Path,
+    lenient: bool,
+) -> Result<HashMap<String, PathBuf>> {
+    let build_dir = path!(target_dir, "build");
+    let deps_dir = path!(target_dir, "deps");
+    let fingerprint_dir = path!(target_dir, ".fingerprint");
 
-    match target_dir.read_dir() {
-        Ok(iter) => {
-            for item in iter {
-                let item =
-                    item.with_context(|| format!("error reading dir: {}", target_dir.display()))?;
-                let path = item.path();
-                let name = path.file_name().unwrap_or_default();
-                if !(name == ".cargo-lock"
-                    || name == ".fingerprint"
-                    || name == "build"
-                    || name == "deps")
-                {
-                    delete(&path)
-                }
+    let mut unit_roots = HashMap::<String, PathBuf>::new();
+    for e in fingerprint_dir
+        .read_dir()
+        .with_context(|| format!("error reading dir: {}", fingerprint_dir.display()))?
+    {
+        let unit_path = e
+            .with_context(|| format!("error reading dir: {}", fingerprint_dir.display()))?
+            .path();
+        if let Some(root) = read_encoded_dep_file(meta, &unit_path)? {
+            if let Some(hash) = artifact_meta_hash(unit_path.file_name().unwrap_or_default()) {
+                unit_roots.insert(hash, root);
             }
         }
-        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
-        Err(e) => {
-            return Err(e).with_context(|| format!("error reading dir: {}", target_dir.display()))
-        }
     }
-
-    // Get a list of metadata hashes for either local packages, or downloaded packages which are no
-    // longer depended on.
-    let mut outdated_meta_hashes = HashSet::<String>::new();
-    let mut meta_hash_features = HashMap::<String, &str>::new();
     for path in build_dir
         .read_dir()
         .with_context(|| format!("error reading dir: {}", build_dir.display()))?
@@ -304,7 +898,7 @@ pub fn clear_target(meta: Metadata, delete: &mut dyn FnMut(&Path)) -> Result<()>
             let e = e.with_context(|| format!("error reading dir: {}", build_dir.display()))?;
             Ok(e.path())
         })
-        .chain(iter::once(Ok(deps_dir.clone())))
+        .chain(iter::once(Ok(deps_dir)))
     {
         let path = path?;
         for e in path
@@ -314,18 +908,228 @@ pub fn clear_target(meta: Metadata, delete: &mut dyn FnMut(&Path)) -> Result<()>
             let path = e
                 .with_context(|| format!("error reading dir: {}", path.display()))?
                 .path();
-            if path.extension() != Some(OsStr::new("d")) {
-                continue;
+            let hash = match parse_artifact_stem(path.file_name().unwrap_or_default()) {
+                Some(ArtifactName {
+                    meta_hash: Some(hash),
+                    kind: ArtifactKind::DepInfo,
+                    ..
+                }) => hash,
+                _ => continue,
+            };
+            let root = match read_dep_file(&path) {
+                Ok(root) => root,
+                Err(_) if lenient => continue,
+                Err(e) => return Err(e),
+            };
+            unit_roots.entry(hash).or_insert(root);
+        }
+    }
+    Ok(unit_roots)
+}
+
+/// Evidence used to decide whether a target directory was built from the current workspace.
+pub struct TargetEvidence {
+    pub target_dir: PathBuf,
+    pub workspace_members: Vec<String>,
+    /// The number of units in the fingerprint directory.
+    pub units: usize,
+    /// Units named after a workspace member.
+    pub member_units: Vec<String>,
+    /// Root source files from dep-info files which are inside a workspace member.
+    pub member_sources: Vec<PathBuf>,
+}
+impl TargetEvidence {
+    /// Whether the target directory appears to belong to the workspace. A target directory with
+    /// nothing built is always considered to belong.
+    pub fn is_match(&self) -> bool {
+        self.units == 0 || !self.member_units.is_empty() || !self.member_sources.is_empty()
+    }
+}
+impl fmt::Display for TargetEvidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "target directory: {}", self.target_dir.display())?;
+        writeln!(
+            f,
+            "workspace members: {}",
+            self.workspace_members.join(", ")
+        )?;
+        writeln!(f, "fingerprint units: {}", self.units)?;
+        writeln!(
+            f,
+            "units named after a workspace member: {}",
+            self.member_units.len()
+        )?;
+        for unit in &self.member_units {
+            writeln!(f, "    {}", unit)?;
+        }
+        write!(
+            f,
+            "units built from a workspace member's sources: {}",
+            self.member_sources.len()
+        )?;
+        for path in &self.member_sources {
+            write!(f, "\n    {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether the target directory was built from the workspace described by the metadata.
+///
+/// Cleaning a target directory belonging to a different workspace would delete nearly everything
+/// in it.
+pub fn check_target(meta: &Metadata) -> Result<TargetEvidence> {
+    let target_dir = path!(&meta.target_directory, "debug");
+    let fingerprint_dir = path!(&target_dir, ".fingerprint");
+
+    let members: Vec<_> = meta.workspace_packages().collect();
+    let mut evidence = TargetEvidence {
+        target_dir: target_dir.clone(),
+        workspace_members: members.iter().map(|p| p.name.clone()).collect(),
+        units: 0,
+        member_units: Vec::new(),
+        member_sources: Vec::new(),
+    };
+
+    let iter = match fingerprint_dir.read_dir() {
+        Ok(iter) => iter,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(evidence),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("error reading dir: {}", fingerprint_dir.display()))
+        }
+    };
+    for e in iter {
+        let name = e
+            .with_context(|| format!("error reading dir: {}", fingerprint_dir.display()))?
+            .file_name();
+        evidence.units += 1;
+        let name = name.to_string_lossy();
+        if let Some((package, _)) = name.rsplit_once('-') {
+            if members.iter().any(|p| p.name == package) {
+                evidence.member_units.push(name.into_owned());
             }
-            let (hash, features) = read_dep_file(&path, &cargo_home, &meta)?;
-            match features {
-                None => {
-                    outdated_meta_hashes.insert(hash);
-                }
-                Some(f) => {
-                    meta_hash_features.insert(hash, f);
+        }
+    }
+    if evidence.units == 0 {
+        return Ok(evidence);
+    }
+
+    let mut member_roots: Vec<_> = members
+        .iter()
+        .map(|p| PrefixMatcher::new(p.root.clone()))
+        .collect();
+    for (_, root) in read_unit_roots(meta, &target_dir, false)? {
+        if member_roots
+            .iter_mut()
+            .any(|m| m.strip_prefix(&root).is_some())
+        {
+            evidence.member_sources.push(root);
+        }
+    }
+
+    evidence.member_units.sort();
+    evidence.member_sources.sort();
+    Ok(evidence)
+}
+
+/// Why `clear_target` removes a unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutdatedReason {
+    /// Built from a package in the global cargo cache which is no longer used, e.g. the previous
+    /// version of an updated dependency.
+    Unused,
+    /// Built from a package outside the global cargo cache, e.g. a workspace member. These are
+    /// rebuilt whenever they change, so they're never kept.
+    Local,
+    /// Built with different features than the package now uses.
+    FeatureChange,
+    /// One of its dependencies is removed.
+    DependencyChanged,
+}
+impl OutdatedReason {
+    /// A short description, e.g. `feature change`.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Unused => "no longer used",
+            Self::Local => "local",
+            Self::FeatureChange => "feature change",
+            Self::DependencyChanged => "dependency changed",
+        }
+    }
+}
+
+/// A unit in the target directory removed by `clear_target`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutdatedUnit {
+    /// The package the unit was built from as `{name} {version}`, if it could be determined. Git
+    /// checkouts which are no longer used have their revision instead of a version.
+    pub package: Option<String>,
+    pub reason: OutdatedReason,
+}
+
+// Gets `{name} {version}` of the package containing a unit's root source file.
+fn root_package(cargo_home: &mut PrefixMatcher, meta: &Metadata, root: &Path) -> Option<String> {
+    let name_version = |id: &String| {
+        package_id_name_version(id).map(|(name, version)| format!("{} {}", name, version))
+    };
+    if let Some(rel) = cargo_home.strip_prefix(root) {
+        let names: Vec<_> = rel.iter().take(4).filter_map(OsStr::to_str).collect();
+        return match names[..] {
+            ["registry", "src", _, package] => split_package_version(package)
+                .map(|(name, version)| format!("{} {}", name, version)),
+            ["git", "checkouts", repo, rev] => {
+                match meta.packages.git.get(OsStr::new(repo)).and_then(|revs| {
+                    revs.iter()
+                        .find(|(r, _)| r.to_string_lossy().starts_with(rev))
+                        .map(|(_, id)| id)
+                }) {
+                    Some(id) => name_version(id),
+                    None => {
+                        let name = repo.rsplit_once('-').map_or(repo, |(name, _)| name);
+                        Some(format!("{} #{}", name, rev))
+                    }
                 }
             }
+            _ => None,
+        };
+    }
+    // The innermost package, since packages can be nested inside each other.
+    meta.packages
+        .local
+        .iter()
+        .filter(|(_, package)| root.starts_with(&package.root))
+        .max_by_key(|(_, package)| package.root.components().count())
+        .and_then(|(id, _)| name_version(id))
+}
+
+// Finds the metadata hash of every unit in the target directory, along with the ones which have
+// to be removed, either because they're outdated or because one of their dependencies is. With
+// `lenient`, units whose files can't be parsed are skipped, so they're never removed.
+fn find_outdated_units(
+    meta: &Metadata,
+    cargo_home: &mut PrefixMatcher,
+    target_dir: &Path,
+    lenient: bool,
+) -> Result<(Vec<String>, HashMap<String, OutdatedUnit>)> {
+    let fingerprint_dir = path!(target_dir, ".fingerprint");
+    let unit_roots = read_unit_roots(meta, target_dir, lenient)?;
+
+    // Get a list of metadata hashes for either local packages, or downloaded packages which are no
+    // longer depended on.
+    let mut outdated_meta_hashes = HashMap::<String, OutdatedReason>::new();
+    let mut meta_hash_features = HashMap::<String, &BTreeSet<String>>::new();
+    for (hash, root) in &unit_roots {
+        match get_dep_features(cargo_home, meta, root) {
+            None if cargo_home.strip_prefix(root).is_some() => {
+                outdated_meta_hashes.insert(hash.clone(), OutdatedReason::Unused);
+            }
+            None => {
+                outdated_meta_hashes.insert(hash.clone(), OutdatedReason::Local);
+            }
+            Some(f) => {
+                meta_hash_features.insert(hash.clone(), f);
+            }
         }
     }
     let outdated_meta_hashes = outdated_meta_hashes;
@@ -340,6 +1144,11 @@ pub fn clear_target(meta: Metadata, delete: &mut dyn FnMut(&Path)) -> Result<()>
         let unit_path = e
             .with_context(|| format!("error reading dir: {}", fingerprint_dir.display()))?
             .path();
+        // Nothing else can refer to a unit without a metadata hash.
+        let hash = match artifact_meta_hash(unit_path.file_name().unwrap_or_default()) {
+            Some(hash) => hash,
+            None => continue,
+        };
         for e in unit_path
             .read_dir()
             .with_context(|| format!("error reading dir: {}", unit_path.display()))?
@@ -350,21 +1159,18 @@ pub fn clear_target(meta: Metadata, delete: &mut dyn FnMut(&Path)) -> Result<()>
             if file_path.extension() != Some(OsStr::new("json")) {
                 continue;
             }
-            let s = fs::read(&file_path)
-                .with_context(|| format!("error reading file: {}", file_path.display()))?;
-            let f = serde_json::from_slice::<Fingerprint>(&s)
-                .with_context(|| format!("error parsing file: {}", file_path.display()))?;
-            fingerprints.push((
-                extract_meta_hash(unit_path.file_stem().unwrap_or_default())
-                    .ok_or_else(|| {
-                        Error::msg(format!(
-                            "error extracting metadata hash from: {}",
-                            unit_path.display()
-                        ))
-                    })?
-                    .into(),
-                f,
-            ));
+            let f = fs::read(&file_path)
+                .with_context(|| format!("error reading file: {}", file_path.display()))
+                .and_then(|s| {
+                    serde_json::from_slice::<Fingerprint>(&s)
+                        .with_context(|| format!("error parsing file: {}", file_path.display()))
+                });
+            let f = match f {
+                Ok(f) => f,
+                Err(_) if lenient => break,
+                Err(e) => return Err(e),
+            };
+            fingerprints.push((hash, f));
             break;
         }
     }
@@ -392,52 +1198,257 @@ pub fn clear_target(meta: Metadata, delete: &mut dyn FnMut(&Path)) -> Result<()>
 
     // Flag all fingerprints which have a metadata hash we are removing. Then propagate that flag
     // through all the reverse dependencies.
-    let mut flagged_deps: Vec<_> = fingerprints.iter().map(|_| false).collect();
-    let mut deps_to_flag: Vec<_> = fingerprints
-        .iter()
-        .enumerate()
-        .filter(|(_, (h, f))| {
-            outdated_meta_hashes.contains(h)
-                || meta_hash_features
-                    .get(h)
-                    .map_or(false, |&feat| feat != f.features)
-        })
-        .map(|(i, _)| i)
-        .collect();
+    let mut flagged_deps: Vec<Option<OutdatedReason>> = fingerprints.iter().map(|_| None).collect();
+    let mut deps_to_flag = Vec::new();
+    for (i, (h, f)) in fingerprints.iter().enumerate() {
+        let reason = match (outdated_meta_hashes.get(h), meta_hash_features.get(h)) {
+            (Some(&reason), _) => reason,
+            (None, Some(feats)) if !feats.contains(&f.features) => OutdatedReason::FeatureChange,
+            _ => continue,
+        };
+        flagged_deps[i] = Some(reason);
+        deps_to_flag.extend_from_slice(&rev_deps[i]);
+    }
 
     while let Some(i) = deps_to_flag.pop() {
-        if flagged_deps[i] {
+        if flagged_deps[i].is_some() {
             continue;
         }
-        flagged_deps[i] = true;
+        flagged_deps[i] = Some(OutdatedReason::DependencyChanged);
         deps_to_flag.extend_from_slice(&rev_deps[i]);
     }
 
     // From the list of flagged fingerprints we now have the full list of metadata hashes which
     // have to be removed.
-    let meta_hashes_to_remove: HashSet<_> = flagged_deps
+    let meta_hashes_to_remove = flagged_deps
         .iter()
         .enumerate()
-        .filter(|(_, f)| **f)
-        .map(|(i, _)| fingerprints[i].0.as_str())
+        .filter_map(|(i, reason)| {
+            let hash = &fingerprints[i].0;
+            let unit = OutdatedUnit {
+                package: unit_roots
+                    .get(hash)
+                    .and_then(|root| root_package(cargo_home, meta, root)),
+                reason: (*reason)?,
+            };
+            Some((hash.clone(), unit))
+        })
         .collect();
+    let meta_hashes = fingerprints.into_iter().map(|(hash, _)| hash).collect();
+    Ok((meta_hashes, meta_hashes_to_remove))
+}
 
-    let dirs = [&build_dir, &deps_dir, &fingerprint_dir];
-    for dir in &dirs {
-        for e in dir
-            .read_dir()
-            .with_context(|| format!("error reading dir: {}", dir.display()))?
-        {
-            let path = e
-                .with_context(|| format!("error reading dir: {}", dir.display()))?
-                .path();
-            if let Some(hash) = extract_meta_hash(path.file_stem().unwrap_or_default()) {
-                if meta_hashes_to_remove.contains(hash) {
-                    delete(&path);
-                }
-            }
-        }
+/// Gets the metadata hashes of the units in the target directory which `clear_target` would keep,
+/// along with any added to the metadata by a `LiveSet`.
+pub fn live_meta_hashes(meta: &Metadata, cargo_home: &Path) -> Result<BTreeSet<String>> {
+    let target_dir = path!(&meta.target_directory, "debug");
+    let mut live: BTreeSet<_> = meta.live_meta_hashes.iter().cloned().collect();
+    if !target_dir.is_dir() {
+        return Ok(live);
     }
+    let mut cargo_home = PrefixMatcher::new(cargo_home.into());
+    let (meta_hashes, outdated) = find_outdated_units(meta, &mut cargo_home, &target_dir, false)?;
+    live.extend(
+        meta_hashes
+            .into_iter()
+            .filter(|hash| !outdated.contains_key(hash)),
+    );
+    Ok(live)
+}
 
-    Ok(())
+/// Gets every unit in the target directory which `clear_target` would remove, keyed by metadata
+/// hash, along with the package it was built from and why it's removed. Units kept by a `LiveSet`
+/// are still included.
+pub fn outdated_units(meta: &Metadata, cargo_home: &Path) -> Result<HashMap<String, OutdatedUnit>> {
+    let target_dir = path!(&meta.target_directory, "debug");
+    if !target_dir.is_dir() {
+        return Ok(HashMap::new());
+    }
+    let mut cargo_home = PrefixMatcher::new(cargo_home.into());
+    Ok(find_outdated_units(meta, &mut cargo_home, &target_dir, false)?.1)
+}
+
+/// Calls delete for every item in the target directory which is no longer used. Dependencies
+/// from the given cargo home are recognized as coming from a registry or git repository.
+///
+/// This is the same as `Cleaner::new(meta).cargo_home(cargo_home).run_target(delete)`.
+pub fn clear_target(
+    meta: Metadata,
+    cargo_home: &Path,
+    delete: &mut dyn FnMut(&Path),
+) -> Result<()> {
+    Cleaner::new(meta).cargo_home(cargo_home).run_target(delete)
+}
+
+/// Finds every item `clear_target` would delete, along with its size and why, without deleting
+/// anything. The plan can be applied with `execute`.
+///
+/// This is the same as `Cleaner::new(meta.clone()).cache_options(options.clone()).plan_target()`.
+pub fn plan_target(meta: &Metadata, options: &CacheOptions) -> Result<Plan> {
+    Cleaner::new(meta.clone())
+        .cache_options(options.clone())
+        .plan_target()
+}
+
+/// Finds every item `clear_cargo_cache` would delete, along with its size and why, without
+/// deleting anything. The plan can be applied with `execute`.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use cargo_ci_precache::{CacheOptions, MetadataCommand};
+///
+/// let meta = MetadataCommand::new().exec()?;
+/// let mut plan = cargo_ci_precache::plan_cargo_cache(&meta, &CacheOptions::default())?;
+/// plan.items.retain(|item| item.bytes > 1024 * 1024);
+/// cargo_ci_precache::execute(&plan, &mut |item| println!("{}", item.path.display()));
+/// # Ok(())
+/// # }
+/// ```
+pub fn plan_cargo_cache(meta: &Metadata, options: &CacheOptions) -> Result<Plan> {
+    Cleaner::new(meta.clone())
+        .cache_options(options.clone())
+        .plan_cargo_cache()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        root_package, split_package_version, CacheOptions, Metadata, MetadataCommand,
+        MetadataError, PrefixMatcher,
+    };
+    use crate::{meta::LocalPackage, version::Version};
+    use std::{ffi::OsStr, path::Path, process::Command};
+
+    #[test]
+    fn metadata_errors() {
+        let e = MetadataCommand(Command::new("cargo-ci-precache-missing-cargo"), None)
+            .exec()
+            .map(drop)
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<MetadataError>(),
+            Some(MetadataError::CargoNotFound { .. })
+        ));
+
+        let e = MetadataCommand::new()
+            .manifest_path(Some("missing/Cargo.toml"))
+            .exec()
+            .map(drop)
+            .unwrap_err();
+        let e = e.downcast_ref::<MetadataError>().unwrap();
+        assert!(matches!(e, MetadataError::Failed { code: Some(_), .. }));
+        assert!(e.stderr().unwrap().contains("Cargo.toml"), "{}", e);
+        assert!(e.to_string().contains("Cargo.toml"), "{}", e);
+
+        let stderr: String = (1..=30).map(|i| format!("line {}\n", i)).collect();
+        let e = MetadataError::Failed {
+            code: Some(101),
+            stderr,
+        };
+        let message = e.to_string();
+        assert!(message.starts_with("cargo metadata failed: exit code 101\n... 10 earlier lines\n"));
+        assert_eq!(message.lines().count(), 22);
+        assert!(message.contains("\nline 11\n"));
+        assert!(message.ends_with("\nline 30"));
+    }
+
+    #[test]
+    fn split_versions() {
+        let split = |s| split_package_version(s).map(|(name, v)| (name, v.to_string()));
+        assert_eq!(split("serde-1.0.118"), Some(("serde", "1.0.118".into())));
+        assert_eq!(
+            split("proc-macro2-1.0.24"),
+            Some(("proc-macro2", "1.0.24".into()))
+        );
+        assert_eq!(
+            split("foo-bar-1.0.0-rc-1"),
+            Some(("foo-bar", "1.0.0-rc-1".into()))
+        );
+        assert_eq!(
+            split("foo-0.1.0+build.5"),
+            Some(("foo", "0.1.0+build.5".into()))
+        );
+        assert_eq!(split("foo"), None);
+        assert_eq!(split("foo-bar"), None);
+
+        let v = |s| Version::parse(s).unwrap();
+        assert!(v("1.0.0-alpha") < v("1.0.0"));
+        assert!(v("1.0.0") < v("1.0.1-rc.1"));
+    }
+
+    #[test]
+    fn registry_filters() {
+        let crates_io = OsStr::new("index.crates.io-1949cf8c6b5b557f");
+        let private = OsStr::new("private.example.com-0123456789abcdef");
+        let options = |exclude: &[&str], only: &[&str]| CacheOptions {
+            exclude_registries: exclude.iter().map(|&s| s.into()).collect(),
+            only_registries: only.iter().map(|&s| s.into()).collect(),
+            ..Default::default()
+        };
+
+        let o = options(&[], &[]);
+        assert!(!o.skips_registry(crates_io) && !o.skips_registry(private));
+
+        let o = options(&["private.example.com"], &[]);
+        assert!(!o.skips_registry(crates_io) && o.skips_registry(private));
+
+        let o = options(&["private.example.com-0123456789abcdef"], &[]);
+        assert!(!o.skips_registry(crates_io) && o.skips_registry(private));
+
+        let o = options(&["https://github.com/rust-lang/crates.io-index"], &[]);
+        assert!(o.skips_registry(crates_io) && !o.skips_registry(private));
+
+        let o = options(&[], &["https://index.crates.io/"]);
+        assert!(!o.skips_registry(crates_io) && o.skips_registry(private));
+
+        let o = options(&["index.crates.io"], &["index.crates.io"]);
+        assert!(o.skips_registry(crates_io) && o.skips_registry(private));
+    }
+
+    #[test]
+    fn root_packages() {
+        let mut meta = Metadata::default();
+        meta.packages
+            .git
+            .entry("dep-0123456789abcdef".into())
+            .or_default()
+            .insert(
+                "0123456789abcdef0123456789abcdef01234567".into(),
+                "dep 0.2.0 (git+https://example.com/dep#0123456789abcdef0123456789abcdef01234567)"
+                    .into(),
+            );
+        for (id, root) in &[
+            ("app 0.1.0 (path+file:///ws)", "/ws"),
+            ("nested 0.3.0 (path+file:///ws/nested)", "/ws/nested"),
+        ] {
+            meta.packages.local.insert(
+                String::from(*id),
+                LocalPackage {
+                    name: String::new(),
+                    root: root.into(),
+                },
+            );
+        }
+        let mut home = PrefixMatcher::new("/home/.cargo".into());
+        let mut package = |root: &str| root_package(&mut home, &meta, Path::new(root));
+
+        assert_eq!(
+            package("/home/.cargo/registry/src/index.crates.io-1949cf8c6b5b557f/serde_json-1.0.117/src/lib.rs"),
+            Some("serde_json 1.0.117".into())
+        );
+        assert_eq!(
+            package("/home/.cargo/git/checkouts/dep-0123456789abcdef/0123456/src/lib.rs"),
+            Some("dep 0.2.0".into())
+        );
+        assert_eq!(
+            package("/home/.cargo/git/checkouts/old-0123456789abcdef/89abcde/src/lib.rs"),
+            Some("old #89abcde".into())
+        );
+        assert_eq!(package("/ws/src/main.rs"), Some("app 0.1.0".into()));
+        assert_eq!(
+            package("/ws/nested/src/lib.rs"),
+            Some("nested 0.3.0".into())
+        );
+        assert_eq!(package("/elsewhere/src/lib.rs"), None);
+    }
 }
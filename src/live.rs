@@ -0,0 +1,291 @@
+use crate::{crate_file_package, effectiveness::list_dir, meta::Metadata, paths::write_atomic};
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const VERSION: u32 = 1;
+
+// Converts one of the package maps to a sorted map of strings.
+fn sorted_packages(
+    packages: &HashMap<OsString, HashMap<OsString, String>>,
+) -> BTreeMap<String, BTreeMap<String, String>> {
+    packages
+        .iter()
+        .map(|(dir, packages)| {
+            let packages = packages
+                .iter()
+                .map(|(name, id)| (name.to_string_lossy().into_owned(), id.clone()))
+                .collect();
+            (dir.to_string_lossy().into_owned(), packages)
+        })
+        .collect()
+}
+
+// Adds a sorted map of packages back into one of the package maps.
+fn add_packages(
+    packages: &BTreeMap<String, BTreeMap<String, String>>,
+    to: &mut HashMap<OsString, HashMap<OsString, String>>,
+) {
+    for (dir, packages) in packages {
+        to.entry(dir.into()).or_default().extend(
+            packages
+                .iter()
+                .map(|(name, id)| (OsString::from(name), id.clone())),
+        );
+    }
+}
+
+/// Everything a run considered live, so several jobs can each analyze a workspace and a final job
+/// can clean while keeping everything any of them uses. Everything is sorted so the same analysis
+/// always gives the same file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LiveSet {
+    version: u32,
+    /// Metadata hashes of the units in the target directory.
+    pub meta_hashes: BTreeSet<String>,
+    /// registry -> `{name}-{version}` -> package id map of packages in the global cargo cache.
+    pub registry: BTreeMap<String, BTreeMap<String, String>>,
+    /// repository -> commit -> package id map of packages in the global cargo cache.
+    pub git: BTreeMap<String, BTreeMap<String, String>>,
+}
+impl LiveSet {
+    /// Creates a set with every package in the metadata, and any metadata hashes already added to
+    /// it. Hashes found by analyzing the target directory can be added with `live_meta_hashes`.
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            version: VERSION,
+            meta_hashes: meta.live_meta_hashes.iter().cloned().collect(),
+            registry: sorted_packages(&meta.packages.registry),
+            git: sorted_packages(&meta.packages.git),
+        }
+    }
+
+    /// Adds everything in the set to the metadata, so it's kept when cleaning.
+    pub fn add_to(&self, meta: &mut Metadata) {
+        meta.live_meta_hashes
+            .extend(self.meta_hashes.iter().cloned());
+        add_packages(&self.registry, &mut meta.packages.registry);
+        add_packages(&self.git, &mut meta.packages.git);
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = fs::read(path)
+            .with_context(|| format!("error reading live hashes {}", path.display()))?;
+        let value: serde_json::Value = serde_json::from_slice(&data)
+            .with_context(|| format!("error parsing live hashes {}", path.display()))?;
+        match value.get("version").and_then(serde_json::Value::as_u64) {
+            Some(version) if version == u64::from(VERSION) => (),
+            Some(version) => {
+                return Err(Error::msg(format!(
+                    "unsupported live hashes version {} in {}",
+                    version,
+                    path.display()
+                )))
+            }
+            None => {
+                return Err(Error::msg(format!(
+                    "missing live hashes version in {}",
+                    path.display()
+                )))
+            }
+        }
+        serde_json::from_value(value)
+            .with_context(|| format!("error parsing live hashes {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("error writing live hashes {}", path.display()))
+    }
+}
+
+/// Gets every item in the global cargo cache which the metadata references, without deleting
+/// anything. This is the inverse of `clear_cargo_cache`: the index of each registry in use, the
+/// `.crate` file of each registry package, and the repository and checkout of each git package.
+///
+/// Items are matched the same way as when cleaning, so `clear_cargo_cache` never deletes any of
+/// them. Unpacked sources in ~/.cargo/registry/src aren't included. The paths are sorted.
+pub fn live_cache_paths(meta: &Metadata, cargo_home: &Path) -> Result<Vec<PathBuf>> {
+    let packages = &meta.packages;
+    let registry_dir = cargo_home.join("registry");
+    let git_dir = cargo_home.join("git");
+    let mut paths = Vec::new();
+
+    for index in list_dir(&registry_dir.join("index"))? {
+        if packages.uses_registry(index.file_name().unwrap_or_default()) {
+            paths.push(index);
+        }
+    }
+    for registry in list_dir(&registry_dir.join("cache"))? {
+        let name = registry.file_name().unwrap_or_default();
+        if !packages.uses_registry(name) {
+            continue;
+        }
+        for file in list_dir(&registry)? {
+            if matches!(crate_file_package(&file), Some(p) if packages.uses_registry_package(name, p))
+            {
+                paths.push(file);
+            }
+        }
+    }
+
+    for repo in list_dir(&git_dir.join("db"))? {
+        if packages.uses_git_repo(repo.file_name().unwrap_or_default()) {
+            paths.push(repo);
+        }
+    }
+    for repo in list_dir(&git_dir.join("checkouts"))? {
+        let name = repo.file_name().unwrap_or_default();
+        if !packages.uses_git_repo(name) {
+            continue;
+        }
+        for checkout in list_dir(&repo)? {
+            if packages.uses_checkout(name, checkout.file_name().unwrap_or_default()) {
+                paths.push(checkout);
+            }
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(all(test, feature = "lockfiles"))]
+mod test {
+    use super::{live_cache_paths, LiveSet};
+    use crate::test_util::scratch_dir;
+    use crate::{clear_cargo_cache, lockfile::Lockfile, meta::Metadata, CacheOptions};
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    fn write_and_union() {
+        let dir = scratch_dir("live_test");
+        let lockfile = |contents: &str| {
+            let path = dir.join("Cargo.lock");
+            fs::write(&path, contents).unwrap();
+            Lockfile::read(&path).unwrap()
+        };
+
+        let mut meta = Metadata::default();
+        meta.packages.add_lockfile(&lockfile(
+            "[[package]]\n\
+             name = \"serde\"\n\
+             version = \"1.0.0\"\n\
+             source = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+        ));
+        meta.live_meta_hashes.insert("0123456789abcdef".into());
+        let live = LiveSet::from_metadata(&meta);
+        let path = dir.join("live.json");
+        live.write(&path).unwrap();
+        assert_eq!(LiveSet::read(&path).unwrap(), live);
+
+        // Writing the same set again gives the same file.
+        let contents = fs::read(&path).unwrap();
+        LiveSet::from_metadata(&meta).write(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), contents);
+
+        let mut other = Metadata::default();
+        other.packages.add_lockfile(&lockfile(
+            "[[package]]\n\
+             name = \"log\"\n\
+             version = \"0.4.0\"\n\
+             source = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+        ));
+        LiveSet::read(&path).unwrap().add_to(&mut other);
+        assert!(other.live_meta_hashes.contains("0123456789abcdef"));
+        let names = other.packages.package_names();
+        assert!(names.contains("serde 1.0.0") && names.contains("log 0.4.0"));
+
+        let json = fs::read_to_string(&path)
+            .unwrap()
+            .replace("\"version\": 1", "\"version\": 2");
+        fs::write(&path, json).unwrap();
+        let e = LiveSet::read(&path).err().unwrap();
+        assert!(e.to_string().contains("unsupported live hashes version 2"));
+    }
+
+    #[test]
+    fn live_cache_items() {
+        let home = scratch_dir("live_cache_test");
+        let lockfile = Lockfile::parse(
+            r#"
+[[package]]
+name = "cfg-if"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "foo"
+version = "0.1.0"
+source = "git+https://example.com/foo/bar#0123456789abcdef0123456789abcdef01234567"
+"#,
+        )
+        .unwrap();
+        let mut meta = Metadata::default();
+        meta.packages.add_lockfile(&lockfile);
+
+        let registry = "index.crates.io-1949cf8c6b5b557f";
+        let repo = &crate::source::git_dir_names("git+https://example.com/foo/bar")[0];
+        let dirs = [
+            home.join("registry/index").join(registry),
+            home.join("registry/index/example.com-0123456789abcdef"),
+            home.join("registry/cache").join(registry),
+            home.join("registry/cache/example.com-0123456789abcdef"),
+            home.join("git/db").join(repo),
+            home.join("git/db/other-0123456789abcdef"),
+            home.join("git/checkouts").join(repo).join("0123456"),
+            home.join("git/checkouts").join(repo).join("fedcba9"),
+            home.join("git/checkouts/other-0123456789abcdef/0123456"),
+        ];
+        for dir in &dirs {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for file in &[
+            "cfg-if-1.0.0.crate",
+            "cfg-if-0.1.0.crate",
+            "cfg-if-1.0.0.txt",
+        ] {
+            fs::write(home.join("registry/cache").join(registry).join(file), "").unwrap();
+        }
+
+        let live = live_cache_paths(&meta, &home).unwrap();
+        assert_eq!(
+            live,
+            [
+                home.join("git/checkouts").join(repo).join("0123456"),
+                home.join("git/db").join(repo),
+                home.join("registry/cache")
+                    .join(registry)
+                    .join("cfg-if-1.0.0.crate"),
+                home.join("registry/index").join(registry),
+            ]
+        );
+
+        // Nothing live is deleted, and everything else in the cache is.
+        let options = CacheOptions {
+            cargo_home: Some(home.clone()),
+            ..CacheOptions::default()
+        };
+        let mut deleted = Vec::new();
+        clear_cargo_cache(meta, &options, &mut |p| deleted.push(PathBuf::from(p))).unwrap();
+        for path in &live {
+            assert!(
+                !path.ancestors().any(|p| deleted.iter().any(|d| d == p)),
+                "{}",
+                path.display()
+            );
+        }
+        assert!(deleted.contains(&home.join("git/checkouts").join(repo).join("fedcba9")));
+        assert!(deleted.contains(
+            &home
+                .join("registry/cache")
+                .join(registry)
+                .join("cfg-if-0.1.0.crate")
+        ));
+    }
+}
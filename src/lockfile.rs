@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+    checksum: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawLockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+    /// Version 1 lockfiles store checksums here, keyed by `checksum {name} {version} ({source})`.
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+/// The packages and checksums recorded in a `Cargo.lock` file.
+#[derive(Default)]
+pub struct Lockfile {
+    /// (name, version, source) for every package which isn't local.
+    pub packages: Vec<(String, String, String)>,
+    /// (source, `{name}-{version}`) -> sha256 checksum
+    checksums: HashMap<(String, String), String>,
+    /// `{name}-{version}` for every package from a registry.
+    registry_packages: HashSet<String>,
+}
+impl Lockfile {
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("error reading lockfile {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("error parsing lockfile {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let raw: RawLockfile = toml::from_str(contents)?;
+        let mut packages = Vec::new();
+        let mut checksums = HashMap::new();
+        let mut registry_packages = HashSet::new();
+
+        for p in raw.package {
+            let source = p.source.as_deref().unwrap_or_default();
+            if source.starts_with("registry+") || source.starts_with("sparse+") {
+                registry_packages.insert(format!("{}-{}", p.name, p.version));
+            }
+            if let (Some(source), Some(checksum)) = (&p.source, p.checksum) {
+                checksums.insert(
+                    (source.clone(), format!("{}-{}", p.name, p.version)),
+                    checksum,
+                );
+            }
+            if let Some(source) = p.source {
+                packages.push((p.name, p.version, source));
+            }
+        }
+        for (key, checksum) in raw.metadata {
+            let parsed = key.strip_prefix("checksum ").and_then(|key| {
+                let (package, source) = key.strip_suffix(')')?.split_once(" (")?;
+                let (name, version) = package.split_once(' ')?;
+                Some((source.to_owned(), format!("{}-{}", name, version)))
+            });
+            // Packages without a checksum are listed as `<none>`.
+            if let (Some(key), false) = (parsed, checksum == "<none>") {
+                checksums.insert(key, checksum);
+            }
+        }
+
+        Ok(Self {
+            packages,
+            checksums,
+            registry_packages,
+        })
+    }
+
+    /// Checks whether the package `{name}-{version}` from any registry is in the lockfile.
+    pub fn contains_registry_package(&self, package: &str) -> bool {
+        self.registry_packages.contains(package)
+    }
+
+    /// Gets the checksum for the package `{name}-{version}` from the given source.
+    #[cfg_attr(not(feature = "checksums"), allow(dead_code))]
+    pub fn checksum(&self, source: &str, package: &str) -> Option<&str> {
+        self.checksums
+            .get(&(source.to_owned(), package.to_owned()))
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Lockfile;
+
+    const CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+    #[test]
+    fn parse_v1() {
+        let lockfile = Lockfile::parse(
+            r#"
+[[package]]
+name = "cfg-if"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "local"
+version = "0.1.0"
+dependencies = ["cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)"]
+
+[metadata]
+"checksum cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)" = "baf1de31"
+"checksum other 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)" = "<none>"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            lockfile.checksum(CRATES_IO, "cfg-if-1.0.0"),
+            Some("baf1de31")
+        );
+        assert_eq!(lockfile.checksum(CRATES_IO, "other-1.0.0"), None);
+        assert_eq!(lockfile.checksum(CRATES_IO, "local-0.1.0"), None);
+        assert!(lockfile.contains_registry_package("cfg-if-1.0.0"));
+        assert!(!lockfile.contains_registry_package("local-0.1.0"));
+    }
+
+    #[test]
+    fn parse_v2() {
+        let lockfile = Lockfile::parse(
+            r#"
+version = 3
+
+[[package]]
+name = "cfg-if"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "baf1de31"
+
+[[package]]
+name = "local"
+version = "0.1.0"
+dependencies = ["cfg-if"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            lockfile.checksum(CRATES_IO, "cfg-if-1.0.0"),
+            Some("baf1de31")
+        );
+        assert_eq!(
+            lockfile.checksum("sparse+https://index.crates.io/", "cfg-if-1.0.0"),
+            None
+        );
+    }
+}
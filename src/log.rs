@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The severity of a log record.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+}
+impl LogLevel {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LogRecord<'a> {
+    time: String,
+    level: LogLevel,
+    message: &'a str,
+}
+
+fn format_record(json: bool, time: SystemTime, level: LogLevel, message: &str) -> String {
+    let time = humantime::format_rfc3339_millis(time).to_string();
+    if json {
+        let record = LogRecord {
+            time,
+            level,
+            message,
+        };
+        // Serializing a struct of strings can't fail.
+        serde_json::to_string(&record).unwrap_or_default() + "\n"
+    } else {
+        // Continuation lines are indented so each record can still be told apart.
+        let mut s = format!("{} {:<7} ", time, level.name());
+        s.push_str(&message.replace('\n', "\n    "));
+        s.push('\n');
+        s
+    }
+}
+
+/// A detailed, timestamped log of a run, written as either plain text or JSON lines.
+pub struct LogFile {
+    file: File,
+    path: PathBuf,
+    json: bool,
+}
+impl LogFile {
+    /// Creates the log file, replacing any existing file.
+    pub fn create(path: &Path, json: bool) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("error creating log file {}", path.display()))?;
+        Ok(Self {
+            file,
+            path: path.into(),
+            json,
+        })
+    }
+
+    /// Writes a single record. Each record is written and flushed immediately so the log is
+    /// complete up to the point a run crashes.
+    pub fn record(&mut self, level: LogLevel, message: &str) -> Result<()> {
+        let record = format_record(self.json, SystemTime::now(), level, message);
+        self.file
+            .write_all(record.as_bytes())
+            .and_then(|_| self.file.flush())
+            .with_context(|| format!("error writing log file {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_record, LogFile, LogLevel};
+    use cargo_ci_precache::test_util::scratch_dir;
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn log_records() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_500);
+        assert_eq!(
+            format_record(false, time, LogLevel::Info, "deleted foo"),
+            "1970-01-01T00:00:01.500Z info    deleted foo\n"
+        );
+        assert_eq!(
+            format_record(
+                false,
+                time,
+                LogLevel::Error,
+                "error removing foo\nnot found"
+            ),
+            "1970-01-01T00:00:01.500Z error   error removing foo\n    not found\n"
+        );
+        assert_eq!(
+            format_record(true, time, LogLevel::Warning, "a\nb"),
+            "{\"time\":\"1970-01-01T00:00:01.500Z\",\"level\":\"warning\",\"message\":\"a\\nb\"}\n"
+        );
+
+        let dir = scratch_dir("log_test");
+        let path = dir.join("log.jsonl");
+        let mut log = LogFile::create(&path, true).unwrap();
+        log.record(LogLevel::Info, "first").unwrap();
+        log.record(LogLevel::Info, "second").unwrap();
+        // Records must be readable without closing the file.
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert!(contents.lines().all(|l| l.contains("\"level\":\"info\"")));
+    }
+}
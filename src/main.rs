@@ -1,26 +1,249 @@
 use anyhow::{Context, Error, Result};
-use cargo_ci_precache::MetadataCommand;
-use clap::Clap;
+use cargo_ci_precache::{
+    format_size, CacheOptions, Cleaner, DiskUsage, GlobalCache, ItemAction, ItemInfo, ItemRecord,
+    Journal, LiveSet, Metadata, MetadataCommand, OutdatedUnit, Plan, PlanEnvironment, PlanItem,
+    PlanReason,
+};
+use clap::{AppSettings, ArgEnum, Clap, FromArgMatches, IntoApp, ValueHint};
 use std::{
-    env, fs, io,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    io::{self, Write},
     path::{Path, PathBuf},
-    time::SystemTime,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-#[derive(Clap)]
+mod archive;
+mod completions;
+use crate::completions::Shell;
+mod config;
+mod log;
+use crate::log::{LogFile, LogLevel};
+mod mtimes;
+use crate::mtimes::Mtimes;
+mod restore;
+use crate::restore::MoveLog;
+mod summary;
+use crate::summary::{Comparison, RunSummary};
+
+// Writes a record to the log file, if there is one.
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => { write_log(LogLevel::$level, &format!($($arg)*)) };
+}
+
+// Prints a warning about a problem which doesn't stop the run.
+macro_rules! warn {
+    ($($arg:tt)*) => { print_warning(&format!($($arg)*)) };
+}
+
+// The subcommands which delete items, and which a plan can be written for.
+#[derive(Clap, Clone, Copy, PartialEq)]
 pub enum Mode {
-    /// Clears the global cargo cache
     CargoCache,
-    /// Clears the projects target directory
     Target,
+    InstalledBins,
+}
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Self::CargoCache => "cargo-cache",
+            Self::Target => "target",
+            Self::InstalledBins => "installed-bins",
+        }
+    }
+}
+
+#[derive(Clap, PartialEq)]
+pub enum OutputFormat {
+    /// One path per line, only when doing a dry run
+    Text,
+    /// A single JSON document once finished
+    Json,
+    /// One JSON record per line as each item is processed
+    JsonLines,
+}
+
+#[derive(Clap, PartialEq)]
+pub enum LogFormat {
+    Text,
+    JsonLines,
+}
+
+#[derive(Clap, Clone, Copy)]
+pub enum RelativeTo {
+    Target,
+    CargoHome,
+    Cwd,
+}
+
+#[derive(Clap, Clone, Copy, PartialEq)]
+pub enum PackRoot {
+    /// The target directory
+    Target,
+    /// ~/.cargo/registry
+    Registry,
+    /// ~/.cargo/git
+    Git,
 }
 
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Jason Newcomb <jsnewcomb@pm.me>")]
 struct Args {
-    /// Path to Cargo.toml
-    #[clap(long, parse(from_os_str))]
-    pub manifest_path: Option<PathBuf>,
+    #[clap(flatten)]
+    pub global: GlobalArgs,
+
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+// Options shared by every subcommand. They can be given before or after the subcommand.
+#[derive(Clap)]
+pub struct GlobalArgs {
+    /// Read options from the given config file instead of the ci-precache.toml next to
+    /// Cargo.toml. Options given on the command line or by CARGO_CI_PRECACHE_* environment
+    /// variables take precedence over the config file
+    // Only read by `config::merge`, before the arguments are parsed.
+    #[allow(dead_code)]
+    #[clap(long, global = true, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+
+    /// Print the options set by the command line, the environment and the config file, and where
+    /// each came from, then exit
+    #[clap(long, global = true)]
+    pub show_config: bool,
+
+    /// The cargo home to clean or report on. Defaults to $CARGO_HOME, or ~/.cargo
+    #[clap(long, global = true, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    pub cargo_home: Option<PathBuf>,
+
+    /// Print warnings as GitHub Actions annotations, and add a summary of the run to the job's
+    /// step summary
+    #[clap(long, global = true)]
+    pub github: bool,
+
+    /// Write a JSON summary of the run to the given file, even if the run fails
+    #[clap(long, global = true, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub summary_json: Option<PathBuf>,
+
+    /// Write a detailed, timestamped log of every decision, deletion and error to the given file,
+    /// regardless of what's printed
+    #[clap(long, global = true, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub log_file: Option<PathBuf>,
+
+    /// The format of the log file
+    #[clap(long, global = true, arg_enum, default_value = "text")]
+    pub log_format: LogFormat,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Clears the global cargo cache
+    CargoCache {
+        #[clap(flatten)]
+        args: CargoCacheArgs,
+        #[clap(flatten)]
+        delete: DeleteArgs,
+    },
+    /// Clears the projects target directory
+    Target {
+        #[clap(flatten)]
+        args: TargetArgs,
+        #[clap(flatten)]
+        delete: DeleteArgs,
+    },
+    /// Checks the binaries in ~/.cargo/bin against cargo's install records
+    InstalledBins {
+        #[clap(flatten)]
+        args: InstalledBinsArgs,
+        #[clap(flatten)]
+        delete: DeleteArgs,
+    },
+    /// Reports what's taking up space in the global cargo cache without changing anything
+    Report(ReportArgs),
+    /// Prints every item in the global cargo cache which is in use, one path per line, e.g. to
+    /// save only those to a CI cache. These are exactly the items clearing the cache keeps, other
+    /// than unpacked sources
+    ListLive(ListLiveArgs),
+    /// Prints a completion script for the given shell
+    Completions(CompletionsArgs),
+    /// Writes a plan of everything a cleaning subcommand would delete, without deleting anything
+    #[clap(setting = AppSettings::SubcommandRequiredElseHelp)]
+    Plan(PlanCommand),
+    /// Deletes the items in a plan written by the plan subcommand, skipping any which have changed
+    /// since
+    Apply(ApplyArgs),
+    /// Moves the directories moved into a run's temp directory back
+    Restore(RestoreArgs),
+    /// Saves or restores the modification times of the files in the target directory
+    #[clap(setting = AppSettings::SubcommandRequiredElseHelp)]
+    Mtimes(MtimesCommand),
+    /// Writes an archive of everything cleaning would keep
+    Pack(PackArgs),
+    /// Unpacks an archive written by the pack subcommand
+    Unpack(UnpackArgs),
+}
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::CargoCache { .. } => "cargo-cache",
+            Self::Target { .. } => "target",
+            Self::InstalledBins { .. } => "installed-bins",
+            Self::Report(_) => "report",
+            Self::ListLive(_) => "list-live",
+            Self::Completions(_) => "completions",
+            Self::Plan(_) => "plan",
+            Self::Apply(_) => "apply",
+            Self::Restore(_) => "restore",
+            Self::Mtimes(_) => "mtimes",
+            Self::Pack(_) => "pack",
+            Self::Unpack(_) => "unpack",
+        }
+    }
+
+    // Whether the run leaves everything in place.
+    fn dry_run(&self) -> bool {
+        match self {
+            Self::CargoCache { delete, .. }
+            | Self::Target { delete, .. }
+            | Self::InstalledBins { delete, .. } => delete.dry_run,
+            Self::Apply(args) => args.delete.dry_run,
+            Self::Restore(args) => args.dry_run,
+            Self::Mtimes(MtimesCommand::Restore(args)) => args.dry_run,
+            Self::Report(_)
+            | Self::ListLive(_)
+            | Self::Completions(_)
+            | Self::Plan(_)
+            | Self::Mtimes(MtimesCommand::Save(_))
+            | Self::Pack(_)
+            | Self::Unpack(_) => true,
+        }
+    }
+}
+
+// How the project's packages are found.
+#[derive(Clap)]
+pub struct ProjectArgs {
+    /// Path to Cargo.toml. Can be given multiple times to keep the packages of several
+    /// workspaces which share a target directory or cargo home
+    #[clap(
+        long,
+        parse(from_os_str),
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_hint = ValueHint::FilePath
+    )]
+    pub manifest_path: Vec<PathBuf>,
+
+    /// The target directory to use, instead of the one used by the workspace. Needed when
+    /// workspaces given by multiple manifest paths use different target directories
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    pub target_dir: Option<PathBuf>,
 
     /// Comma separated list of features to activate
     #[clap(long)]
@@ -30,6 +253,11 @@ struct Args {
     #[clap(long)]
     pub filter_platform: Option<String>,
 
+    /// The rustup toolchain to run `cargo metadata` with, e.g. `nightly-2024-05-01`. Should
+    /// match the toolchain used to build the project
+    #[clap(long)]
+    pub toolchain: Option<String>,
+
     /// Activate all available features
     #[clap(long)]
     pub all_features: bool,
@@ -38,105 +266,2118 @@ struct Args {
     #[clap(long)]
     pub no_default_features: bool,
 
+    /// Read the output of `cargo metadata --format-version 1` from the given file, or stdin with
+    /// `-`, instead of running cargo. Can't be used with the options passed to `cargo metadata`
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_hint = ValueHint::FilePath,
+        conflicts_with_all = &[
+            "manifest-path",
+            "features",
+            "filter-platform",
+            "toolchain",
+            "all-features",
+            "no-default-features",
+        ]
+    )]
+    pub metadata_json: Option<PathBuf>,
+}
+
+// What's kept in the global cargo cache.
+#[derive(Clap)]
+pub struct KeepArgs {
+    /// Keep everything referenced by the Cargo.lock files in the given directory tree, or matching
+    /// the given glob pattern, instead of using the current project. Can be given multiple times
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_hint = ValueHint::AnyPath,
+        conflicts_with = "metadata-json"
+    )]
+    pub lockfiles: Vec<String>,
+
+    /// Ignore --filter-platform when deciding what to keep in the global cargo cache, so crates
+    /// needed by other platforms sharing the cache are kept
+    #[clap(long)]
+    pub keep_all_platforms: bool,
+}
+
+// Which unused items in the global cargo cache are deleted.
+#[derive(Clap)]
+pub struct RetentionArgs {
+    /// Keep the newest N unused versions of each crate in the registry, instead of deleting every
+    /// unused version
+    #[clap(long, default_value = "0")]
+    pub keep_versions: usize,
+
+    /// Only delete unused items which cargo hasn't used within this duration, e.g. `30days`. Uses
+    /// the last use times recorded by cargo 1.78 and later, and the journal of items referenced by
+    /// previous runs
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    pub max_age: Option<Duration>,
+
+    /// The journal recording when each item was last referenced by a run. Defaults to
+    /// `ci-precache-journal` in the cargo home
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub journal_path: Option<PathBuf>,
+
+    /// Never touch the given registry, by directory name, host or url. Can be given multiple
+    /// times
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    pub exclude_registry: Vec<String>,
+
+    /// Only clean the given registry, by directory name, host or url. Can be given multiple times
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    pub only_registry: Vec<String>,
+}
+
+// Live sets shared with other jobs.
+#[derive(Clap)]
+pub struct LiveHashArgs {
+    /// Write the metadata hashes and packages the run considers live to the given file, so another
+    /// job can keep them with --extra-live-hashes
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub emit_live_hashes: Option<PathBuf>,
+
+    /// Also keep everything listed in the given file written by --emit-live-hashes. Can be given
+    /// multiple times
+    #[clap(
+        long,
+        parse(from_os_str),
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_hint = ValueHint::FilePath
+    )]
+    pub extra_live_hashes: Vec<PathBuf>,
+}
+
+// Measuring how much of a restored cache was used.
+#[derive(Clap)]
+pub struct StatsArgs {
+    /// Report how much of the cache restored before the job started was used: how many of the
+    /// kept units or packages were already restored, and how much of what was restored is deleted
+    /// as unused. Requires --since
+    #[clap(long, requires = "since")]
+    pub stats_effectiveness: bool,
+
+    /// When the job started, e.g. `2024-01-01T12:00:00Z`. Anything modified since then was built
+    /// or downloaded by the job
+    #[clap(
+        long,
+        parse(try_from_str = humantime::parse_rfc3339_weak),
+        requires = "stats-effectiveness"
+    )]
+    pub since: Option<SystemTime>,
+}
+
+// How deleted items are listed and the run is reported.
+#[derive(Clap)]
+pub struct OutputArgs {
+    /// The format used to list items on stdout.
+    #[clap(long, arg_enum, default_value = "text")]
+    pub output_format: OutputFormat,
+
+    /// Print paths relative to the given directory. Paths outside it are printed in full.
+    #[clap(long, arg_enum)]
+    pub relative_to: Option<RelativeTo>,
+
+    /// Compare the run with the summary written by a previous run using --summary-json, and print
+    /// how the cache has changed
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub compare: Option<PathBuf>,
+
+    /// Print a summary after cleaning, including any items which were skipped
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+// How the items are deleted.
+#[derive(Clap)]
+pub struct DeleteArgs {
     /// Do not make any changes, but show a list of files to be deleted
     #[clap(long)]
     pub dry_run: bool,
 
     /// Temporary directory to move directories into, will default to $TEMP.
-    #[clap(long)]
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
     pub temp: Option<PathBuf>,
 
-    /// Whether to clear the global cargo cache, or the projects target directory.
+    /// Clean even if the directories being cleaned look dangerous, e.g. the filesystem root
+    #[clap(long)]
+    pub force_unsafe: bool,
+
+    /// List who owns each item skipped because of a permission error, and anything inside it
+    /// owned by someone else. Only supported on unix
+    #[clap(long)]
+    pub chown_check: bool,
+
+    /// Abort without deleting anything if more than this many files would be deleted
+    #[clap(long)]
+    pub max_delete: Option<u64>,
+
+    /// Abort without deleting anything if more than this many bytes would be deleted. Accepts
+    /// K, M, G and T suffixes.
+    #[clap(long, parse(try_from_str = parse_size))]
+    pub max_delete_bytes: Option<u64>,
+}
+
+#[derive(Clap)]
+pub struct CargoCacheArgs {
+    #[clap(flatten)]
+    pub project: ProjectArgs,
+
+    #[clap(flatten)]
+    pub keep: KeepArgs,
+
+    #[clap(flatten)]
+    pub retention: RetentionArgs,
+
+    #[clap(flatten)]
+    pub live: LiveHashArgs,
+
+    #[clap(flatten)]
+    pub stats: StatsArgs,
+
+    #[clap(flatten)]
+    pub output: OutputArgs,
+
+    /// Only delete unpacked sources in ~/.cargo/registry/src which no longer have a .crate file in
+    /// ~/.cargo/registry/cache. Doesn't need a project
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "lockfiles",
+            "metadata-json",
+            "verify-checksums",
+            "remove-yanked",
+            "emit-live-hashes",
+            "extra-live-hashes",
+            "stats-effectiveness",
+        ]
+    )]
+    pub consistency_only: bool,
+
+    /// Also clear unpacked sources in ~/.cargo/registry/src which are no longer used
+    #[clap(long)]
+    pub include_src: bool,
+
+    /// Also delete kept .crate files in ~/.cargo/registry/cache whose checksum doesn't match the
+    /// workspace's Cargo.lock
+    #[clap(long, conflicts_with = "lockfiles")]
+    pub verify_checksums: bool,
+
+    /// Also delete yanked .crate files in ~/.cargo/registry/cache which aren't in the workspace's
+    /// Cargo.lock. Only locally available index data is used
+    #[clap(long, conflicts_with = "lockfiles")]
+    pub remove_yanked: bool,
+
+    /// Never delete items from the global cargo cache which were modified within this duration,
+    /// e.g. `1h`, even if they aren't used. Protects crates downloaded by jobs running at the same
+    /// time
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    pub min_age: Option<Duration>,
+
+    /// Run `git gc` on every repository kept in ~/.cargo/git/db
+    #[clap(long)]
+    pub gc_git: bool,
+
+    /// Arguments passed to `git gc` when using --gc-git
+    #[clap(
+        long,
+        allow_hyphen_values = true,
+        default_value = "--prune=now --aggressive"
+    )]
+    pub gc_git_args: String,
+}
+
+#[derive(Clap)]
+pub struct TargetArgs {
+    #[clap(flatten)]
+    pub project: ProjectArgs,
+
+    #[clap(flatten)]
+    pub live: LiveHashArgs,
+
+    #[clap(flatten)]
+    pub stats: StatsArgs,
+
+    #[clap(flatten)]
+    pub output: OutputArgs,
+
+    /// Clean the target directory even if it doesn't appear to belong to the workspace
+    #[clap(long)]
+    pub force: bool,
+}
+
+#[derive(Clap)]
+pub struct InstalledBinsArgs {
+    #[clap(flatten)]
+    pub output: OutputArgs,
+
+    /// Delete binaries in ~/.cargo/bin which aren't listed in any install record
+    #[clap(long)]
+    pub remove_untracked_bins: bool,
+
+    /// Remove install records whose binaries in ~/.cargo/bin no longer exist
+    #[clap(long)]
+    pub remove_missing_records: bool,
+
+    /// Comma separated list of binaries in ~/.cargo/bin to never touch
+    #[clap(long, use_delimiter = true)]
+    pub keep_bins: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct ReportArgs {
+    #[clap(flatten)]
+    pub project: ProjectArgs,
+
+    #[clap(flatten)]
+    pub keep: KeepArgs,
+
+    /// The number of the largest crates and repositories to list
+    #[clap(long, default_value = "10")]
+    pub top: usize,
+}
+
+#[derive(Clap)]
+pub struct ListLiveArgs {
+    #[clap(flatten)]
+    pub project: ProjectArgs,
+
+    #[clap(flatten)]
+    pub keep: KeepArgs,
+
+    /// Also list everything listed in the given file written by --emit-live-hashes. Can be given
+    /// multiple times
+    #[clap(
+        long,
+        parse(from_os_str),
+        multiple_occurrences = true,
+        number_of_values = 1,
+        value_hint = ValueHint::FilePath
+    )]
+    pub extra_live_hashes: Vec<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct CompletionsArgs {
+    /// The shell to print a completion script for
     #[clap(arg_enum)]
-    pub mode: Mode,
+    pub shell: Shell,
+}
+
+// The cleaning subcommands a plan can be written for.
+#[derive(Clap)]
+pub enum PlanCommand {
+    /// Plans clearing the global cargo cache
+    CargoCache {
+        #[clap(flatten)]
+        args: CargoCacheArgs,
+        #[clap(flatten)]
+        plan: PlanOutput,
+    },
+    /// Plans clearing the projects target directory
+    Target {
+        #[clap(flatten)]
+        args: TargetArgs,
+        #[clap(flatten)]
+        plan: PlanOutput,
+    },
+    /// Plans removing the binaries in ~/.cargo/bin which aren't installed
+    InstalledBins {
+        #[clap(flatten)]
+        args: InstalledBinsArgs,
+        #[clap(flatten)]
+        plan: PlanOutput,
+    },
+}
+
+#[derive(Clap)]
+pub struct PlanOutput {
+    /// The file to write the plan to
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub output: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct ApplyArgs {
+    /// The plan to apply, written by the plan subcommand
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub plan: PathBuf,
+
+    #[clap(flatten)]
+    pub output: OutputArgs,
+
+    #[clap(flatten)]
+    pub delete: DeleteArgs,
 }
 
-fn remove_item(path: &Path, counter: &mut u32, temp: &Path) -> io::Result<()> {
+#[derive(Clap)]
+pub struct RestoreArgs {
+    /// The run's directory inside the temp directory to restore from, e.g.
+    /// `./target/.temp/1700000000000000000`
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    pub from: PathBuf,
+
+    /// List what would be moved back without moving anything
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+// Saving before the cache is stored, and restoring after it's unpacked.
+#[derive(Clap)]
+pub enum MtimesCommand {
+    /// Records the modification time and length of every file cleaning would keep to the given
+    /// file
+    Save(MtimesSaveArgs),
+    /// Sets the modification time of every file recorded in the given file, skipping files whose
+    /// length has changed
+    Restore(MtimesRestoreArgs),
+}
+
+#[derive(Clap)]
+pub struct MtimesSaveArgs {
+    #[clap(flatten)]
+    pub project: ProjectArgs,
+
+    #[clap(flatten)]
+    pub retention: RetentionArgs,
+
+    /// The file to save the modification times to
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub file: PathBuf,
+
+    /// Also save the modification times of the files in ~/.cargo/registry and ~/.cargo/git
+    #[clap(long)]
+    pub include_cargo_home: bool,
+
+    /// The temp directory cleaning moves directories into, which isn't saved
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    pub temp: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct MtimesRestoreArgs {
+    #[clap(flatten)]
+    pub project: ProjectArgs,
+
+    /// The file saved by `mtimes save`
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub file: PathBuf,
+
+    /// Also restore the modification times of the files in ~/.cargo/registry and ~/.cargo/git
+    #[clap(long)]
+    pub include_cargo_home: bool,
+
+    /// List how many modification times would be restored without changing any
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clap)]
+pub struct PackArgs {
+    #[clap(flatten)]
+    pub project: ProjectArgs,
+
+    #[clap(flatten)]
+    pub retention: RetentionArgs,
+
+    /// The archive to write
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub output: PathBuf,
+
+    /// The directories to pack
+    #[clap(
+        long,
+        arg_enum,
+        use_delimiter = true,
+        default_value = "target,registry,git"
+    )]
+    pub roots: Vec<PackRoot>,
+
+    /// The zstd compression level to pack with, from 1 to 22
+    #[clap(long, default_value = "3")]
+    pub compression_level: i32,
+
+    /// The temp directory cleaning moves directories into, which isn't packed
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    pub temp: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct UnpackArgs {
+    /// The archive to unpack
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub input: PathBuf,
+
+    /// The directories to unpack
+    #[clap(
+        long,
+        arg_enum,
+        use_delimiter = true,
+        default_value = "target,registry,git"
+    )]
+    pub roots: Vec<PackRoot>,
+
+    /// Path to Cargo.toml. The target directory is unpacked next to it, unless --target-dir or
+    /// $CARGO_TARGET_DIR is set
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub manifest_path: Option<PathBuf>,
+
+    /// The target directory to unpack into
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    pub target_dir: Option<PathBuf>,
+}
+
+// Parses a size in bytes, with an optional binary unit suffix. e.g. 10G
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("invalid size: {}", s))?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(Error::msg(format!("invalid size unit: {}", unit))),
+    };
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| Error::msg(format!("size too large: {}", s)))
+}
+
+// The exit status when some items couldn't be deleted because of permission errors, but there
+// were no other errors.
+const EXIT_NOT_PERMITTED: i32 = 3;
+
+// Set by --github to print warnings as GitHub Actions annotations.
+static GITHUB: AtomicBool = AtomicBool::new(false);
+
+// Set by --log-file.
+static LOG: Mutex<Option<LogFile>> = Mutex::new(None);
+
+fn write_log(level: LogLevel, msg: &str) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(Err(e)) = log.as_mut().map(|log| log.record(level, msg)) {
+        // Stop logging rather than warning about every record.
+        *log = None;
+        eprintln!("warning: {:#}", e);
+    }
+}
+
+fn print_warning(msg: &str) {
+    log!(Warning, "{}", msg);
+    if GITHUB.load(Ordering::Relaxed) {
+        eprintln!("::warning::{}", escape_workflow_data(msg));
+    } else {
+        eprintln!("warning: {}", msg);
+    }
+}
+
+// Escapes the message of a GitHub Actions workflow command.
+fn escape_workflow_data(msg: &str) -> String {
+    msg.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+// Checks the deletion plan against the limits given on the command line. On failure the totals
+// and the largest items are included in the error.
+fn check_limits(plan: &[PlanItem], max_files: Option<u64>, max_bytes: Option<u64>) -> Result<()> {
+    if max_files.is_none() && max_bytes.is_none() {
+        return Ok(());
+    }
+
+    let mut items = Vec::with_capacity(plan.len());
+    let mut total = DiskUsage::default();
+    for item in plan {
+        let usage = DiskUsage {
+            files: item.files,
+            bytes: item.bytes,
+        };
+        total += usage;
+        items.push((&item.path, usage));
+    }
+
+    let mut msg = String::new();
+    if let Some(max) = max_files.filter(|&max| total.files > max) {
+        msg.push_str(&format!(
+            "{} files would be deleted, the limit is {}\n",
+            total.files, max
+        ));
+    }
+    if let Some(max) = max_bytes.filter(|&max| total.bytes > max) {
+        msg.push_str(&format!(
+            "{} would be deleted, the limit is {}\n",
+            format_size(total.bytes),
+            format_size(max)
+        ));
+    }
+    if msg.is_empty() {
+        return Ok(());
+    }
+
+    items.sort_by(|(_, x), (_, y)| y.bytes.cmp(&x.bytes).then(y.files.cmp(&x.files)));
+    msg.push_str("largest items:");
+    for (path, usage) in items.iter().take(10) {
+        msg.push_str(&format!(
+            "\n    {} ({} files, {})",
+            path.display(),
+            usage.files,
+            format_size(usage.bytes)
+        ));
+    }
+    Err(Error::msg(msg))
+}
+
+// Runs `git gc` on each repository, reporting the size before and after. Failures are reported,
+// but don't stop the remaining repositories from being collected.
+fn gc_git_repos(repos: &[PathBuf], args: &str) {
+    match process::Command::new("git").arg("--version").output() {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("git not found, skipping --gc-git");
+            return;
+        }
+        Err(e) => {
+            warn!("error running git, skipping --gc-git\n{}", e);
+            return;
+        }
+    }
+
+    for repo in repos {
+        let before = cargo_ci_precache::disk_usage(repo).unwrap_or_default();
+        let output = process::Command::new("git")
+            .arg("--git-dir")
+            .arg(repo)
+            .arg("gc")
+            .args(args.split_whitespace())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                let after = cargo_ci_precache::disk_usage(repo).unwrap_or_default();
+                let msg = format!(
+                    "git gc {}: {} -> {}",
+                    repo.display(),
+                    format_size(before.bytes),
+                    format_size(after.bytes)
+                );
+                log!(Info, "{}", msg);
+                eprintln!("{}", msg);
+            }
+            Ok(output) => {
+                let msg = format!(
+                    "error running git gc on {}, exit code {:?}\n{}",
+                    repo.display(),
+                    output.status.code(),
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                );
+                log!(Error, "{}", msg);
+                eprintln!("{}", msg);
+            }
+            Err(e) => {
+                let msg = format!("error running git gc on {}\n{}", repo.display(), e);
+                log!(Error, "{}", msg);
+                eprintln!("{}", msg);
+            }
+        }
+    }
+}
+
+// Lists items on stdout. Items are sorted by kind and then by path so the output doesn't depend on
+// the order directories were read in.
+struct ItemWriter<'a> {
+    format: &'a OutputFormat,
+    relative_to: Option<PathBuf>,
+    /// The units being removed from the target directory, keyed by metadata hash. Text output is
+    /// annotated with the package and reason of each item's unit.
+    outdated: Option<HashMap<String, OutdatedUnit>>,
+}
+impl ItemWriter<'_> {
+    fn display_path<'p>(&self, path: &'p Path) -> &'p Path {
+        match self
+            .relative_to
+            .as_ref()
+            .map(|base| path.strip_prefix(base))
+        {
+            Some(Ok(rel)) if !rel.as_os_str().is_empty() => rel,
+            _ => path,
+        }
+    }
+
+    // Describes the unit an item in the target directory belongs to, e.g.
+    // `serde_json 1.0.117 [feature change]`. Units whose package can't be found are still
+    // described, but marked as unresolved.
+    fn annotation(&self, info: &ItemInfo) -> Option<String> {
+        let hash = info.hash.as_ref()?;
+        let name = info.name.as_deref().unwrap_or("?");
+        Some(match self.outdated.as_ref()?.get(hash) {
+            Some(OutdatedUnit {
+                package: Some(package),
+                reason,
+            }) => format!("{} [{}]", package, reason.describe()),
+            Some(OutdatedUnit {
+                package: None,
+                reason,
+            }) => format!("{} (unresolved) [{}]", name, reason.describe()),
+            None => format!("{} (unresolved)", name),
+        })
+    }
+
+    fn write(
+        &self,
+        kept: &[PlanItem],
+        plan: &[PlanItem],
+        action: ItemAction,
+    ) -> Result<Vec<ItemRecord>> {
+        let mut records = Vec::new();
+        for &(items, action) in &[(kept, ItemAction::Kept), (plan, action)] {
+            let mut items: Vec<_> = items.iter().collect();
+            items.sort_by(|x, y| {
+                x.info
+                    .kind
+                    .cmp(&y.info.kind)
+                    .then_with(|| x.path.cmp(&y.path))
+            });
+
+            for item in items {
+                let record = ItemRecord {
+                    path: self.display_path(&item.path).to_string_lossy().into(),
+                    action,
+                    info: item.info.clone(),
+                    bytes: item.bytes,
+                };
+                match self.format {
+                    OutputFormat::Text if action == ItemAction::WouldDelete => {
+                        match self.annotation(&record.info) {
+                            Some(annotation) => println!("{}  {}", record.path, annotation),
+                            None => println!("{}", record.path),
+                        }
+                    }
+                    OutputFormat::JsonLines => println!("{}", serde_json::to_string(&record)?),
+                    _ => (),
+                }
+                records.push(record);
+            }
+        }
+        if *self.format == OutputFormat::Json {
+            let output = serde_json::json!({ "items": records });
+            println!("{}", serde_json::to_string(&output)?);
+        }
+        Ok(records)
+    }
+}
+
+fn format_delta(bytes: i64) -> String {
+    let sign = if bytes < 0 { '-' } else { '+' };
+    format!("{}{}", sign, format_size(bytes.unsigned_abs()))
+}
+
+fn print_comparison(path: &Path, comparison: &Comparison) {
+    eprintln!("compared with {}:", path.display());
+    for (dir, delta) in &comparison.retained {
+        eprintln!(
+            "    {:<20}{:>12}{:>+10} files",
+            dir,
+            format_delta(delta.bytes),
+            delta.files
+        );
+    }
+    for (packages, what) in &[
+        (&comparison.added_packages, "new packages"),
+        (
+            &comparison.removed_packages,
+            "packages no longer referenced",
+        ),
+    ] {
+        if !packages.is_empty() {
+            eprintln!("{} {}:", packages.len(), what);
+            for package in packages.iter() {
+                eprintln!("    {}", package);
+            }
+        }
+    }
+}
+
+fn print_report(report: &cargo_ci_precache::CargoHomeReport) {
+    for (component, usage) in cargo_ci_precache::REPORT_COMPONENTS
+        .iter()
+        .zip(&report.components)
+    {
+        println!(
+            "{:<16}{:>12}{:>10} files",
+            component,
+            format_size(usage.bytes),
+            usage.files
+        );
+    }
+    if !report.registries.is_empty() {
+        println!("\nregistries:");
+        for (registry, usage) in &report.registries {
+            println!(
+                "    {:<40}{:>12}",
+                registry.to_string_lossy(),
+                format_size(usage.bytes)
+            );
+        }
+    }
+    if !report.largest.is_empty() {
+        println!("\nlargest items:");
+        for entry in &report.largest {
+            println!(
+                "    {:>12}  {:<8}{}",
+                format_size(entry.usage.bytes),
+                if entry.used { "used" } else { "unused" },
+                entry.path.display()
+            );
+        }
+    }
+}
+
+// Lists the registry directories skipped because of --exclude-registry and --only-registry.
+fn skipped_registries(options: &CacheOptions, include_src: bool) -> Result<Vec<PathBuf>> {
+    let registry_dir = options.cargo_home()?.join("registry");
+    let dirs: &[&str] = if include_src {
+        &["cache", "src"]
+    } else {
+        &["cache"]
+    };
+    let mut skipped = Vec::new();
+    for dir in dirs {
+        if let Ok(iter) = registry_dir.join(dir).read_dir() {
+            skipped.extend(
+                iter.filter_map(|e| e.ok())
+                    .filter(|e| options.skips_registry(&e.file_name()))
+                    .map(|e| e.path()),
+            );
+        }
+    }
+    skipped.sort();
+    Ok(skipped)
+}
+
+// Deletes a file, or moves a directory into the temp directory. Returns the name a directory was
+// moved to.
+fn remove_item(path: &Path, counter: &mut u32, temp: &Path) -> io::Result<Option<String>> {
     let meta = match path.symlink_metadata() {
         Ok(m) => m,
         // If the file was not found then it's removed.
         // This also shouldn't happen.
-        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
         Err(e) => return Err(e),
     };
 
+    // Files and links are deleted directly. Links are removed without touching their target.
     if !meta.is_dir() {
-        match fs::remove_file(path) {
-            Ok(()) => Ok(()),
+        return cargo_ci_precache::remove_tree(path).map(|_| None);
+    }
+
+    // Just need a random unique name for the directory.
+    // Incrementing counter it is.
+    let target_name = counter.to_string();
+    *counter += 1;
+    let target_dir = temp.join(&target_name);
+
+    // Can only move a directory to another empty directory on unix.
+    #[cfg(unix)]
+    {
+        fs::create_dir(&target_dir)?;
+    }
+    match fs::rename(path, &target_dir) {
+        Ok(()) => Ok(Some(target_name)),
+        // Directories can't be moved to another filesystem. They're left alone rather than deleted
+        // in place, so everything removed can still be restored.
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let _ = fs::remove_dir(&target_dir);
+            Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "{}, use --temp to choose a temp directory on the same filesystem",
+                    e
+                ),
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Lists who owns each item, along with anything inside it owned by someone else, to help find what
+// created them.
+#[cfg(unix)]
+fn list_owners(paths: &[PathBuf]) -> String {
+    use std::os::unix::fs::MetadataExt;
 
-            // Read-only files on windows will fail with PermissionDenied.
-            // Remove the read-only flag if that happens, and try again.
-            #[cfg(windows)]
-            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                let mut perm = meta.permissions();
-                perm.set_readonly(false);
-                fs::set_permissions(path, perm)?;
-                fs::remove_file(path)
+    // uid -> (count, first item found)
+    fn walk(path: &Path, owners: &mut BTreeMap<u32, (u64, PathBuf)>) {
+        let meta = match path.symlink_metadata() {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+        owners
+            .entry(meta.uid())
+            .or_insert_with(|| (0, path.into()))
+            .0 += 1;
+        if meta.is_dir() {
+            for e in fs::read_dir(path).into_iter().flatten().flatten() {
+                walk(&e.path(), owners);
             }
-            Err(e) => Err(e),
         }
+    }
+
+    let mut s = String::new();
+    for path in paths {
+        let owner = match path.symlink_metadata() {
+            Ok(meta) => format!("uid {}, gid {}", meta.uid(), meta.gid()),
+            Err(e) => e.to_string(),
+        };
+        s.push_str(&format!("{} ({})\n", path.display(), owner));
+        let mut owners = BTreeMap::new();
+        walk(path, &mut owners);
+        if owners.len() > 1 {
+            for (uid, (count, first)) in &owners {
+                s.push_str(&format!(
+                    "    uid {}: {} items, e.g. {}\n",
+                    uid,
+                    count,
+                    first.display()
+                ));
+            }
+        }
+    }
+    s
+}
+
+#[cfg(not(unix))]
+fn list_owners(_: &[PathBuf]) -> String {
+    "owners can only be listed on unix\n".into()
+}
+
+// Moves everything a previous run moved into its temp directory back, reporting anything which
+// couldn't be restored.
+fn restore(run_dir: &Path, dry_run: bool, summary: &mut RunSummary) -> Result<()> {
+    let report = restore::restore(run_dir, dry_run)?;
+    let action = if dry_run { "would restore" } else { "restored" };
+    for path in &report.restored {
+        log!(Info, "{} {}", action, path.display());
+        eprintln!("{} {}", action, path.display());
+    }
+    for path in &report.conflicts {
+        warn!("skipping {}, it already exists", path.display());
+    }
+    for path in &report.missing {
+        warn!(
+            "skipping {}, it's no longer in {}",
+            path.display(),
+            run_dir.display()
+        );
+    }
+    for (path, e) in &report.errors {
+        let msg = format!("error restoring {}\n{}", path.display(), e);
+        log!(Error, "{}", msg);
+        eprintln!("{}", msg);
+        summary
+            .errors
+            .push(format!("error restoring {}: {}", path.display(), e));
+    }
+    eprintln!(
+        "{} {} directories, {} skipped",
+        action,
+        report.restored.len(),
+        report.conflicts.len() + report.missing.len()
+    );
+    Ok(())
+}
+
+// The directories modification times are saved for, along with their names in the saved file.
+fn mtimes_roots(
+    include_cargo_home: bool,
+    target_dir: &Path,
+    cargo_home: &Path,
+) -> Vec<(&'static str, PathBuf)> {
+    let mut roots = vec![("target", target_dir.to_path_buf())];
+    if include_cargo_home {
+        roots.push(("registry", cargo_home.join("registry")));
+        roots.push(("git", cargo_home.join("git")));
+    }
+    roots
+}
+
+// Saves the modification times of the files cleaning would keep in the target directory, and with
+// --include-cargo-home the cargo home's registry and git directories.
+fn mtimes_save(
+    args: &MtimesSaveArgs,
+    meta: &Metadata,
+    cache_options: CacheOptions,
+    cargo_home: &Path,
+) -> Result<()> {
+    let excluded = cleaned_paths(
+        meta,
+        cache_options,
+        true,
+        args.include_cargo_home,
+        args.temp.as_deref(),
+    )?;
+    let mut mtimes = Mtimes::default();
+    for (name, root) in mtimes_roots(args.include_cargo_home, meta.target_directory(), cargo_home) {
+        mtimes.add_root(name, &root, &|p| excluded.contains(p))?;
+    }
+    mtimes.write(&args.file)?;
+    let count: usize = mtimes.roots.values().map(BTreeMap::len).sum();
+    log!(
+        Info,
+        "saved the modification times of {} files to {}",
+        count,
+        args.file.display()
+    );
+    eprintln!(
+        "saved the modification times of {} files to {}",
+        count,
+        args.file.display()
+    );
+    Ok(())
+}
+
+// Restores the modification times saved by `mtimes save`.
+fn mtimes_restore(
+    args: &MtimesRestoreArgs,
+    target_dir: &Path,
+    cargo_home: &Path,
+    summary: &mut RunSummary,
+) -> Result<()> {
+    let mtimes = Mtimes::read(&args.file)?;
+    let action = if args.dry_run {
+        "would restore"
     } else {
-        // Just need a random unique name for the directory.
-        // Incrementing counter it is.
-        let target_name = counter.to_string();
-        *counter += 1;
-        let target_dir = temp.join(target_name);
-
-        // Can only move a directory to another empty directory on unix.
-        #[cfg(unix)]
-        {
-            fs::create_dir(&target_dir)?;
+        "restored"
+    };
+    let (mut restored, mut skipped) = (0, 0);
+    for (name, root) in mtimes_roots(args.include_cargo_home, target_dir, cargo_home) {
+        let report = mtimes.restore(name, &root, args.dry_run);
+        for path in &report.changed {
+            log!(Info, "skipping {}, its length has changed", path.display());
+        }
+        for (path, e) in &report.errors {
+            let msg = format!(
+                "error restoring the modification time of {}\n{}",
+                path.display(),
+                e
+            );
+            log!(Error, "{}", msg);
+            eprintln!("{}", msg);
+            summary.errors.push(format!(
+                "error restoring the modification time of {}: {}",
+                path.display(),
+                e
+            ));
         }
-        fs::rename(path, &target_dir)
+        restored += report.restored;
+        skipped += report.changed.len() + report.missing;
+    }
+    log!(
+        Info,
+        "{} the modification times of {} files, {} skipped",
+        action,
+        restored,
+        skipped
+    );
+    eprintln!(
+        "{} the modification times of {} files, {} skipped",
+        action, restored, skipped
+    );
+    Ok(())
+}
+
+// Every path cleaning would remove from the global cargo cache and the target directory, along
+// with the temp directory. The temp directory is often inside the target directory, but what's
+// moved into it isn't meant to be cached.
+fn cleaned_paths(
+    meta: &Metadata,
+    cache_options: CacheOptions,
+    target: bool,
+    cargo_cache: bool,
+    temp: Option<&Path>,
+) -> Result<HashSet<PathBuf>> {
+    let mut excluded = HashSet::new();
+    let mut exclude = |p: &Path| {
+        excluded.insert(PathBuf::from(p));
+    };
+    let cleaner = Cleaner::new(meta.clone()).cache_options(cache_options);
+    if cargo_cache {
+        cleaner
+            .clone()
+            .include_src(true)
+            .run_cargo_cache(&mut exclude)?;
+    }
+    if target {
+        cleaner.run_target(&mut exclude)?;
     }
+    if let Some(temp) = temp {
+        excluded.insert(env::current_dir()?.join(temp));
+    }
+    Ok(excluded)
+}
+
+// The directory for each root, along with its name in the archive.
+fn pack_roots(
+    roots: &[PackRoot],
+    target_dir: &Path,
+    cargo_home: &Path,
+) -> Vec<(&'static str, PathBuf)> {
+    roots
+        .iter()
+        .map(|root| match root {
+            PackRoot::Target => ("target", target_dir.into()),
+            PackRoot::Registry => ("registry", cargo_home.join("registry")),
+            PackRoot::Git => ("git", cargo_home.join("git")),
+        })
+        .collect()
+}
+
+// Writes an archive of exactly what cleaning would keep, without changing anything.
+fn pack(
+    args: &PackArgs,
+    meta: &Metadata,
+    cache_options: CacheOptions,
+    cargo_home: &Path,
+) -> Result<()> {
+    let excluded = cleaned_paths(
+        meta,
+        cache_options,
+        args.roots.contains(&PackRoot::Target),
+        args.roots.iter().any(|&root| root != PackRoot::Target),
+        args.temp.as_deref(),
+    )?;
+    let roots = pack_roots(&args.roots, meta.target_directory(), cargo_home);
+    let roots: Vec<_> = roots
+        .iter()
+        .map(|(name, path)| (*name, path.as_path()))
+        .collect();
+    let usage = archive::pack(
+        &roots,
+        &|p| excluded.contains(p),
+        &args.output,
+        args.compression_level,
+    )?;
+    log!(
+        Info,
+        "packed {} files, {}, into {}",
+        usage.files,
+        format_size(usage.bytes),
+        args.output.display()
+    );
+    eprintln!(
+        "packed {} files, {}, into {}",
+        usage.files,
+        format_size(usage.bytes),
+        args.output.display()
+    );
+    Ok(())
+}
+
+// Unpacks an archive written by the pack subcommand. Unpacking happens before the project's
+// dependencies are available, so it doesn't run `cargo metadata`. The target directory is taken
+// from --target-dir or $CARGO_TARGET_DIR, and otherwise is `target` next to the manifest path or
+// in the current directory.
+fn unpack(args: &UnpackArgs, cargo_home: &Path, summary: &mut RunSummary) -> Result<()> {
+    let target_dir = match (&args.target_dir, env::var_os("CARGO_TARGET_DIR")) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(dir)) => dir.into(),
+        (None, None) => match args.manifest_path.as_deref().and_then(Path::parent) {
+            Some(dir) => dir.join("target"),
+            None => "target".into(),
+        },
+    };
+    let target_dir = env::current_dir()?.join(target_dir);
+    summary.target_dir = Some(target_dir.clone());
+    let roots = pack_roots(&args.roots, &target_dir, cargo_home);
+    let roots: Vec<_> = roots
+        .iter()
+        .map(|(name, path)| (*name, path.as_path()))
+        .collect();
+    let usage = archive::unpack(&args.input, &roots)?;
+    log!(
+        Info,
+        "unpacked {} files, {}, from {}",
+        usage.files,
+        format_size(usage.bytes),
+        args.input.display()
+    );
+    eprintln!(
+        "unpacked {} files, {}, from {}",
+        usage.files,
+        format_size(usage.bytes),
+        args.input.display()
+    );
+    Ok(())
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-
-    let meta = MetadataCommand::new()
-        .manifest_path(args.manifest_path)
-        .features(args.features)
-        .filter_platform(args.filter_platform)
-        .all_features(args.all_features)
-        .no_default_features(args.no_default_features)
-        .exec()?;
-
-    let mut delete: Box<dyn FnMut(&Path)> = if args.dry_run {
-        Box::new(|p| println!("{}", p.display()))
+    let merged = config::merge(Args::into_app(), env::args_os().collect(), &|var| {
+        env::var_os(var)
+    })?;
+    let args = Args::from_arg_matches(&Args::into_app().get_matches_from(&merged.args));
+    if args.global.show_config {
+        print!("{}", merged.display());
+        return Ok(());
+    }
+    let start = Instant::now();
+    let step_summary = match env::var_os("GITHUB_STEP_SUMMARY") {
+        Some(path) if args.global.github => Some(PathBuf::from(path)),
+        _ => None,
+    };
+    if args.global.github && step_summary.is_none() {
+        eprintln!("notice: GITHUB_STEP_SUMMARY isn't set, ignoring --github");
+    }
+    GITHUB.store(step_summary.is_some(), Ordering::Relaxed);
+    let summary_path = args.global.summary_json.clone();
+    let mut summary = RunSummary::new(
+        args.command.name(),
+        env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        args.command.dry_run(),
+    );
+    summary.cargo_home = args
+        .global
+        .cargo_home
+        .clone()
+        .or_else(|| home::cargo_home().ok());
+
+    // The log is opened before anything else so it includes every problem with the run.
+    let result = match &args.global.log_file {
+        Some(path) => {
+            LogFile::create(path, args.global.log_format == LogFormat::JsonLines).map(|log| {
+                summary.log_file = Some(path.clone());
+                *LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(log);
+            })
+        }
+        None => Ok(()),
+    };
+    log!(
+        Info,
+        "cargo-ci-precache {} {}",
+        env!("CARGO_PKG_VERSION"),
+        summary.args.join(" ")
+    );
+    let result = result.and_then(|_| run(args, &mut summary));
+    summary.timings.total = start.elapsed().as_secs_f64();
+    if let Err(e) = &result {
+        summary.errors.push(format!("{:#}", e));
+    }
+    summary.ok = summary.errors.is_empty();
+    match &result {
+        Ok(()) => log!(
+            Info,
+            "finished in {:.2}s with {} errors",
+            summary.timings.total,
+            summary.errors.len()
+        ),
+        Err(e) => log!(Error, "{:#}", e),
+    }
+    if let Some(path) = step_summary {
+        if let Err(e) = &result {
+            eprintln!("::error::{}", escape_workflow_data(&format!("{:#}", e)));
+        }
+        let written = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(summary.to_markdown().as_bytes()));
+        if let Err(e) = written {
+            warn!("error writing step summary {}\n{}", path.display(), e);
+        }
+    }
+    if let Some(path) = summary_path {
+        if let Err(e) = summary.write(&path) {
+            warn!("{:#}", e);
+        }
+    }
+    // Items skipped because of permission errors aren't errors, but get their own exit status so
+    // pipelines can decide whether they're fatal.
+    if result.is_ok() && !summary.not_permitted.is_empty() {
+        process::exit(EXIT_NOT_PERMITTED);
+    }
+    result
+}
+
+fn run(args: Args, summary: &mut RunSummary) -> Result<()> {
+    let global = &args.global;
+    let cargo_home = match &global.cargo_home {
+        Some(path) => path.clone(),
+        None => home::cargo_home()?,
+    };
+    match &args.command {
+        Command::CargoCache { args, delete } => clean(
+            Clean::CargoCache(args),
+            &args.output,
+            Some(delete),
+            None,
+            global,
+            &cargo_home,
+            summary,
+        ),
+        Command::Target { args, delete } => clean(
+            Clean::Target(args),
+            &args.output,
+            Some(delete),
+            None,
+            global,
+            &cargo_home,
+            summary,
+        ),
+        Command::InstalledBins { args, delete } => clean(
+            Clean::InstalledBins(args),
+            &args.output,
+            Some(delete),
+            None,
+            global,
+            &cargo_home,
+            summary,
+        ),
+        Command::Plan(PlanCommand::CargoCache { args, plan }) => clean(
+            Clean::CargoCache(args),
+            &args.output,
+            None,
+            Some(&plan.output),
+            global,
+            &cargo_home,
+            summary,
+        ),
+        Command::Plan(PlanCommand::Target { args, plan }) => clean(
+            Clean::Target(args),
+            &args.output,
+            None,
+            Some(&plan.output),
+            global,
+            &cargo_home,
+            summary,
+        ),
+        Command::Plan(PlanCommand::InstalledBins { args, plan }) => clean(
+            Clean::InstalledBins(args),
+            &args.output,
+            None,
+            Some(&plan.output),
+            global,
+            &cargo_home,
+            summary,
+        ),
+        Command::Apply(args) => {
+            let (plan, mode) = read_plan(&args.plan, &cargo_home)?;
+            clean(
+                Clean::Apply(&plan, mode),
+                &args.output,
+                Some(&args.delete),
+                None,
+                global,
+                &cargo_home,
+                summary,
+            )
+        }
+        // Reporting is read-only, so it doesn't need a temp dir or any of the safety checks.
+        Command::Report(args) => {
+            let filter_platform = cache_filter_platform(&args.project, &args.keep);
+            let meta = load_metadata(
+                &args.project,
+                &args.keep.lockfiles,
+                filter_platform,
+                &[],
+                global,
+                summary,
+            )?;
+            print_report(&cargo_ci_precache::cargo_home_report(
+                &cargo_home,
+                &meta,
+                args.top,
+            )?);
+            Ok(())
+        }
+        // Listing what's in use is read-only, like reporting.
+        Command::ListLive(args) => {
+            let filter_platform = cache_filter_platform(&args.project, &args.keep);
+            let meta = load_metadata(
+                &args.project,
+                &args.keep.lockfiles,
+                filter_platform,
+                &args.extra_live_hashes,
+                global,
+                summary,
+            )?;
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for path in cargo_ci_precache::live_cache_paths(&meta, &cargo_home)? {
+                writeln!(stdout, "{}", path.display())?;
+            }
+            Ok(())
+        }
+        Command::Completions(args) => {
+            let script =
+                completions::generate(args.shell, Args::into_app(), env!("CARGO_PKG_NAME"));
+            print!("{}", script);
+            Ok(())
+        }
+        Command::Restore(args) => restore(&args.from, args.dry_run, summary),
+        // Saving records exactly what cleaning would keep.
+        Command::Mtimes(MtimesCommand::Save(args)) => {
+            let filter_platform = args.project.filter_platform.as_ref();
+            let meta = load_metadata(&args.project, &[], filter_platform, &[], global, summary)?;
+            let cache_options = cache_options(&args.retention, &cargo_home);
+            mtimes_save(args, &meta, cache_options, &cargo_home)
+        }
+        Command::Mtimes(MtimesCommand::Restore(args)) => {
+            let filter_platform = args.project.filter_platform.as_ref();
+            let meta = load_metadata(&args.project, &[], filter_platform, &[], global, summary)?;
+            mtimes_restore(args, meta.target_directory(), &cargo_home, summary)
+        }
+        // Packing keeps exactly what cleaning would, without changing anything.
+        Command::Pack(args) => {
+            let filter_platform = args.project.filter_platform.as_ref();
+            let meta = load_metadata(&args.project, &[], filter_platform, &[], global, summary)?;
+            let cache_options = cache_options(&args.retention, &cargo_home);
+            pack(args, &meta, cache_options, &cargo_home)
+        }
+        Command::Unpack(args) => unpack(args, &cargo_home, summary),
+    }
+}
+
+// The platform `cargo metadata` is filtered by when deciding what to keep in the global cargo
+// cache.
+fn cache_filter_platform<'a>(project: &'a ProjectArgs, keep: &KeepArgs) -> Option<&'a String> {
+    if keep.keep_all_platforms {
+        None
     } else {
-        let mut temp = args
-            .temp
-            .or_else(|| env::var_os("TEMP").map(PathBuf::from))
-            .ok_or_else(|| Error::msg("no temp dir"))?;
-
-        // Directories moved into the temp folder are named only from an incrementing counter to
-        // avoid name collisions on a single run, but this would mean multiple runs would certainly
-        // have a collision. Working in a directory named after the current time should avoid this.
-        temp.push(
-            match SystemTime::UNIX_EPOCH.elapsed() {
-                Ok(x) => x,
-                Err(e) => e.duration(),
-            }
-            .as_nanos()
-            .to_string(),
-        );
+        project.filter_platform.as_ref()
+    }
+}
 
-        fs::create_dir_all(&temp)
-            .with_context(|| format!("error creating temp dir: {}", temp.display()))?;
+fn cache_options(args: &RetentionArgs, cargo_home: &Path) -> CacheOptions {
+    CacheOptions {
+        keep_versions: args.keep_versions,
+        max_age: args.max_age,
+        journal_path: Some(journal_path(args, cargo_home)),
+        exclude_registries: args.exclude_registry.clone(),
+        only_registries: args.only_registry.clone(),
+        cargo_home: Some(cargo_home.into()),
+    }
+}
 
-        let mut counter = 0u32;
+fn journal_path(args: &RetentionArgs, cargo_home: &Path) -> PathBuf {
+    match &args.journal_path {
+        Some(path) => path.clone(),
+        None => Journal::default_path(cargo_home),
+    }
+}
 
-        Box::new(move |path| match remove_item(path, &mut counter, &temp) {
-            Ok(()) => (),
-            Err(e) => {
-                eprintln!("error removing {}\n{}", path.display(), e);
+// Reads the project's metadata from --metadata-json, the lockfiles given by --lockfiles or by
+// running `cargo metadata`, and adds everything other jobs found live.
+fn load_metadata(
+    args: &ProjectArgs,
+    lockfiles: &[String],
+    filter_platform: Option<&String>,
+    extra_live_hashes: &[PathBuf],
+    global: &GlobalArgs,
+    summary: &mut RunSummary,
+) -> Result<Metadata> {
+    let start = Instant::now();
+    let mut meta = if let Some(path) = &args.metadata_json {
+        let mut meta = if path == Path::new("-") {
+            Metadata::from_reader(io::stdin().lock())
+                .context("error reading metadata from stdin")?
+        } else {
+            let data = fs::read(path)
+                .with_context(|| format!("error reading metadata {}", path.display()))?;
+            Metadata::from_slice(&data)
+                .with_context(|| format!("error reading metadata {}", path.display()))?
+        };
+        if let Some(dir) = &args.target_dir {
+            meta.set_target_directory(env::current_dir()?.join(dir));
+        }
+        meta
+    } else if !lockfiles.is_empty() {
+        let mut paths = Vec::new();
+        for pattern in lockfiles {
+            let found = cargo_ci_precache::find_lockfiles(pattern)?;
+            if found.is_empty() {
+                warn!("no lockfiles found matching {}", pattern);
             }
-        })
+            paths.extend(found);
+        }
+        let mut lockfile_count = paths.len();
+        let meta = cargo_ci_precache::metadata_from_lockfiles(&paths, &mut |path, e| {
+            lockfile_count -= 1;
+            warn!("skipping {}\n{:?}", path.display(), e);
+        });
+        // Without any lockfiles nothing would be kept, and the whole cache would be deleted.
+        if lockfile_count == 0 {
+            return Err(Error::msg(
+                "none of the lockfiles given by --lockfiles could be read, refusing to clean",
+            ));
+        }
+        log!(Info, "{} lockfiles used", lockfile_count);
+        eprintln!("{} lockfiles used", lockfile_count);
+        summary.lockfiles = Some(lockfile_count);
+        meta
+    } else {
+        let target_dir = match &args.target_dir {
+            Some(dir) => Some(env::current_dir()?.join(dir)),
+            None => None,
+        };
+        let metadata = |manifest_path: Option<&PathBuf>| -> Result<_> {
+            let mut meta = MetadataCommand::new()
+                .manifest_path(manifest_path)
+                .features(args.features.as_ref())
+                .filter_platform(filter_platform)
+                .toolchain(args.toolchain.clone())
+                .cargo_home(global.cargo_home.as_ref())
+                .all_features(args.all_features)
+                .no_default_features(args.no_default_features)
+                .exec()?;
+            if let Some(dir) = &target_dir {
+                meta.set_target_directory(dir);
+            }
+            Ok(meta)
+        };
+        let mut meta = metadata(args.manifest_path.first())?;
+        for manifest_path in args.manifest_path.iter().skip(1) {
+            let other = metadata(Some(manifest_path))?;
+            if other.target_directory() != meta.target_directory() {
+                return Err(Error::msg(format!(
+                    "the workspaces use different target directories, {} and {}, use \
+                     --target-dir to choose one",
+                    meta.target_directory().display(),
+                    other.target_directory().display()
+                )));
+            }
+            meta.merge(other)?;
+        }
+        meta
     };
 
-    match args.mode {
-        Mode::CargoCache => cargo_ci_precache::clear_cargo_cache(meta, &mut delete),
-        Mode::Target => cargo_ci_precache::clear_target(meta, &mut delete),
+    // Everything other jobs found live is kept as if this run used it.
+    for path in extra_live_hashes {
+        LiveSet::read(path)?.add_to(&mut meta);
+    }
+
+    summary.target_dir = Some(meta.target_directory().to_path_buf());
+    summary.packages = meta.package_names();
+    log!(
+        Info,
+        "found {} packages, target directory {}",
+        summary.packages.len(),
+        meta.target_directory().display()
+    );
+    summary.timings.metadata = Some(start.elapsed().as_secs_f64());
+    Ok(meta)
+}
+
+// Writes the metadata hashes and packages the run considers live for --emit-live-hashes. Metadata
+// hashes are only needed to keep units in the target directory.
+fn emit_live_hashes(
+    path: &Path,
+    meta: &Metadata,
+    cargo_home: &Path,
+    meta_hashes: bool,
+) -> Result<()> {
+    let mut live = LiveSet::from_metadata(meta);
+    if meta_hashes {
+        live.meta_hashes = cargo_ci_precache::live_meta_hashes(meta, cargo_home)?;
+    }
+    live.write(path)?;
+    log!(
+        Info,
+        "wrote {} live metadata hashes to {}",
+        live.meta_hashes.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+// Reads a plan written by the plan subcommand, checking it was made for the same cargo home and
+// target directory. Returns the plan and the subcommand it was written for.
+fn read_plan(path: &Path, cargo_home: &Path) -> Result<(Plan, Mode)> {
+    let plan = Plan::read(path)?;
+    let mode = Mode::from_str(&plan.mode, false).map_err(|_| {
+        Error::msg(format!(
+            "unsupported mode {} in plan {}",
+            plan.mode,
+            path.display()
+        ))
+    })?;
+    let environment = PlanEnvironment::new(cargo_home.into(), plan.environment.target_dir.clone());
+    if environment != plan.environment {
+        return Err(Error::msg(format!(
+            "plan {} was made in a different environment
+plan digest:    {}
+current digest: {}",
+            path.display(),
+            plan.digest,
+            environment.digest()
+        )));
+    }
+    log!(
+        Info,
+        "applying plan {} with {} items, digest {}",
+        path.display(),
+        plan.items.len(),
+        plan.digest
+    );
+    Ok((plan, mode))
+}
+
+// What a cleaning run deletes.
+enum Clean<'a> {
+    CargoCache(&'a CargoCacheArgs),
+    Target(&'a TargetArgs),
+    InstalledBins(&'a InstalledBinsArgs),
+    // A plan written by the plan subcommand, and the subcommand it was written for.
+    Apply(&'a Plan, Mode),
+}
+
+// Finds what to delete, then lists and deletes it. Without `delete` a plan is written to
+// `plan_path` instead, and nothing is deleted.
+fn clean(
+    what: Clean<'_>,
+    output: &OutputArgs,
+    delete: Option<&DeleteArgs>,
+    plan_path: Option<&PathBuf>,
+    global: &GlobalArgs,
+    cargo_home: &Path,
+    summary: &mut RunSummary,
+) -> Result<()> {
+    let mut start = Instant::now();
+    let dry_run = match delete {
+        Some(delete) => delete.dry_run,
+        None => true,
+    };
+    let mode = match &what {
+        Clean::CargoCache(_) => Mode::CargoCache,
+        Clean::Target(_) => Mode::Target,
+        Clean::InstalledBins(_) => Mode::InstalledBins,
+        Clean::Apply(_, mode) => *mode,
+    };
+
+    // Collect everything to delete first so the limits can be checked before anything is
+    // deleted. Each item is collected along with why it's being deleted.
+    let collected = RefCell::new(Vec::new());
+    // Measuring every item is slow, so it's only done when something reports the sizes.
+    let measure = plan_path.is_some()
+        || global.summary_json.is_some()
+        || GITHUB.load(Ordering::Relaxed)
+        || output.output_format != OutputFormat::Text
+        || matches!(delete, Some(d) if d.max_delete.is_some() || d.max_delete_bytes.is_some());
+    let collect = |reason: PlanReason| {
+        let collected = &collected;
+        move |p: &Path| collected.borrow_mut().push((PathBuf::from(p), reason))
+    };
+    let mut problems = Vec::new();
+    let mut gc = None;
+    let mut journal = None;
+    let mut missing_records = Vec::new();
+    let mut outdated = None;
+    let mut found = None;
+    let mut applied = Vec::new();
+    let mut min_age = None;
+    let mut since = None;
+    let mut registries = Vec::new();
+    match &what {
+        Clean::CargoCache(args) => {
+            let cache_options = cache_options(&args.retention, cargo_home);
+            // The consistency pass only looks at the cargo cache, so it doesn't need a project.
+            if args.consistency_only {
+                cargo_ci_precache::clear_orphaned_src(
+                    &cache_options,
+                    &mut collect(PlanReason::Orphaned),
+                )?;
+            } else {
+                // Both read the lockfile from the workspace root, so they only work with a single
+                // workspace.
+                if args.project.manifest_path.len() > 1
+                    && (args.verify_checksums || args.remove_yanked)
+                {
+                    return Err(Error::msg(
+                        "--verify-checksums and --remove-yanked can't be used with multiple \
+                         manifest paths",
+                    ));
+                }
+                if args.project.filter_platform.is_some() && !args.keep.keep_all_platforms {
+                    warn!(
+                        "crates only used by other platforms will be deleted from the cargo \
+                         cache, use --keep-all-platforms to keep them"
+                    );
+                }
+                let meta = load_metadata(
+                    &args.project,
+                    &args.keep.lockfiles,
+                    cache_filter_platform(&args.project, &args.keep),
+                    &args.live.extra_live_hashes,
+                    global,
+                    summary,
+                )?;
+                start = Instant::now();
+                if let Some(path) = &args.live.emit_live_hashes {
+                    emit_live_hashes(path, &meta, cargo_home, false)?;
+                }
+                // The journal is saved before anything is deleted so the current run is included
+                // when checking `--max-age`.
+                if !dry_run {
+                    let journal_path = journal_path(&args.retention, cargo_home);
+                    let mut j = Journal::load(&journal_path).unwrap_or_else(|e| {
+                        warn!("starting a new journal\n{:#}", e);
+                        Journal::default()
+                    });
+                    j.record(cargo_home, &meta, SystemTime::now());
+                    if let Err(e) = j.save(&journal_path) {
+                        warn!("{:#}", e);
+                    }
+                    journal = Some((j, journal_path));
+                }
+                if args.gc_git {
+                    let repos = cargo_ci_precache::retained_git_dbs(&meta, &cache_options)?;
+                    gc = Some((repos, args.gc_git_args.as_str()));
+                }
+                if args.verify_checksums {
+                    cargo_ci_precache::verify_crate_checksums(
+                        &meta,
+                        &cache_options,
+                        &mut collect(PlanReason::ChecksumMismatch),
+                    )?;
+                }
+                if args.remove_yanked {
+                    cargo_ci_precache::remove_yanked(
+                        &meta,
+                        &cache_options,
+                        &mut collect(PlanReason::Yanked),
+                    )?;
+                }
+                found = Some(
+                    Cleaner::new(meta)
+                        .cache_options(cache_options.clone())
+                        .include_src(args.include_src)
+                        .measure(measure)
+                        .plan_cargo_cache()?,
+                );
+            }
+            if output.verbose {
+                registries =
+                    skipped_registries(&cache_options, args.include_src || args.consistency_only)?;
+            }
+            min_age = args.min_age;
+            if args.stats.stats_effectiveness {
+                since = args.stats.since;
+            }
+        }
+        Clean::Target(args) => {
+            let meta = load_metadata(
+                &args.project,
+                &[],
+                args.project.filter_platform.as_ref(),
+                &args.live.extra_live_hashes,
+                global,
+                summary,
+            )?;
+            start = Instant::now();
+            let evidence = cargo_ci_precache::check_target(&meta)?;
+            if !evidence.is_match() {
+                if !args.force {
+                    return Err(Error::msg(format!(
+                        "target directory doesn't appear to belong to the workspace, use --force to clean it anyways\n{}",
+                        evidence
+                    )));
+                }
+                warn!(
+                    "target directory doesn't appear to belong to the workspace\n{}",
+                    evidence
+                );
+            }
+            if let Some(path) = &args.live.emit_live_hashes {
+                emit_live_hashes(path, &meta, cargo_home, true)?;
+            }
+            // Only a dry run's list is read by people, so it's the only one annotated.
+            if dry_run && output.output_format == OutputFormat::Text {
+                outdated = Some(cargo_ci_precache::outdated_units(&meta, cargo_home)?);
+            }
+            found = Some(
+                Cleaner::new(meta)
+                    .cargo_home(cargo_home)
+                    .measure(measure)
+                    .plan_target()?,
+            );
+            if args.stats.stats_effectiveness {
+                since = args.stats.since;
+            }
+        }
+        Clean::InstalledBins(args) => {
+            let report = cargo_ci_precache::check_installed_bins(cargo_home, &args.keep_bins)?;
+            for path in &report.untracked {
+                log!(Info, "untracked binary: {}", path.display());
+                eprintln!("untracked binary: {}", path.display());
+                if args.remove_untracked_bins {
+                    collect(PlanReason::UntrackedBin)(path);
+                }
+            }
+            for (package, bin) in &report.missing {
+                log!(Info, "missing binary: {} from {}", bin, package);
+                eprintln!("missing binary: {} from {}", bin, package);
+            }
+            for package in &report.mismatched {
+                eprintln!(
+                    "package only listed in one of .crates.toml and .crates2.json: {}",
+                    package
+                );
+            }
+            if args.remove_missing_records && !report.missing.is_empty() {
+                missing_records = report.missing;
+            }
+        }
+        Clean::Apply(plan, _) => {
+            summary.target_dir = plan.environment.target_dir.clone();
+            problems = plan.check_paths();
+            for item in &plan.items {
+                match item.changed() {
+                    Ok(None) => applied.push(item.clone()),
+                    Ok(Some(why)) => warn!("skipping {}, {}", item.path.display(), why),
+                    Err(e) => warn!("skipping {}\n{:#}", item.path.display(), e),
+                }
+            }
+        }
+    }
+    let target_dir = summary.target_dir.clone();
+
+    let temp = match delete {
+        Some(delete) if !delete.dry_run => Some(
+            delete
+                .temp
+                .clone()
+                .or_else(|| env::var_os("TEMP").map(PathBuf::from))
+                .ok_or_else(|| Error::msg("no temp dir"))?,
+        ),
+        _ => None,
+    };
+
+    // Writing a plan doesn't delete anything, and applying it checks again.
+    if let Some(delete) = delete {
+        problems.extend(match (mode, &target_dir) {
+            (Mode::Target, Some(target_dir)) => {
+                cargo_ci_precache::check_target_safety(target_dir, cargo_home, temp.as_deref())?
+            }
+            _ => cargo_ci_precache::check_cargo_cache_safety(cargo_home, temp.as_deref())?,
+        });
+        if !problems.is_empty() {
+            let problems = problems.join("\n");
+            if !delete.force_unsafe {
+                return Err(Error::msg(format!(
+                    "refusing to clean, use --force-unsafe to clean anyways\n{}",
+                    problems
+                )));
+            }
+            warn!("cleaning despite safety problems\n{}", problems);
+        }
+    }
+
+    let errors = RefCell::new(Vec::new());
+    let not_permitted = RefCell::new(Vec::new());
+    let mut delete_item: Box<dyn FnMut(&Path)> = match temp {
+        // Items are listed separately.
+        None => Box::new(|_| ()),
+        Some(mut temp) => {
+            // Directories moved into the temp folder are named only from an incrementing counter
+            // to avoid name collisions on a single run, but this would mean multiple runs would
+            // certainly have a collision. Working in a directory named after the current time
+            // should avoid this.
+            temp.push(
+                match SystemTime::UNIX_EPOCH.elapsed() {
+                    Ok(x) => x,
+                    Err(e) => e.duration(),
+                }
+                .as_nanos()
+                .to_string(),
+            );
+
+            fs::create_dir_all(&temp)
+                .with_context(|| format!("error creating temp dir: {}", temp.display()))?;
+            // Where each directory is moved is recorded so the run can be undone.
+            let mut moves = MoveLog::create(&temp)?;
+            log!(Info, "moving directories into {}", temp.display());
+            if output.verbose {
+                eprintln!("moving directories into {}", temp.display());
+            }
+
+            let mut counter = 0u32;
+
+            // Keep cargo's record of the global cache in sync with what's deleted.
+            let global_cache = match mode {
+                Mode::CargoCache => GlobalCache::open(cargo_home).unwrap_or_else(|e| {
+                    warn!("error opening cargo's global cache database\n{}", e);
+                    None
+                }),
+                Mode::Target | Mode::InstalledBins => None,
+            };
+
+            let errors = &errors;
+            let not_permitted = &not_permitted;
+            Box::new(move |path| match remove_item(path, &mut counter, &temp) {
+                Ok(moved) => {
+                    log!(Info, "deleted {}", path.display());
+                    if let Some(Err(e)) = moved.map(|name| moves.record(path, &name)) {
+                        warn!("{:#}", e);
+                    }
+                    if let Some(Err(e)) = global_cache.as_ref().map(|c| c.remove(path)) {
+                        warn!(
+                            "error updating cargo's global cache database for {}\n{}",
+                            path.display(),
+                            e
+                        );
+                    }
+                }
+                // Usually files left by another user, e.g. root in a container. These are
+                // reported together once everything else is deleted.
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    log!(
+                        Warning,
+                        "skipping {}, not owned or permitted\n{}",
+                        path.display(),
+                        e
+                    );
+                    not_permitted.borrow_mut().push(PathBuf::from(path));
+                }
+                Err(e) => {
+                    let msg = format!("error removing {}\n{}", path.display(), e);
+                    log!(Error, "{}", msg);
+                    if GITHUB.load(Ordering::Relaxed) {
+                        eprintln!("::warning::{}", escape_workflow_data(&msg));
+                    } else {
+                        eprintln!("{}", msg);
+                    }
+                    errors
+                        .borrow_mut()
+                        .push(format!("error removing {}: {}", path.display(), e));
+                }
+            })
+        }
+    };
+
+    // The same item may be listed more than once, e.g. a yanked crate which is also unused. The
+    // first reason it was found for is kept.
+    let mut plan = Plan::new(
+        mode.name(),
+        PlanEnvironment::new(cargo_home.into(), target_dir.clone()),
+    );
+    plan.items = applied;
+    let mut listed = HashSet::new();
+    for (path, reason) in collected.into_inner() {
+        if !listed.insert(path.clone()) {
+            continue;
+        }
+        if measure {
+            plan.add(&path, reason)?;
+        } else {
+            plan.add_unmeasured(&path, reason);
+        }
+    }
+    if let Some(found) = found {
+        plan.merge(found);
+    }
+
+    // Items modified too recently may be in use by another job.
+    let mut skipped = Vec::new();
+    if let Some(min_age) = min_age {
+        skipped = plan.take_recently_modified(min_age);
+        for item in &skipped {
+            log!(
+                Info,
+                "keeping {}, modified within --min-age",
+                item.path.display()
+            );
+        }
+    }
+    for item in &plan.items {
+        log!(Info, "planned deletion of {}", item.path.display());
+    }
+    if let Some(delete) = delete {
+        check_limits(&plan.items, delete.max_delete, delete.max_delete_bytes)?;
+    }
+    // Measured before anything is deleted, since the deleted items count as restored.
+    if let Some(since) = since {
+        let deleted: HashSet<_> = plan.items.iter().map(|item| item.path.as_path()).collect();
+        let deleted = |p: &Path| deleted.contains(p);
+        let stats = match (mode, &target_dir) {
+            (Mode::Target, Some(target_dir)) => {
+                cargo_ci_precache::target_effectiveness(target_dir, since, &deleted)?
+            }
+            _ => cargo_ci_precache::cache_effectiveness(cargo_home, since, &deleted)?,
+        };
+        log!(Info, "cache effectiveness: {}", stats.describe());
+        summary.effectiveness = Some(stats);
+    }
+    if let Some(path) = plan_path {
+        plan.write(path)?;
+        log!(
+            Info,
+            "wrote plan {} with {} items, digest {}",
+            path.display(),
+            plan.items.len(),
+            plan.digest
+        );
+        eprintln!(
+            "wrote plan {} with {} items, environment digest {}",
+            path.display(),
+            plan.items.len(),
+            plan.digest
+        );
+    }
+
+    // Only the output is sorted, items are still deleted in the order they were found.
+    skipped.sort_by(|x, y| x.path.cmp(&y.path));
+    let relative_to = match output.relative_to {
+        Some(RelativeTo::Target) => target_dir,
+        Some(RelativeTo::CargoHome) => Some(cargo_home.into()),
+        Some(RelativeTo::Cwd) => Some(env::current_dir()?),
+        None => None,
+    };
+    let writer = ItemWriter {
+        format: &output.output_format,
+        relative_to,
+        outdated,
+    };
+    let action = if dry_run {
+        ItemAction::WouldDelete
+    } else {
+        ItemAction::Delete
+    };
+    summary.add_records(&writer.write(&skipped, &plan.items, action)?);
+    summary.timings.plan = Some(start.elapsed().as_secs_f64());
+    let start = Instant::now();
+
+    cargo_ci_precache::execute(&plan, &mut |item| delete_item(&item.path));
+    drop(delete_item);
+    summary.errors.append(&mut errors.borrow_mut());
+    let not_permitted = not_permitted.into_inner();
+    if !not_permitted.is_empty() {
+        let chown_check = matches!(delete, Some(delete) if delete.chown_check);
+        warn!(
+            "{} items skipped, not owned or permitted{}",
+            not_permitted.len(),
+            if chown_check {
+                ""
+            } else {
+                ", use --chown-check to list their owners"
+            }
+        );
+        if chown_check {
+            let owners = list_owners(&not_permitted);
+            log!(Info, "{}", owners);
+            eprint!("{}", owners);
+        }
+        for path in &not_permitted {
+            if let Some(item) = plan.items.iter().find(|item| item.path == *path) {
+                summary.add_not_permitted(path, item.info.kind, item.bytes);
+            }
+        }
+    }
+    summary.timings.delete = Some(start.elapsed().as_secs_f64());
+    if !missing_records.is_empty() {
+        if dry_run {
+            for (package, bin) in &missing_records {
+                eprintln!("would remove the record of {} from {}", bin, package);
+            }
+        } else {
+            cargo_ci_precache::remove_install_records(cargo_home, &missing_records)?;
+        }
+    }
+    if let Some((mut journal, journal_path)) = journal {
+        journal.compact(cargo_home);
+        if let Err(e) = journal.save(&journal_path) {
+            warn!("{:#}", e);
+        }
+    }
+
+    if global.summary_json.is_some() || output.compare.is_some() {
+        let dirs: Vec<(&Path, &str)> = match mode {
+            Mode::CargoCache => cargo_ci_precache::REPORT_COMPONENTS
+                .iter()
+                .map(|dir| (cargo_home, *dir))
+                .collect(),
+            Mode::Target => match &summary.target_dir {
+                Some(target_dir) => ["debug/deps", "debug/build", "debug/.fingerprint"]
+                    .iter()
+                    .map(|dir| (target_dir.as_path(), *dir))
+                    .collect(),
+                None => Vec::new(),
+            },
+            Mode::InstalledBins => vec![(cargo_home, "bin")],
+        };
+        let mut retained = BTreeMap::new();
+        for (base, dir) in dirs {
+            let usage = cargo_ci_precache::disk_usage(&base.join(dir)).unwrap_or_default();
+            retained.insert(String::from(dir), usage);
+        }
+        summary.retained = retained;
+    }
+    if let Some(path) = &output.compare {
+        match RunSummary::read(path).and_then(|previous| summary.compare(&previous)) {
+            Ok(comparison) => {
+                print_comparison(path, &comparison);
+                summary.comparison = Some(comparison);
+            }
+            Err(e) => warn!("skipping comparison\n{:#}", e),
+        }
+    }
+
+    if let Some(stats) = &summary.effectiveness {
+        eprintln!("cache effectiveness: {}", stats.describe());
+    }
+    if output.verbose {
+        let action = if dry_run {
+            "would be deleted"
+        } else {
+            "deleted"
+        };
+        eprintln!("{} items {}", plan.items.len(), action);
+        if !skipped.is_empty() {
+            eprintln!("{} recently modified items skipped:", skipped.len());
+            for item in &skipped {
+                eprintln!("    {}", writer.display_path(&item.path).display());
+            }
+        }
+        if !registries.is_empty() {
+            eprintln!("{} registries skipped:", registries.len());
+            for path in &registries {
+                eprintln!("    {}", path.display());
+            }
+        }
+    }
+
+    if let Some((repos, args)) = gc {
+        if dry_run {
+            for repo in &repos {
+                eprintln!("would run git gc on {}", repo.display());
+            }
+        } else {
+            gc_git_repos(&repos, args);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_size;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("123").unwrap(), 123);
+        assert_eq!(parse_size("2K").unwrap(), 2048);
+        assert_eq!(parse_size("3mb").unwrap(), 3 << 20);
+        assert_eq!(parse_size("1 GiB").unwrap(), 1 << 30);
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999T").is_err());
     }
 }
@@ -1,16 +1,22 @@
+#[cfg(feature = "lockfiles")]
+use crate::lockfile::Lockfile;
+use crate::source::{git_dir_names, registry_dir_names};
+use anyhow::Context;
 use serde::{
     de::{SeqAccess, Visitor},
     Deserialize, Deserializer,
 };
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     ffi::{OsStr, OsString},
     fmt,
-    path::PathBuf,
+    io::Read,
+    path::{Path, PathBuf},
 };
 
 #[derive(Deserialize)]
 struct Package {
+    name: String,
     source: Option<String>,
     manifest_path: PathBuf,
     id: String,
@@ -18,10 +24,12 @@ struct Package {
 
 enum CachedPackage<'a> {
     Registry {
+        source: &'a str,
         registry: &'a OsStr,
         name: &'a OsStr,
     },
     Git {
+        source: &'a str,
         repo: &'a OsStr,
         rev: &'a OsStr,
     },
@@ -29,13 +37,16 @@ enum CachedPackage<'a> {
 impl<'a> CachedPackage<'a> {
     fn new(p: &'a Package) -> Option<Self> {
         let source = p.source.as_deref()?;
-        Some(if source.starts_with("registry+") {
+        let is_registry = source.starts_with("registry+") || source.starts_with("sparse+");
+        Some(if is_registry {
             Self::Registry {
+                source,
                 registry: p.manifest_path.parent()?.parent()?.file_name()?,
                 name: p.manifest_path.parent()?.file_name()?,
             }
         } else if source.starts_with("git+") {
             Self::Git {
+                source,
                 repo: p.manifest_path.parent()?.parent()?.file_name()?,
                 rev: p.manifest_path.parent()?.file_name()?,
             }
@@ -47,12 +58,104 @@ impl<'a> CachedPackage<'a> {
 
 /// Directory names for packages in the global cargo cache, stored for lookup during filesystem
 /// traversal.
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct PackageSet {
     /// registry -> package map. package has the form `{name}-{version}`.
-    pub registry: HashMap<OsString, HashMap<OsString, String>>,
+    ///
+    /// Packages are listed both under the directory their manifest is in, and under every
+    /// directory cargo could use for their source. With source replacement these differ, and both
+    /// the mirror and the original registry are treated as the same registry.
+    pub(crate) registry: HashMap<OsString, HashMap<OsString, String>>,
     /// repository -> commit map.
-    pub git: HashMap<OsString, HashMap<OsString, String>>,
+    ///
+    /// Repositories are listed under the directory names computed from their canonicalized url, so
+    /// every url referring to the same repository maps to the same directories.
+    pub(crate) git: HashMap<OsString, HashMap<OsString, String>>,
+    /// id -> package map for packages which are not in the global cargo cache.
+    pub(crate) local: HashMap<String, LocalPackage>,
+    /// id -> the directory containing the manifest, for every package. Empty when read from
+    /// lockfiles.
+    pub(crate) roots: HashMap<String, PathBuf>,
+}
+
+impl PackageSet {
+    /// Adds every package from a lockfile. Git packages are added using their full commit hash,
+    /// rather than the abbreviated hash used for the checkout directory.
+    #[cfg(feature = "lockfiles")]
+    pub fn add_lockfile(&mut self, lockfile: &Lockfile) {
+        for (name, version, source) in &lockfile.packages {
+            let id = format!("{} {} ({})", name, version, source);
+            if source.starts_with("registry+") || source.starts_with("sparse+") {
+                for registry in registry_dir_names(source) {
+                    self.registry
+                        .entry(registry.into())
+                        .or_default()
+                        .insert(format!("{}-{}", name, version).into(), id.clone());
+                }
+            } else if let Some(rev) = source
+                .strip_prefix("git+")
+                .and_then(|s| s.split('#').nth(1))
+            {
+                for repo in git_dir_names(source) {
+                    self.git
+                        .entry(repo.into())
+                        .or_default()
+                        .insert(rev.into(), id.clone());
+                }
+            }
+        }
+    }
+
+    /// Gets `{name} {version}` for every package in the global cargo cache.
+    pub fn package_names(&self) -> BTreeSet<String> {
+        self.registry
+            .values()
+            .chain(self.git.values())
+            .flat_map(HashMap::values)
+            .filter_map(|id| package_id_name_version(id))
+            .map(|(name, version)| format!("{} {}", name, version))
+            .collect()
+    }
+
+    /// Checks whether any package is from the registry stored in the given directory of
+    /// `registry/cache`, `registry/src` or `registry/index`.
+    pub fn uses_registry(&self, registry: &OsStr) -> bool {
+        self.registry.contains_key(registry)
+    }
+
+    /// Checks whether a package, `{name}-{version}`, is referenced in the given registry.
+    pub fn uses_registry_package(&self, registry: &OsStr, package: &OsStr) -> bool {
+        matches!(self.registry.get(registry), Some(packages) if packages.contains_key(package))
+    }
+
+    /// Checks whether any package is from the repository in `git/db/{repo}`, or
+    /// `git/checkouts/{repo}`.
+    pub fn uses_git_repo(&self, repo: &OsStr) -> bool {
+        self.git.contains_key(repo)
+    }
+
+    /// Checks whether a checkout in `git/checkouts/{repo}` is referenced. Checkouts are named with
+    /// an abbreviated commit hash, but packages read from a lockfile have the full hash.
+    pub fn uses_checkout(&self, repo: &OsStr, rev: &OsStr) -> bool {
+        match self.git.get(repo) {
+            Some(revs) => {
+                let rev_str = rev.to_string_lossy();
+                revs.contains_key(rev)
+                    || revs
+                        .keys()
+                        .any(|r| r.to_string_lossy().starts_with(&*rev_str))
+            }
+            None => false,
+        }
+    }
+}
+
+/// A package built from a local path.
+#[derive(Clone)]
+pub struct LocalPackage {
+    pub(crate) name: String,
+    /// The directory containing the package's manifest.
+    pub(crate) root: PathBuf,
 }
 impl<'d> Deserialize<'d> for PackageSet {
     fn deserialize<D: Deserializer<'d>>(d: D) -> Result<Self, D::Error> {
@@ -66,21 +169,51 @@ impl<'d> Deserialize<'d> for PackageSet {
 
             fn visit_seq<A: SeqAccess<'d>>(mut self, mut seq: A) -> Result<Self::Value, A::Error> {
                 while let Some(p) = seq.next_element::<Package>()? {
+                    let root = p.manifest_path.parent().unwrap_or(&p.manifest_path);
+                    self.0.roots.insert(p.id.clone(), root.into());
                     match CachedPackage::new(&p) {
+                        None if p.source.is_none() => {
+                            let root = p.manifest_path.parent().unwrap_or(&p.manifest_path);
+                            let package = LocalPackage {
+                                name: p.name,
+                                root: root.into(),
+                            };
+                            self.0.local.insert(p.id, package);
+                        }
                         None => (),
-                        Some(CachedPackage::Registry { registry, name }) => {
-                            self.0
-                                .registry
-                                .entry(registry.into())
-                                .or_default()
-                                .insert(name.into(), p.id);
+                        Some(CachedPackage::Registry {
+                            source,
+                            registry,
+                            name,
+                        }) => {
+                            let aliases = registry_dir_names(source);
+                            let registries = aliases
+                                .iter()
+                                .map(OsStr::new)
+                                .filter(|&alias| alias != registry)
+                                .chain(Some(registry));
+                            for registry in registries {
+                                self.0
+                                    .registry
+                                    .entry(registry.into())
+                                    .or_default()
+                                    .insert(name.into(), p.id.clone());
+                            }
                         }
-                        Some(CachedPackage::Git { repo, rev }) => {
-                            self.0
-                                .git
-                                .entry(repo.into())
-                                .or_default()
-                                .insert(rev.into(), p.id);
+                        Some(CachedPackage::Git { source, repo, rev }) => {
+                            let names = git_dir_names(source);
+                            let repos = names
+                                .iter()
+                                .map(OsStr::new)
+                                .filter(|&name| name != repo)
+                                .chain(Some(repo));
+                            for repo in repos {
+                                self.0
+                                    .git
+                                    .entry(repo.into())
+                                    .or_default()
+                                    .insert(rev.into(), p.id.clone());
+                            }
                         }
                     }
                 }
@@ -118,7 +251,7 @@ fn build_feature_string(features: &[String]) -> String {
 
 #[derive(Default)]
 struct ResolveNodes {
-    package_features: HashMap<String, String>,
+    package_features: HashMap<String, BTreeSet<String>>,
 }
 impl<'d> Deserialize<'d> for ResolveNodes {
     fn deserialize<D: Deserializer<'d>>(d: D) -> Result<Self, D::Error> {
@@ -132,9 +265,12 @@ impl<'d> Deserialize<'d> for ResolveNodes {
 
             fn visit_seq<A: SeqAccess<'d>>(mut self, mut seq: A) -> Result<Self::Value, A::Error> {
                 while let Some(n) = seq.next_element::<ResolveNode>()? {
-                    self.0
-                        .package_features
-                        .insert(n.id, build_feature_string(&n.features));
+                    self.0.package_features.insert(
+                        n.id,
+                        Some(build_feature_string(&n.features))
+                            .into_iter()
+                            .collect(),
+                    );
                 }
                 Ok(self.0)
             }
@@ -144,7 +280,9 @@ impl<'d> Deserialize<'d> for ResolveNodes {
     }
 }
 
-fn deserialize_resolve<'d, D: Deserializer<'d>>(d: D) -> Result<HashMap<String, String>, D::Error> {
+fn deserialize_resolve<'d, D: Deserializer<'d>>(
+    d: D,
+) -> Result<HashMap<String, BTreeSet<String>>, D::Error> {
     #[derive(Deserialize)]
     struct X {
         nodes: ResolveNodes,
@@ -153,11 +291,545 @@ fn deserialize_resolve<'d, D: Deserializer<'d>>(d: D) -> Result<HashMap<String,
     X::deserialize(d).map(|x| x.nodes.package_features)
 }
 
-#[derive(Deserialize)]
+/// Gets the source from a package id. Handles both the `{name} {version} ({source})` form, and the
+/// `{source}#{name}@{version}` form used since cargo 1.77.
+pub fn package_id_source(id: &str) -> Option<&str> {
+    match id.strip_suffix(')') {
+        Some(id) => id.rsplit_once(" (").map(|(_, source)| source),
+        None => id.rsplit_once('#').map(|(source, _)| source),
+    }
+}
+
+/// Gets the name and version from a package id. In the newer form the name is left out when it
+/// matches the last segment of the source url.
+pub fn package_id_name_version(id: &str) -> Option<(&str, &str)> {
+    if id.ends_with(')') {
+        let mut parts = id.splitn(3, ' ');
+        return Some((parts.next()?, parts.next()?));
+    }
+    let (source, package) = id.rsplit_once('#')?;
+    match package.split_once('@') {
+        Some((name, version)) => Some((name, version)),
+        None => {
+            let url = source.split('?').next().unwrap_or(source);
+            let name = url.trim_end_matches('/').rsplit('/').next()?;
+            Some((name.strip_suffix(".git").unwrap_or(name), package))
+        }
+    }
+}
+
+/// A package in the global cargo cache referenced by the metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackageRef<'a> {
+    /// The name of the directory the registry or repository is stored in, e.g.
+    /// `index.crates.io-1949cf8c6b5b557f`.
+    pub dir: &'a OsStr,
+    /// For registry packages their `{name}-{version}`, which names both the unpacked source and the
+    /// `.crate` file. For git packages the commit they're checked out at.
+    pub key: &'a OsStr,
+    /// The package id, in the form used by the metadata it came from.
+    pub id: &'a str,
+}
+
+// Lists every package in one of the package maps.
+fn package_refs(
+    packages: &HashMap<OsString, HashMap<OsString, String>>,
+) -> impl Iterator<Item = PackageRef<'_>> {
+    packages.iter().flat_map(|(dir, packages)| {
+        packages
+            .iter()
+            .map(move |(key, id)| PackageRef { dir, key, id })
+    })
+}
+
+/// The parts of `cargo metadata`'s output needed to decide what's still used, either from
+/// `MetadataCommand` or from lockfiles with `metadata_from_lockfiles`.
+#[derive(Deserialize, Default, Clone)]
 pub struct Metadata {
-    pub packages: PackageSet,
-    pub target_directory: PathBuf,
+    pub(crate) packages: PackageSet,
+    pub(crate) workspace_members: Vec<String>,
+    pub(crate) workspace_root: PathBuf,
+    pub(crate) target_directory: PathBuf,
 
+    /// id -> feature strings map. A package can have several feature strings once metadata from
+    /// multiple workspaces is merged.
     #[serde(deserialize_with = "deserialize_resolve", rename = "resolve")]
-    pub package_features: HashMap<String, String>,
+    pub(crate) package_features: HashMap<String, BTreeSet<String>>,
+
+    /// Metadata hashes of units in the target directory to keep even if they look outdated, e.g.
+    /// ones another job found were still in use.
+    #[serde(skip)]
+    pub(crate) live_meta_hashes: HashSet<String>,
+}
+impl Metadata {
+    /// Reads the output of `cargo metadata --format-version 1`, e.g. saved by an earlier build
+    /// step, instead of running cargo.
+    pub fn from_reader(mut reader: impl Read) -> anyhow::Result<Self> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .context("error reading cargo metadata")?;
+        Self::from_slice(&data)
+    }
+
+    /// Parses the output of `cargo metadata --format-version 1`. Output in any other format is an
+    /// error.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let data = std::fs::read("metadata.json")?;
+    /// let meta = cargo_ci_precache::Metadata::from_slice(&data)?;
+    /// println!("{}", meta.target_directory().display());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_slice(data: &[u8]) -> anyhow::Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_slice(data).context("error parsing cargo metadata")?;
+        match value.get("version").and_then(serde_json::Value::as_u64) {
+            Some(1) => (),
+            Some(version) => {
+                return Err(anyhow::Error::msg(format!(
+                    "unsupported cargo metadata format version {}, expected the output of \
+                     `cargo metadata --format-version 1`",
+                    version
+                )))
+            }
+            None => {
+                return Err(anyhow::Error::msg(
+                    "missing cargo metadata format version, expected the output of \
+                     `cargo metadata --format-version 1`",
+                ))
+            }
+        }
+        serde_json::from_value(value).context("error parsing cargo metadata")
+    }
+
+    /// The target directory used by the workspace.
+    pub fn target_directory(&self) -> &Path {
+        &self.target_directory
+    }
+
+    /// Changes the target directory, e.g. when building with `--target-dir`.
+    pub fn set_target_directory(&mut self, dir: impl Into<PathBuf>) {
+        self.target_directory = dir.into();
+    }
+
+    /// The root directory of the workspace. Empty for metadata read from lockfiles.
+    pub fn workspace_root(&self) -> &Path {
+        &self.workspace_root
+    }
+
+    /// The package ids of the workspace's members.
+    pub fn workspace_members(&self) -> &[String] {
+        &self.workspace_members
+    }
+
+    /// Iterates over the registry packages in the global cargo cache, in no particular order.
+    /// Packages from a registry which can be stored under several directories, e.g. crates.io
+    /// through the git and sparse protocols, are listed once for each directory.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let meta = cargo_ci_precache::MetadataCommand::new().exec()?;
+    /// for package in meta.registry_packages() {
+    ///     // e.g. `registry/cache/index.crates.io-1949cf8c6b5b557f/serde-1.0.0.crate`
+    ///     println!(
+    ///         "registry/cache/{}/{}.crate",
+    ///         package.dir.to_string_lossy(),
+    ///         package.key.to_string_lossy()
+    ///     );
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn registry_packages(&self) -> impl Iterator<Item = PackageRef<'_>> {
+        package_refs(&self.packages.registry)
+    }
+
+    /// Iterates over the git packages in the global cargo cache, in no particular order. Like
+    /// `registry_packages`, a repository may be listed under several directories.
+    pub fn git_packages(&self) -> impl Iterator<Item = PackageRef<'_>> {
+        package_refs(&self.packages.git)
+    }
+
+    /// Iterates over the commits used from the repository stored in the given directory of
+    /// `git/db`. Commits read from lockfiles are full hashes, while ones from `cargo metadata` are
+    /// abbreviated like the directories in `git/checkouts`; `uses_checkout` handles both.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// use std::ffi::OsStr;
+    ///
+    /// let meta = cargo_ci_precache::MetadataCommand::new().exec()?;
+    /// for rev in meta.git_revisions(OsStr::new("serde-0123456789abcdef")) {
+    ///     println!("{}", rev.to_string_lossy());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn git_revisions(&self, repo: &OsStr) -> impl Iterator<Item = &OsStr> {
+        self.packages
+            .git
+            .get(repo)
+            .into_iter()
+            .flat_map(|revs| revs.keys().map(OsString::as_os_str))
+    }
+
+    /// Checks whether a checkout in `git/checkouts/{repo}` is referenced.
+    pub fn uses_checkout(&self, repo: &OsStr, rev: &OsStr) -> bool {
+        self.packages.uses_checkout(repo, rev)
+    }
+
+    /// Gets the features a package is built with, by package id. Each entry is one set of
+    /// features formatted like cargo's fingerprints, e.g. `["default", "std"]`. Merged metadata
+    /// can have a different set for each workspace. Returns `None` for packages which aren't
+    /// built, and for metadata read from lockfiles.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let meta = cargo_ci_precache::MetadataCommand::new().exec()?;
+    /// for package in meta.registry_packages() {
+    ///     if let Some(features) = meta.package_features(package.id) {
+    ///         println!("{}: {:?}", package.id, features);
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn package_features(&self, id: &str) -> Option<&BTreeSet<String>> {
+        self.package_features.get(id)
+    }
+
+    /// Gets `{name} {version}` for every package in the global cargo cache.
+    pub fn package_names(&self) -> BTreeSet<String> {
+        self.packages.package_names()
+    }
+
+    /// Merges the metadata of another workspace into this one. Both must use the same target
+    /// directory. The workspace root of this metadata is kept.
+    pub fn merge(&mut self, other: Metadata) -> anyhow::Result<()> {
+        if self.target_directory != other.target_directory {
+            return Err(anyhow::Error::msg(format!(
+                "conflicting target directories {} and {}",
+                self.target_directory.display(),
+                other.target_directory.display(),
+            )));
+        }
+        for (registry, packages) in other.packages.registry {
+            self.packages
+                .registry
+                .entry(registry)
+                .or_default()
+                .extend(packages);
+        }
+        for (repo, revs) in other.packages.git {
+            self.packages.git.entry(repo).or_default().extend(revs);
+        }
+        self.packages.local.extend(other.packages.local);
+        self.packages.roots.extend(other.packages.roots);
+        for id in other.workspace_members {
+            if !self.workspace_members.contains(&id) {
+                self.workspace_members.push(id);
+            }
+        }
+        for (id, features) in other.package_features {
+            self.package_features
+                .entry(id)
+                .or_default()
+                .extend(features);
+        }
+        self.live_meta_hashes.extend(other.live_meta_hashes);
+        Ok(())
+    }
+
+    /// Iterates over the packages which are members of the workspace.
+    pub(crate) fn workspace_packages(&self) -> impl Iterator<Item = &LocalPackage> {
+        self.workspace_members
+            .iter()
+            .filter_map(move |id| self.packages.local.get(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{package_id_name_version, package_id_source, Metadata};
+    #[cfg(feature = "lockfiles")]
+    use crate::lockfile::Lockfile;
+    use std::{
+        ffi::OsStr,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn id_sources() {
+        let source = "registry+https://github.com/rust-lang/crates.io-index";
+        assert_eq!(
+            package_id_source(&format!("cfg-if 1.0.0 ({})", source)),
+            Some(source)
+        );
+        assert_eq!(
+            package_id_source(&format!("{}#cfg-if@1.0.0", source)),
+            Some(source)
+        );
+        assert_eq!(package_id_source("cfg-if"), None);
+    }
+
+    #[test]
+    fn id_names() {
+        let source = "registry+https://github.com/rust-lang/crates.io-index";
+        assert_eq!(
+            package_id_name_version(&format!("cfg-if 1.0.0 ({})", source)),
+            Some(("cfg-if", "1.0.0"))
+        );
+        assert_eq!(
+            package_id_name_version(&format!("{}#cfg-if@1.0.0", source)),
+            Some(("cfg-if", "1.0.0"))
+        );
+        assert_eq!(
+            package_id_name_version("git+https://github.com/foo/bar.git?branch=main#0.1.0"),
+            Some(("bar", "0.1.0"))
+        );
+        assert_eq!(package_id_name_version("cfg-if"), None);
+    }
+
+    #[test]
+    fn mirrored_registry() {
+        let meta: Metadata = serde_json::from_str(
+            r#"{
+                "packages": [{
+                    "name": "cfg-if",
+                    "source": "registry+https://github.com/rust-lang/crates.io-index",
+                    "manifest_path": "/cargo/registry/src/mirror.example.com-0123456789abcdef/cfg-if-1.0.0/Cargo.toml",
+                    "id": "cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)"
+                }],
+                "workspace_members": [],
+                "workspace_root": "/project",
+                "target_directory": "/project/target",
+                "resolve": { "nodes": [] }
+            }"#,
+        )
+        .unwrap();
+
+        for registry in &[
+            "mirror.example.com-0123456789abcdef",
+            "github.com-1ecc6299db9ec823",
+            "index.crates.io-6f17d22bba15001f",
+            "index.crates.io-1949cf8c6b5b557f",
+        ] {
+            let packages = meta
+                .packages
+                .registry
+                .get(OsStr::new(registry))
+                .unwrap_or_else(|| panic!("missing registry {}", registry));
+            assert!(packages.contains_key(OsStr::new("cfg-if-1.0.0")));
+        }
+        assert!(!meta
+            .packages
+            .registry
+            .contains_key(OsStr::new("other.example.com-0123456789abcdef")));
+    }
+
+    #[test]
+    fn git_repo_url_variants() {
+        let meta: Metadata = serde_json::from_str(
+            r#"{
+                "packages": [{
+                    "name": "foo",
+                    "source": "git+https://example.com/foo/bar.git#0123456789abcdef0123456789abcdef01234567",
+                    "manifest_path": "/cargo/git/checkouts/bar-fedcba9876543210/0123456/Cargo.toml",
+                    "id": "foo 0.1.0 (git+https://example.com/foo/bar.git#0123456789abcdef0123456789abcdef01234567)"
+                }],
+                "workspace_members": [],
+                "workspace_root": "/project",
+                "target_directory": "/project/target",
+                "resolve": { "nodes": [] }
+            }"#,
+        )
+        .unwrap();
+
+        let names = crate::source::git_dir_names("git+https://example.com/foo/bar");
+        for repo in names
+            .iter()
+            .map(String::as_str)
+            .chain(Some("bar-fedcba9876543210"))
+        {
+            let revs = meta
+                .packages
+                .git
+                .get(OsStr::new(repo))
+                .unwrap_or_else(|| panic!("missing repository {}", repo));
+            assert!(revs.contains_key(OsStr::new("0123456")));
+        }
+    }
+
+    #[test]
+    fn accessors() {
+        let mut meta: Metadata = serde_json::from_str(
+            r#"{
+                "packages": [{
+                    "name": "cfg-if",
+                    "source": "registry+https://github.com/rust-lang/crates.io-index",
+                    "manifest_path": "/cargo/registry/src/index.crates.io-1949cf8c6b5b557f/cfg-if-1.0.0/Cargo.toml",
+                    "id": "cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)"
+                }, {
+                    "name": "foo",
+                    "source": "git+https://example.com/foo/bar.git#0123456789abcdef0123456789abcdef01234567",
+                    "manifest_path": "/cargo/git/checkouts/bar-fedcba9876543210/0123456/Cargo.toml",
+                    "id": "foo 0.1.0 (git+https://example.com/foo/bar.git#0123456789abcdef0123456789abcdef01234567)"
+                }, {
+                    "name": "project",
+                    "source": null,
+                    "manifest_path": "/project/Cargo.toml",
+                    "id": "project 0.1.0 (path+file:///project)"
+                }],
+                "workspace_members": ["project 0.1.0 (path+file:///project)"],
+                "workspace_root": "/project",
+                "target_directory": "/project/target",
+                "resolve": { "nodes": [{
+                    "id": "cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "features": ["std"]
+                }] }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(meta.workspace_root(), Path::new("/project"));
+        assert_eq!(
+            meta.workspace_members(),
+            ["project 0.1.0 (path+file:///project)"]
+        );
+        assert_eq!(meta.target_directory(), Path::new("/project/target"));
+        meta.set_target_directory("/other/target");
+        assert_eq!(meta.target_directory(), Path::new("/other/target"));
+
+        let cfg_if = "cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)";
+        assert!(meta
+            .registry_packages()
+            .any(|p| p.dir == "index.crates.io-1949cf8c6b5b557f"
+                && p.key == "cfg-if-1.0.0"
+                && p.id == cfg_if));
+        assert!(meta.git_packages().all(|p| p.key == "0123456"));
+        let repo = OsStr::new("bar-fedcba9876543210");
+        assert_eq!(meta.git_revisions(repo).collect::<Vec<_>>(), ["0123456"]);
+        assert!(meta.uses_checkout(repo, OsStr::new("0123456")));
+        assert!(!meta.uses_checkout(repo, OsStr::new("89abcde")));
+        assert_eq!(meta.git_revisions(OsStr::new("missing")).count(), 0);
+
+        let features: Vec<_> = meta.package_features(cfg_if).unwrap().iter().collect();
+        assert_eq!(features, ["[\"std\"]"]);
+        assert!(meta.package_features("project").is_none());
+        assert_eq!(
+            meta.package_names().into_iter().collect::<Vec<_>>(),
+            ["cfg-if 1.0.0", "foo 0.1.0"]
+        );
+    }
+
+    #[test]
+    fn format_version() {
+        let json = |version: &str| {
+            format!(
+                r#"{{
+                    "packages": [],
+                    "workspace_members": [],
+                    "workspace_root": "/project",
+                    "target_directory": "/project/target",
+                    "resolve": {{ "nodes": [] }}{}
+                }}"#,
+                version
+            )
+        };
+
+        let meta = Metadata::from_reader(json(r#", "version": 1"#).as_bytes()).unwrap();
+        assert_eq!(meta.target_directory(), Path::new("/project/target"));
+        let e = Metadata::from_slice(json(r#", "version": 2"#).as_bytes())
+            .map(drop)
+            .unwrap_err();
+        assert!(e.to_string().contains("version 2"), "{}", e);
+        let e = Metadata::from_slice(json("").as_bytes())
+            .map(drop)
+            .unwrap_err();
+        assert!(e.to_string().contains("--format-version 1"), "{}", e);
+        assert!(Metadata::from_slice(b"[1]").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "lockfiles")]
+    fn lockfile_packages() {
+        let lockfile = Lockfile::parse(
+            r#"
+version = 3
+
+[[package]]
+name = "cfg-if"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "baf1de31"
+
+[[package]]
+name = "foo"
+version = "0.1.0"
+source = "git+https://example.com/foo/bar.git?branch=main#0123456789abcdef0123456789abcdef01234567"
+
+[[package]]
+name = "local"
+version = "0.1.0"
+"#,
+        )
+        .unwrap();
+        let mut meta = Metadata::default();
+        meta.packages.add_lockfile(&lockfile);
+
+        let packages = &meta.packages.registry[OsStr::new("index.crates.io-1949cf8c6b5b557f")];
+        assert!(packages.contains_key(OsStr::new("cfg-if-1.0.0")));
+        let repo = &crate::source::git_dir_names("git+https://example.com/foo/bar")[0];
+        let revs = &meta.packages.git[OsStr::new(repo)];
+        assert!(revs.contains_key(OsStr::new("0123456789abcdef0123456789abcdef01234567")));
+        assert!(meta.packages.local.is_empty());
+    }
+
+    #[test]
+    fn merge_workspaces() {
+        let meta = |root: &str, target: &str, features: &str| -> Metadata {
+            serde_json::from_str(&format!(
+                r#"{{
+                    "packages": [{{
+                        "name": "cfg-if",
+                        "source": "registry+https://github.com/rust-lang/crates.io-index",
+                        "manifest_path": "/cargo/registry/src/github.com-1ecc6299db9ec823/cfg-if-1.0.0/Cargo.toml",
+                        "id": "cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)"
+                    }}, {{
+                        "name": "{root}",
+                        "source": null,
+                        "manifest_path": "/{root}/Cargo.toml",
+                        "id": "{root} 0.1.0 (path+file:///{root})"
+                    }}],
+                    "workspace_members": ["{root} 0.1.0 (path+file:///{root})"],
+                    "workspace_root": "/{root}",
+                    "target_directory": "{target}",
+                    "resolve": {{ "nodes": [{{
+                        "id": "cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                        "features": [{features}]
+                    }}] }}
+                }}"#,
+                root = root,
+                target = target,
+                features = features,
+            ))
+            .unwrap()
+        };
+
+        let mut merged = meta("a", "/target", "");
+        merged.merge(meta("b", "/target", "\"std\"")).unwrap();
+        assert_eq!(merged.workspace_root, PathBuf::from("/a"));
+        assert_eq!(merged.workspace_packages().count(), 2);
+        let features: Vec<_> = merged.package_features
+            ["cfg-if 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)"]
+            .iter()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(features, ["[\"std\"]", "[]"]);
+
+        assert!(merged.merge(meta("c", "/c/target", "")).is_err());
+    }
 }
@@ -0,0 +1,271 @@
+use anyhow::{Context, Error, Result};
+use cargo_ci_precache::write_atomic;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+const VERSION: u32 = 1;
+
+/// The recorded state of a single file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileMtime {
+    /// The modification time, in RFC 3339 format.
+    pub modified: String,
+    pub len: u64,
+}
+
+/// Records every file in a directory tree, keyed by its path relative to the root with `/` as the
+/// separator. Anything `skip` returns true for isn't recorded. Symlinks aren't followed, and paths
+/// which aren't valid unicode are skipped.
+pub fn record_tree(
+    root: &Path,
+    dir: &Path,
+    skip: &dyn Fn(&Path) -> bool,
+    files: &mut BTreeMap<String, FileMtime>,
+) -> io::Result<()> {
+    for e in fs::read_dir(dir)? {
+        let path = e?.path();
+        if skip(&path) {
+            continue;
+        }
+        let meta = path.symlink_metadata()?;
+        if meta.is_dir() {
+            record_tree(root, &path, skip, files)?;
+            continue;
+        }
+        if !meta.is_file() {
+            continue;
+        }
+        let relative = path
+            .strip_prefix(root)
+            .ok()
+            .and_then(|p| p.iter().map(|c| c.to_str()).collect::<Option<Vec<_>>>());
+        if let Some(relative) = relative {
+            files.insert(
+                relative.join("/"),
+                FileMtime {
+                    modified: humantime::format_rfc3339_nanos(meta.modified()?).to_string(),
+                    len: meta.len(),
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sets the modification time of a file or directory.
+pub fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    // Only the right to change the file's times is needed, so read-only files can be changed.
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_WRITE_ATTRIBUTES
+        options.access_mode(0x100);
+        // FILE_FLAG_BACKUP_SEMANTICS, needed to open a directory.
+        options.custom_flags(0x0200_0000);
+    }
+    #[cfg(not(windows))]
+    options.read(true);
+    options.open(path)?.set_modified(time)
+}
+
+/// What happened to each file when restoring modification times.
+#[derive(Default, Debug)]
+pub struct MtimesReport {
+    pub restored: usize,
+    /// Files which were already at their recorded time.
+    pub unchanged: usize,
+    /// Files whose length no longer matches the record, so their contents have changed.
+    pub changed: Vec<PathBuf>,
+    /// Files which no longer exist.
+    pub missing: usize,
+    pub errors: Vec<(PathBuf, io::Error)>,
+}
+
+/// The modification times of every file in a set of directories. Cargo decides whether something
+/// needs to be rebuilt by comparing modification times, which many CI caches don't preserve.
+///
+/// Paths are stored relative to each directory, so the times can be restored when the directories
+/// are unpacked somewhere else.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mtimes {
+    version: u32,
+    /// root name -> relative path -> file map.
+    pub roots: BTreeMap<String, BTreeMap<String, FileMtime>>,
+}
+impl Default for Mtimes {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            roots: BTreeMap::new(),
+        }
+    }
+}
+impl Mtimes {
+    /// Records every file in a directory under the given name. Anything `skip` returns true for,
+    /// e.g. what cleaning would remove, isn't recorded. Directories which don't exist are skipped.
+    pub fn add_root(
+        &mut self,
+        name: &str,
+        root: &Path,
+        skip: &dyn Fn(&Path) -> bool,
+    ) -> Result<()> {
+        if !root.is_dir() {
+            return Ok(());
+        }
+        let files = self.roots.entry(name.into()).or_default();
+        record_tree(root, root, skip, files)
+            .with_context(|| format!("error reading {}", root.display()))
+    }
+
+    /// Sets the modification time of every file recorded under the given name, relative to the
+    /// given directory. Files whose length has changed are skipped. With `dry_run` nothing is
+    /// changed, but the report still lists what would be restored.
+    pub fn restore(&self, name: &str, root: &Path, dry_run: bool) -> MtimesReport {
+        let mut report = MtimesReport::default();
+        for (relative, file) in self.roots.get(name).into_iter().flatten() {
+            let path = relative
+                .split('/')
+                .fold(root.to_path_buf(), |p, c| p.join(c));
+            let meta = match path.symlink_metadata() {
+                Ok(meta) if meta.is_file() => meta,
+                Ok(_) => {
+                    report.missing += 1;
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    report.missing += 1;
+                    continue;
+                }
+                Err(e) => {
+                    report.errors.push((path, e));
+                    continue;
+                }
+            };
+            if meta.len() != file.len {
+                report.changed.push(path);
+                continue;
+            }
+            let time = match humantime::parse_rfc3339(&file.modified) {
+                Ok(time) => time,
+                Err(e) => {
+                    report
+                        .errors
+                        .push((path, io::Error::new(io::ErrorKind::InvalidData, e)));
+                    continue;
+                }
+            };
+            if meta.modified().ok() == Some(time) {
+                report.unchanged += 1;
+                continue;
+            }
+            if dry_run {
+                report.restored += 1;
+                continue;
+            }
+            match set_modified(&path, time) {
+                Ok(()) => report.restored += 1,
+                Err(e) => report.errors.push((path, e)),
+            }
+        }
+        report
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let data =
+            fs::read(path).with_context(|| format!("error reading mtimes {}", path.display()))?;
+        let value: serde_json::Value = serde_json::from_slice(&data)
+            .with_context(|| format!("error parsing mtimes {}", path.display()))?;
+        match value.get("version").and_then(serde_json::Value::as_u64) {
+            Some(version) if version == u64::from(VERSION) => (),
+            Some(version) => {
+                return Err(Error::msg(format!(
+                    "unsupported mtimes version {} in {}",
+                    version,
+                    path.display()
+                )))
+            }
+            None => {
+                return Err(Error::msg(format!(
+                    "missing mtimes version in {}",
+                    path.display()
+                )))
+            }
+        }
+        serde_json::from_value(value)
+            .with_context(|| format!("error parsing mtimes {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &serde_json::to_vec(self)?)
+            .with_context(|| format!("error writing mtimes {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{set_modified, Mtimes};
+    use cargo_ci_precache::test_util::scratch_dir;
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn save_and_restore() {
+        let dir = scratch_dir("mtimes_test");
+        let root = dir.join("target");
+        fs::create_dir_all(root.join("debug/deps")).unwrap();
+        fs::create_dir_all(root.join(".temp")).unwrap();
+        for name in &["a", "b", "c"] {
+            fs::write(root.join("debug/deps").join(name), "x").unwrap();
+        }
+        fs::write(root.join(".temp/moved"), "x").unwrap();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let a = root.join("debug/deps/a");
+        set_modified(&a, old).unwrap();
+
+        let mut mtimes = Mtimes::default();
+        mtimes
+            .add_root("target", &root, &|p| p == root.join(".temp"))
+            .unwrap();
+        mtimes
+            .add_root("missing", &dir.join("missing"), &|_| false)
+            .unwrap();
+        assert_eq!(
+            mtimes.roots["target"].keys().collect::<Vec<_>>(),
+            ["debug/deps/a", "debug/deps/b", "debug/deps/c"]
+        );
+        let path = dir.join("mtimes.json");
+        mtimes.write(&path).unwrap();
+        let mtimes = Mtimes::read(&path).unwrap();
+
+        // Unpacking a cache touches everything, `b` was changed and `c` is gone.
+        let unpacked = dir.join("unpacked");
+        fs::rename(&root, &unpacked).unwrap();
+        let now = SystemTime::now();
+        for name in &["a", "b"] {
+            set_modified(&unpacked.join("debug/deps").join(name), now).unwrap();
+        }
+        fs::write(unpacked.join("debug/deps/b"), "xx").unwrap();
+        fs::remove_file(unpacked.join("debug/deps/c")).unwrap();
+
+        let report = mtimes.restore("target", &unpacked, true);
+        assert_eq!(report.restored, 1);
+        let a = unpacked.join("debug/deps/a");
+        assert_ne!(fs::metadata(&a).unwrap().modified().unwrap(), old);
+
+        let report = mtimes.restore("target", &unpacked, false);
+        assert_eq!(report.restored, 1);
+        assert_eq!(report.changed, [unpacked.join("debug/deps/b")]);
+        assert_eq!(report.missing, 1);
+        assert!(report.errors.is_empty());
+        assert_eq!(fs::metadata(&a).unwrap().modified().unwrap(), old);
+        assert_eq!(mtimes.restore("target", &unpacked, false).unchanged, 1);
+    }
+}
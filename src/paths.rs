@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Component, Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Strips a directory prefix from paths which may refer to it through a different route. e.g.
+/// symlinks, or on windows a different case or a verbatim (`\\?\`) prefix.
+pub struct PrefixMatcher {
+    prefix: PathBuf,
+    canonical_prefix: Option<PathBuf>,
+    // Canonicalized directories, keyed by the original path.
+    canonical_dirs: HashMap<PathBuf, Option<PathBuf>>,
+}
+impl PrefixMatcher {
+    pub fn new(prefix: PathBuf) -> Self {
+        let canonical_prefix = prefix.canonicalize().ok();
+        Self {
+            prefix,
+            canonical_prefix,
+            canonical_dirs: HashMap::new(),
+        }
+    }
+
+    /// Gets the remainder of the path after the prefix.
+    pub fn strip_prefix(&mut self, path: &Path) -> Option<PathBuf> {
+        if let Some(p) = strip_prefix(path, &self.prefix) {
+            return Some(p.into());
+        }
+
+        let path = self.canonicalize(path)?;
+        let prefix = self.canonical_prefix.as_deref().unwrap_or(&self.prefix);
+        strip_prefix(&path, prefix).map(PathBuf::from)
+    }
+
+    // Only the parent directory is canonicalized as that's shared between many files, and the
+    // file itself may no longer exist.
+    fn canonicalize(&mut self, path: &Path) -> Option<PathBuf> {
+        let (dir, name) = (path.parent()?, path.file_name()?);
+        let dir = self
+            .canonical_dirs
+            .entry(dir.into())
+            .or_insert_with(|| dir.canonicalize().ok())
+            .as_deref()?;
+        Some(dir.join(name))
+    }
+}
+
+fn strip_prefix<'a>(path: &'a Path, prefix: &Path) -> Option<&'a Path> {
+    let mut components = path.components();
+    for p in prefix.components() {
+        if !component_eq(components.next()?, p) {
+            return None;
+        }
+    }
+    Some(components.as_path())
+}
+
+#[cfg(not(windows))]
+fn component_eq(x: Component, y: Component) -> bool {
+    x == y
+}
+
+// Paths on windows are case insensitive, and may have a verbatim prefix.
+#[cfg(windows)]
+fn component_eq(x: Component, y: Component) -> bool {
+    use std::path::Prefix;
+    match (x, y) {
+        (Component::Prefix(x), Component::Prefix(y)) => match (x.kind(), y.kind()) {
+            (
+                Prefix::Disk(x) | Prefix::VerbatimDisk(x),
+                Prefix::Disk(y) | Prefix::VerbatimDisk(y),
+            ) => x.eq_ignore_ascii_case(&y),
+            (
+                Prefix::UNC(x1, x2) | Prefix::VerbatimUNC(x1, x2),
+                Prefix::UNC(y1, y2) | Prefix::VerbatimUNC(y1, y2),
+            ) => x1.eq_ignore_ascii_case(y1) && x2.eq_ignore_ascii_case(y2),
+            _ => x.as_os_str().eq_ignore_ascii_case(y.as_os_str()),
+        },
+        (x, y) => x.as_os_str().eq_ignore_ascii_case(y.as_os_str()),
+    }
+}
+
+/// Gets a temporary path in the same directory as the given file, e.g. `.plan.json.1234.0.tmp`.
+/// The name is unique to this process and call, so it never replaces another file.
+pub fn temp_path(path: &Path) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_else(|| OsStr::new("tmp")));
+    name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// Writes a file through a temporary file in the same directory, so the file is either complete
+/// or unchanged.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+    let result = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{temp_path, write_atomic, PrefixMatcher};
+    use crate::test_util::scratch_dir;
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn atomic_write() {
+        let dir = scratch_dir("atomic_write_test");
+        // A sibling with the name the temporary file would once have had is left alone.
+        let path = dir.join("summary.json");
+        fs::write(dir.join("summary.tmp"), "other").unwrap();
+        assert_ne!(temp_path(&path), temp_path(&path));
+        assert_eq!(temp_path(&path).parent(), Some(dir.as_path()));
+
+        write_atomic(&path, b"data").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        assert_eq!(fs::read(dir.join("summary.tmp")).unwrap(), b"other");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // The temporary file is removed when the rename fails.
+        let target = dir.join("dir");
+        fs::create_dir_all(target.join("child")).unwrap();
+        assert!(write_atomic(&target, b"data").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn same_prefix() {
+        let mut m = PrefixMatcher::new(PathBuf::from("cargo_home"));
+        assert_eq!(
+            m.strip_prefix(Path::new("cargo_home/registry/src/lib.rs")),
+            Some(PathBuf::from("registry/src/lib.rs"))
+        );
+        assert_eq!(m.strip_prefix(Path::new("other/registry/src/lib.rs")), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinked_prefix() {
+        let dir = scratch_dir("symlinked_prefix");
+        let real = dir.join("real");
+        let link = dir.join("link");
+        std::fs::create_dir_all(real.join("registry/src")).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let mut m = PrefixMatcher::new(link.clone());
+        assert_eq!(
+            m.strip_prefix(&real.join("registry/src/lib.rs")),
+            Some(PathBuf::from("registry/src/lib.rs"))
+        );
+
+        let mut m = PrefixMatcher::new(real);
+        assert_eq!(
+            m.strip_prefix(&link.join("registry/src/lib.rs")),
+            Some(PathBuf::from("registry/src/lib.rs"))
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_prefix() {
+        let mut m = PrefixMatcher::new(PathBuf::from(r"C:\Users\user\.cargo"));
+        assert_eq!(
+            m.strip_prefix(Path::new(r"c:\users\user\.cargo\registry\src\lib.rs")),
+            Some(PathBuf::from(r"registry\src\lib.rs"))
+        );
+        assert_eq!(
+            m.strip_prefix(Path::new(r"\\?\C:\Users\user\.cargo\registry\src\lib.rs")),
+            Some(PathBuf::from(r"registry\src\lib.rs"))
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{
+    describe_item, disk_usage, last_modified, paths::write_atomic, source::stable_hash, ItemInfo,
+    OutdatedReason,
+};
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs, mem,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const VERSION: u32 = 1;
+
+// Formats a time in UTC as RFC 3339, e.g. `2021-01-02T03:04:05Z`, optionally with nanoseconds. Times
+// before the unix epoch are formatted as the epoch.
+fn format_rfc3339(time: SystemTime, nanos: bool) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = time.as_secs();
+
+    // Days since the epoch to a date, from Howard Hinnant's `civil_from_days`. Eras are 400 years
+    // long and start on March 1st.
+    let days = secs / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = match month {
+        0..=9 => (era * 400 + year_of_era, month + 3),
+        _ => (era * 400 + year_of_era + 1, month - 9),
+    };
+
+    let mut s = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    );
+    if nanos {
+        s += &format!(".{:09}", time.subsec_nanos());
+    }
+    s + "Z"
+}
+
+/// Why an item is in a plan.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlanReason {
+    /// Not used by the project.
+    Unused,
+    /// An unpacked source whose `.crate` file is gone.
+    Orphaned,
+    /// A `.crate` file which doesn't match the checksum in `Cargo.lock`.
+    ChecksumMismatch,
+    /// A yanked version which isn't in `Cargo.lock`.
+    Yanked,
+    /// A unit in the target directory built from a package outside the global cargo cache, e.g.
+    /// a workspace member.
+    Local,
+    /// A unit in the target directory built with different features than its package now uses.
+    FeatureChange,
+    /// A unit in the target directory with a removed dependency.
+    DependencyChanged,
+    /// Anything directly in the profile directory of the target directory, e.g. `incremental`,
+    /// `examples` or the final binaries. These are never kept.
+    ProfileOutput,
+    /// A binary in `bin` which isn't in cargo's install records.
+    UntrackedBin,
+}
+impl From<OutdatedReason> for PlanReason {
+    fn from(reason: OutdatedReason) -> Self {
+        match reason {
+            OutdatedReason::Unused => Self::Unused,
+            OutdatedReason::Local => Self::Local,
+            OutdatedReason::FeatureChange => Self::FeatureChange,
+            OutdatedReason::DependencyChanged => Self::DependencyChanged,
+        }
+    }
+}
+
+/// Everything about where a plan was made which needs to be the same when it's applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlanEnvironment {
+    pub tool_version: String,
+    pub os: String,
+    pub arch: String,
+    pub cargo_home: PathBuf,
+    pub target_dir: Option<PathBuf>,
+}
+impl PlanEnvironment {
+    pub fn new(cargo_home: PathBuf, target_dir: Option<PathBuf>) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").into(),
+            os: std::env::consts::OS.into(),
+            arch: std::env::consts::ARCH.into(),
+            cargo_home,
+            target_dir,
+        }
+    }
+
+    /// A hash of the environment, so two environments can be compared at a glance.
+    pub fn digest(&self) -> String {
+        // Serializing a struct of strings can't fail.
+        stable_hash(serde_json::to_vec(self).unwrap_or_default())
+    }
+}
+
+/// A single item to delete.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlanItem {
+    pub path: PathBuf,
+    pub reason: PlanReason,
+    #[serde(flatten)]
+    pub info: ItemInfo,
+    pub bytes: u64,
+    pub files: u64,
+    /// The most recent modification time of anything in the item, in RFC 3339 format.
+    pub modified: String,
+}
+impl PlanItem {
+    /// Checks whether the item still exists and hasn't been modified since the plan was made.
+    /// Returns why the item should be skipped if it has changed.
+    pub fn changed(&self) -> Result<Option<&'static str>> {
+        let modified = last_modified(&self.path)
+            .with_context(|| format!("error reading {}", self.path.display()))?;
+        Ok(match modified {
+            None => Some("it no longer exists"),
+            Some(time) if format_rfc3339(time, true) != self.modified => {
+                Some("it was modified after the plan was made")
+            }
+            Some(_) => None,
+        })
+    }
+}
+
+/// A list of items to delete, written by a dry run so it can be reviewed before being applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    version: u32,
+    /// The mode the plan was made with.
+    pub mode: String,
+    pub created: String,
+    pub environment: PlanEnvironment,
+    /// The digest of the environment.
+    pub digest: String,
+    pub items: Vec<PlanItem>,
+}
+impl Plan {
+    pub fn new(mode: &str, environment: PlanEnvironment) -> Self {
+        Self {
+            version: VERSION,
+            mode: mode.into(),
+            created: format_rfc3339(SystemTime::now(), false),
+            digest: environment.digest(),
+            environment,
+            items: Vec::new(),
+        }
+    }
+
+    /// Adds an item to the plan, recording its current size and modification time.
+    pub fn add(&mut self, path: &Path, reason: PlanReason) -> Result<()> {
+        let usage =
+            disk_usage(path).with_context(|| format!("error measuring {}", path.display()))?;
+        let modified = last_modified(path)
+            .with_context(|| format!("error reading {}", path.display()))?
+            .ok_or_else(|| Error::msg(format!("{} no longer exists", path.display())))?;
+        self.items.push(PlanItem {
+            path: path.into(),
+            reason,
+            info: describe_item(&self.environment.cargo_home, path),
+            bytes: usage.bytes,
+            files: usage.files,
+            modified: format_rfc3339(modified, true),
+        });
+        Ok(())
+    }
+
+    /// Adds an item to the plan without measuring it, for when only the paths are needed. Its
+    /// size is zero and its modification time is empty, so `PlanItem::changed` always reports it
+    /// as modified.
+    pub fn add_unmeasured(&mut self, path: &Path, reason: PlanReason) {
+        self.items.push(PlanItem {
+            path: path.into(),
+            reason,
+            info: describe_item(&self.environment.cargo_home, path),
+            bytes: 0,
+            files: 0,
+            modified: String::new(),
+        });
+    }
+
+    /// Adds the items of another plan which aren't already in this one. An item found for more
+    /// than one reason keeps the first. The other plan's environment isn't checked.
+    pub fn merge(&mut self, other: Plan) {
+        let mut listed: HashSet<_> = self.items.iter().map(|item| item.path.clone()).collect();
+        self.items.extend(
+            other
+                .items
+                .into_iter()
+                .filter(|item| listed.insert(item.path.clone())),
+        );
+    }
+
+    /// Removes the items modified within `min_age` from the plan, since they may be in use by
+    /// another job, and returns them. A `min_age` reaching back further than the system clock can
+    /// represent removes every item.
+    pub fn take_recently_modified(&mut self, min_age: Duration) -> Vec<PlanItem> {
+        let cutoff = SystemTime::now().checked_sub(min_age);
+        let (kept, items) = mem::take(&mut self.items)
+            .into_iter()
+            .partition(|item| match cutoff {
+                Some(cutoff) => {
+                    matches!(last_modified(&item.path), Ok(Some(time)) if time > cutoff)
+                }
+                None => true,
+            });
+        self.items = items;
+        kept
+    }
+
+    /// Reads a plan, checking that it hasn't been edited in a way which would change where it
+    /// applies.
+    pub fn read(path: &Path) -> Result<Self> {
+        let data =
+            fs::read(path).with_context(|| format!("error reading plan {}", path.display()))?;
+        let value: serde_json::Value = serde_json::from_slice(&data)
+            .with_context(|| format!("error parsing plan {}", path.display()))?;
+        // The version is checked first so a newer plan gives a useful error.
+        match value.get("version").and_then(serde_json::Value::as_u64) {
+            Some(version) if version == u64::from(VERSION) => (),
+            Some(version) => {
+                return Err(Error::msg(format!(
+                    "unsupported plan version {} in {}",
+                    version,
+                    path.display()
+                )))
+            }
+            None => {
+                return Err(Error::msg(format!(
+                    "missing plan version in {}",
+                    path.display()
+                )))
+            }
+        }
+        let plan: Self = serde_json::from_value(value)
+            .with_context(|| format!("error parsing plan {}", path.display()))?;
+        if plan.digest != plan.environment.digest() {
+            return Err(Error::msg(format!(
+                "the environment in plan {} doesn't match its digest",
+                path.display()
+            )));
+        }
+        Ok(plan)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("error writing plan {}", path.display()))
+    }
+
+    /// Checks that every item is inside the cargo home or target directory the plan was made for.
+    /// Returns a list of problems found.
+    pub fn check_paths(&self) -> Vec<String> {
+        let roots: Vec<_> = Some(&self.environment.cargo_home)
+            .into_iter()
+            .chain(&self.environment.target_dir)
+            .collect();
+        self.items
+            .iter()
+            .filter(|item| {
+                item.path
+                    .components()
+                    .any(|c| matches!(c, Component::ParentDir))
+                    || !roots
+                        .iter()
+                        .any(|root| item.path.starts_with(root) && item.path != **root)
+            })
+            .map(|item| {
+                format!(
+                    "{} is outside of the cargo home and the target directory",
+                    item.path.display()
+                )
+            })
+            .collect()
+    }
+}
+
+/// Calls delete for every item in a plan, in order. Items inside a directory which was already
+/// deleted are skipped.
+///
+/// Items aren't checked for changes made since the plan was made; use `PlanItem::changed` to skip
+/// them first.
+pub fn execute(plan: &Plan, delete: &mut dyn FnMut(&PlanItem)) {
+    let mut deleted = HashSet::new();
+    for item in &plan.items {
+        if item
+            .path
+            .ancestors()
+            .skip(1)
+            .any(|dir| deleted.contains(dir))
+        {
+            continue;
+        }
+        delete(item);
+        deleted.insert(item.path.as_path());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{execute, format_rfc3339, Plan, PlanEnvironment, PlanItem, PlanReason};
+    use crate::test_util::scratch_dir;
+    use crate::usage::set_modified;
+    use std::{
+        fs,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn rfc3339() {
+        let time = |secs, nanos| UNIX_EPOCH + Duration::new(secs, nanos);
+        assert_eq!(format_rfc3339(time(0, 0), false), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            format_rfc3339(time(951_782_400, 5), true),
+            "2000-02-29T00:00:00.000000005Z"
+        );
+        assert_eq!(
+            format_rfc3339(time(1_709_251_199, 0), false),
+            "2024-02-29T23:59:59Z"
+        );
+        assert_eq!(
+            format_rfc3339(time(4_102_531_199, 123_456_789), true),
+            "2100-01-01T23:59:59.123456789Z"
+        );
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH - Duration::from_secs(1), false),
+            "1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn write_and_apply() {
+        let dir = scratch_dir("plan_test");
+        let home = dir.join("home");
+        let repo = home.join("git/db/repo-0123456789abcdef");
+        let krate = home.join("registry/cache/example.com-0123456789abcdef/foo-1.0.0.crate");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(krate.parent().unwrap()).unwrap();
+        fs::write(repo.join("HEAD"), "ref").unwrap();
+        fs::write(&krate, "crate").unwrap();
+
+        let mut plan = Plan::new("cargo-cache", PlanEnvironment::new(home.clone(), None));
+        plan.add(&repo, PlanReason::Unused).unwrap();
+        plan.add(&krate, PlanReason::Yanked).unwrap();
+        assert!(plan.add(&home.join("missing"), PlanReason::Unused).is_err());
+        assert_eq!((plan.items[0].files, plan.items[0].bytes), (1, 3));
+        let mut unmeasured = Plan::new("cargo-cache", PlanEnvironment::new(home.clone(), None));
+        unmeasured.add_unmeasured(&repo, PlanReason::Unused);
+        assert_eq!(
+            (unmeasured.items[0].files, unmeasured.items[0].bytes),
+            (0, 0)
+        );
+        assert!(unmeasured.items[0].changed().unwrap().is_some());
+        assert!(plan.check_paths().is_empty());
+
+        let path = dir.join("plan.json");
+        plan.write(&path).unwrap();
+        let read = Plan::read(&path).unwrap();
+        assert_eq!(read, plan);
+        assert_eq!(read.items[0].changed().unwrap(), None);
+
+        fs::remove_file(&krate).unwrap();
+        assert_eq!(
+            read.items[1].changed().unwrap(),
+            Some("it no longer exists")
+        );
+        let mut item = read.items[0].clone();
+        item.modified = "2000-01-01T00:00:00Z".into();
+        assert!(item.changed().unwrap().is_some());
+
+        // Edits moving the plan to another cargo home, and items outside the cargo home, are
+        // rejected.
+        let mut edited = plan.clone();
+        edited.environment.cargo_home = dir.clone();
+        edited.write(&path).unwrap();
+        assert!(Plan::read(&path).is_err());
+        let mut edited = plan.clone();
+        edited.items[0].path = dir.join("other");
+        edited.items[1].path = home.join("../other");
+        assert_eq!(edited.check_paths().len(), 2);
+
+        let json = serde_json::to_string(&plan)
+            .unwrap()
+            .replace("\"version\":1", "\"version\":2");
+        fs::write(&path, json).unwrap();
+        let e = Plan::read(&path).err().unwrap();
+        assert!(e.to_string().contains("unsupported plan version 2"));
+    }
+
+    #[test]
+    fn min_age() {
+        let dir = scratch_dir("plan_min_age_test");
+        let home = dir.join("home");
+        let registry = home.join("registry/cache/example.com-0123456789abcdef");
+        fs::create_dir_all(&registry).unwrap();
+        let old = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        let mut plan = Plan::new("cargo-cache", PlanEnvironment::new(home.clone(), None));
+        for &(name, fresh) in &[("fresh", true), ("old", false)] {
+            let krate = registry.join(format!("{}-1.0.0.crate", name));
+            let repo = home.join(format!("git/db/{}-0123456789abcdef", name));
+            fs::create_dir_all(&repo).unwrap();
+            fs::write(repo.join("HEAD"), "ref").unwrap();
+            fs::write(&krate, "crate").unwrap();
+            if !fresh {
+                for path in &[&krate, &repo.join("HEAD"), &repo] {
+                    set_modified(path, old).unwrap();
+                }
+            }
+            plan.add(&krate, PlanReason::Unused).unwrap();
+            plan.add(&repo, PlanReason::Unused).unwrap();
+        }
+
+        let mut unlimited = plan.clone();
+        let kept = plan.take_recently_modified(Duration::from_secs(60 * 60));
+        let names = |items: &[PlanItem]| {
+            items
+                .iter()
+                .map(|item| item.path.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&kept),
+            ["fresh-1.0.0.crate", "fresh-0123456789abcdef"]
+        );
+        assert_eq!(
+            names(&plan.items),
+            ["old-1.0.0.crate", "old-0123456789abcdef"]
+        );
+
+        // A duration the clock can't go back by keeps everything.
+        assert_eq!(unlimited.take_recently_modified(Duration::MAX).len(), 4);
+        assert!(unlimited.items.is_empty());
+    }
+
+    #[test]
+    fn merge_and_execute() {
+        let dir = scratch_dir("plan_execute_test");
+        let repo = dir.join("git/db/repo-0123456789abcdef");
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(repo.join("HEAD"), "ref").unwrap();
+        let krate = dir.join("foo-1.0.0.crate");
+        fs::write(&krate, "crate").unwrap();
+
+        let mut plan = Plan::new("cargo-cache", PlanEnvironment::new(dir.clone(), None));
+        plan.add(&repo, PlanReason::Unused).unwrap();
+        let mut other = Plan::new("cargo-cache", PlanEnvironment::new(dir.clone(), None));
+        other.add(&repo, PlanReason::Yanked).unwrap();
+        other.add(&repo.join("HEAD"), PlanReason::Unused).unwrap();
+        other.add(&krate, PlanReason::Yanked).unwrap();
+        plan.merge(other);
+        let reasons: Vec<_> = plan.items.iter().map(|item| item.reason).collect();
+        assert_eq!(
+            reasons,
+            [PlanReason::Unused, PlanReason::Unused, PlanReason::Yanked]
+        );
+
+        // The file inside the deleted repository is skipped.
+        let mut deleted = Vec::new();
+        execute(&plan, &mut |item| deleted.push(item.path.clone()));
+        assert_eq!(deleted, [repo, krate]);
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Makes an item writable so it, or its children on unix, can be removed. Returns whether anything
+// was changed. Symlinks are never changed since setting permissions follows the link.
+fn make_writable(path: &Path) -> io::Result<bool> {
+    let meta = path.symlink_metadata()?;
+    if meta.file_type().is_symlink() {
+        return Ok(false);
+    }
+    let mut perm = meta.permissions();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Directories also need to be readable and searchable to remove their children.
+        let needed = if meta.is_dir() { 0o700 } else { 0o200 };
+        if perm.mode() & needed == needed {
+            return Ok(false);
+        }
+        perm.set_mode(perm.mode() | needed);
+    }
+    #[cfg(not(unix))]
+    {
+        if !perm.readonly() {
+            return Ok(false);
+        }
+        perm.set_readonly(false);
+    }
+
+    fs::set_permissions(path, perm)?;
+    Ok(true)
+}
+
+// Runs the removal, making the item writable and retrying if it fails with `PermissionDenied`. On
+// unix removing an entry needs write access to its parent directory, while on windows the entry
+// itself can't be read-only.
+fn remove_with_retry(path: &Path, remove: fn(&Path) -> io::Result<()>) -> io::Result<()> {
+    match remove(path) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            let fix = if cfg!(unix) {
+                path.parent()
+            } else {
+                Some(path)
+            };
+            match fix {
+                Some(fix) if make_writable(fix)? => remove(path),
+                _ => Err(e),
+            }
+        }
+        result => result,
+    }
+}
+
+// Removes a symlink, or a junction on windows, without touching its target.
+fn remove_link(path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    // Links to directories, including junctions, have to be removed as directories on windows.
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileTypeExt;
+        if meta.file_type().is_symlink_dir() {
+            return remove_with_retry(path, |p| fs::remove_dir(p));
+        }
+    }
+    #[cfg(not(windows))]
+    let _ = meta;
+    remove_with_retry(path, |p| fs::remove_file(p))
+}
+
+// Removes as much of the tree as possible, collecting every failure. Returns whether the item was
+// removed.
+fn remove_tree_inner(path: &Path, failures: &mut Vec<(PathBuf, io::Error)>) -> bool {
+    let meta = match path.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return true,
+        Err(e) => {
+            failures.push((path.into(), e));
+            return false;
+        }
+    };
+    let result = if meta.file_type().is_symlink() {
+        remove_link(path, &meta)
+    } else if !meta.is_dir() {
+        remove_with_retry(path, |p| fs::remove_file(p))
+    } else {
+        let entries = match fs::read_dir(path) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => match make_writable(path) {
+                Ok(true) => fs::read_dir(path),
+                _ => Err(e),
+            },
+            entries => entries,
+        };
+        let mut removed = true;
+        match entries {
+            Ok(entries) => {
+                for e in entries {
+                    match e {
+                        Ok(e) => removed &= remove_tree_inner(&e.path(), failures),
+                        Err(e) => {
+                            failures.push((path.into(), e));
+                            removed = false;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                failures.push((path.into(), e));
+                removed = false;
+            }
+        }
+        // The directory can't be removed until all of its children are, and the children's
+        // failures have already been reported.
+        if !removed {
+            return false;
+        }
+        remove_with_retry(path, |p| fs::remove_dir(p))
+    };
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            failures.push((path.into(), e));
+            false
+        }
+    }
+}
+
+/// Removes a file or directory tree in place. Read-only items are made writable and retried as
+/// they're found, and symlinks are removed without being followed. Removal continues past failures
+/// so as much of the tree as possible is removed, and a single error summarizing every failure is
+/// returned.
+pub fn remove_tree(path: &Path) -> io::Result<()> {
+    let mut failures = Vec::new();
+    if remove_tree_inner(path, &mut failures) {
+        return Ok(());
+    }
+    let count = failures.len();
+    let (first, e) = failures.swap_remove(0);
+    let msg = if count == 1 {
+        format!("{}: {}", first.display(), e)
+    } else {
+        format!(
+            "{} items couldn't be removed, including {}: {}",
+            count,
+            first.display(),
+            e
+        )
+    };
+    Err(io::Error::new(e.kind(), msg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{remove_link, remove_tree};
+    use crate::test_util::scratch_dir;
+    use std::{
+        fs, io,
+        path::{Path, PathBuf},
+    };
+
+    #[cfg(unix)]
+    fn symlink(target: &Path, link: &Path, _dir: bool) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    #[cfg(windows)]
+    fn symlink(target: &Path, link: &Path, dir: bool) -> io::Result<()> {
+        if dir {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+
+    // Creates a link to a file, a directory and a missing file, and a junction on windows. Returns
+    // `None` if links can't be created.
+    fn create_links(dir: &Path, target_file: &Path, target_dir: &Path) -> Option<Vec<PathBuf>> {
+        let _ = remove_tree(dir);
+        fs::create_dir_all(dir).unwrap();
+        let links = [
+            (dir.join("file_link"), target_file, false),
+            (dir.join("dir_link"), target_dir, true),
+            (dir.join("broken_link"), &*dir.join("missing"), false),
+        ];
+        for (link, target, is_dir) in &links {
+            match symlink(target, link, *is_dir) {
+                Ok(()) => (),
+                // Creating symlinks on windows requires developer mode or admin rights.
+                Err(e) if cfg!(windows) && e.kind() == io::ErrorKind::PermissionDenied => {
+                    return None
+                }
+                Err(e) => panic!("error creating {}: {}", link.display(), e),
+            }
+        }
+        let links: Vec<_> = links.iter().map(|(link, ..)| link.clone()).collect();
+        #[cfg(windows)]
+        let links = {
+            let junction = dir.join("junction");
+            let status = std::process::Command::new("cmd")
+                .arg("/C")
+                .arg("mklink")
+                .arg("/J")
+                .arg(&junction)
+                .arg(target_dir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            let mut links = links;
+            links.push(junction);
+            links
+        };
+        Some(links)
+    }
+
+    #[test]
+    fn remove_links() {
+        let dir = scratch_dir("remove_links");
+        let target_dir = dir.join("target_dir");
+        let target_file = dir.join("target_file");
+        fs::create_dir_all(&target_dir).unwrap();
+        fs::write(target_dir.join("file"), "x").unwrap();
+        fs::write(&target_file, "x").unwrap();
+
+        let links = match create_links(&dir.join("links"), &target_file, &target_dir) {
+            Some(links) => links,
+            None => return,
+        };
+        for link in &links {
+            let meta = link.symlink_metadata().unwrap();
+            remove_link(link, &meta).unwrap();
+            assert!(
+                link.symlink_metadata().is_err(),
+                "{} wasn't removed",
+                link.display()
+            );
+        }
+
+        // Links are removed the same way by `remove_tree`, both directly and inside a tree.
+        let links = create_links(&dir.join("links"), &target_file, &target_dir).unwrap();
+        for link in &links {
+            remove_tree(link).unwrap();
+            assert!(
+                link.symlink_metadata().is_err(),
+                "{} wasn't removed",
+                link.display()
+            );
+        }
+        create_links(&dir.join("links"), &target_file, &target_dir).unwrap();
+        remove_tree(&dir.join("links")).unwrap();
+        assert!(dir.join("links").symlink_metadata().is_err());
+
+        assert!(target_file.is_file());
+        assert!(target_dir.join("file").is_file());
+    }
+
+    fn set_readonly(path: &std::path::Path) {
+        let mut perm = fs::metadata(path).unwrap().permissions();
+        perm.set_readonly(true);
+        fs::set_permissions(path, perm).unwrap();
+    }
+
+    #[test]
+    fn remove_readonly_tree() {
+        let dir = scratch_dir("remove_tree");
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("a/b")).unwrap();
+        fs::write(tree.join("a/b/file"), "x").unwrap();
+        fs::write(tree.join("a/file"), "x").unwrap();
+        let outside = dir.join("outside");
+        fs::write(&outside, "x").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, tree.join("link")).unwrap();
+
+        // Read-only files inside read-only directories, like a git checkout.
+        set_readonly(&tree.join("a/b/file"));
+        set_readonly(&tree.join("a/file"));
+        set_readonly(&tree.join("a/b"));
+        set_readonly(&tree.join("a"));
+
+        remove_tree(&tree).unwrap();
+        assert!(tree.symlink_metadata().is_err());
+        assert!(outside.is_file());
+        assert!(!fs::metadata(&outside).unwrap().permissions().readonly());
+        remove_tree(&tree).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{crate_file_package, meta::Metadata, src_dir_package, usage::disk_usage, DiskUsage};
+use anyhow::{Context, Result};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The directories in the cargo home which are measured, relative to the cargo home.
+pub const REPORT_COMPONENTS: [&str; 5] = [
+    "registry/cache",
+    "registry/src",
+    "registry/index",
+    "git/db",
+    "git/checkouts",
+];
+
+/// A single crate, unpacked source, repository or checkout in the cargo home.
+pub struct ReportEntry {
+    pub path: PathBuf,
+    pub usage: DiskUsage,
+    /// Whether the entry is referenced by the metadata.
+    pub used: bool,
+}
+
+/// The disk usage of a cargo home.
+pub struct CargoHomeReport {
+    /// The usage of each of `REPORT_COMPONENTS`, in the same order.
+    pub components: Vec<DiskUsage>,
+    /// The combined usage of each registry's cache, sources and index, by directory name.
+    pub registries: BTreeMap<OsString, DiskUsage>,
+    /// The largest entries, largest first.
+    pub largest: Vec<ReportEntry>,
+}
+
+fn read_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    match fs::read_dir(path) {
+        Ok(iter) => Ok(iter.filter_map(|e| e.ok()).map(|e| e.path()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("error reading dir: {}", path.display())),
+    }
+}
+
+/// Measures everything in the cargo home's registry and git directories, noting which entries are
+/// referenced by the given metadata. Only the `top` largest entries are kept. Nothing is modified.
+pub fn cargo_home_report(
+    cargo_home: &Path,
+    meta: &Metadata,
+    top: usize,
+) -> Result<CargoHomeReport> {
+    let file_name = |path: &Path| path.file_name().unwrap_or_default().to_owned();
+    let mut components = Vec::new();
+    let mut registries = BTreeMap::<OsString, DiskUsage>::new();
+    let mut entries = Vec::new();
+
+    for component in &REPORT_COMPONENTS {
+        let dir = cargo_home.join(component);
+        let mut total = DiskUsage::default();
+        for path in read_dir(&dir)? {
+            let name = file_name(&path);
+            let mut push_entry = |path: PathBuf, used: bool| -> Result<DiskUsage> {
+                let usage = disk_usage(&path)
+                    .with_context(|| format!("error measuring {}", path.display()))?;
+                entries.push(ReportEntry { path, usage, used });
+                Ok(usage)
+            };
+            let usage = match *component {
+                "registry/cache" | "registry/src" => {
+                    let package: fn(&Path) -> Option<&OsStr> = if *component == "registry/cache" {
+                        crate_file_package
+                    } else {
+                        src_dir_package
+                    };
+                    let packages = meta.packages.registry.get(&name);
+                    let mut usage = DiskUsage::default();
+                    for path in read_dir(&path)? {
+                        let used = match (package(&path), packages) {
+                            (Some(package), Some(packages)) => packages.contains_key(package),
+                            _ => false,
+                        };
+                        usage += push_entry(path, used)?;
+                    }
+                    usage
+                }
+                "git/db" => {
+                    let used = meta.packages.git.contains_key(&name);
+                    push_entry(path, used)?
+                }
+                "git/checkouts" => {
+                    let mut usage = DiskUsage::default();
+                    for path in read_dir(&path)? {
+                        let used = meta.packages.uses_checkout(&name, &file_name(&path));
+                        usage += push_entry(path, used)?;
+                    }
+                    usage
+                }
+                _ => disk_usage(&path)
+                    .with_context(|| format!("error measuring {}", path.display()))?,
+            };
+            if component.starts_with("registry") {
+                *registries.entry(name).or_default() += usage;
+            }
+            total += usage;
+        }
+        components.push(total);
+    }
+
+    entries.sort_by_key(|e| Reverse(e.usage.bytes));
+    entries.truncate(top);
+    Ok(CargoHomeReport {
+        components,
+        registries,
+        largest: entries,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::cargo_home_report;
+    use crate::meta::Metadata;
+    use crate::test_util::scratch_dir;
+    use std::{ffi::OsStr, fs};
+
+    #[test]
+    fn measure_home() {
+        let home = scratch_dir("report_test");
+        let registry = "example.com-0123456789abcdef";
+        fs::create_dir_all(home.join("registry/cache").join(registry)).unwrap();
+        fs::create_dir_all(home.join("registry/index").join(registry)).unwrap();
+        fs::create_dir_all(home.join("git/checkouts/repo-0123456789abcdef/0123456")).unwrap();
+        let cache = home.join("registry/cache").join(registry);
+        fs::write(cache.join("foo-0.1.0.crate"), [0; 10]).unwrap();
+        fs::write(cache.join("bar-0.1.0.crate"), [0; 20]).unwrap();
+        fs::write(
+            home.join("registry/index")
+                .join(registry)
+                .join("config.json"),
+            [0; 5],
+        )
+        .unwrap();
+        fs::write(
+            home.join("git/checkouts/repo-0123456789abcdef/0123456/lib.rs"),
+            [0; 7],
+        )
+        .unwrap();
+
+        let mut meta = Metadata::default();
+        meta.packages
+            .registry
+            .entry(registry.into())
+            .or_default()
+            .insert("foo-0.1.0".into(), "foo".into());
+        meta.packages
+            .git
+            .entry("repo-0123456789abcdef".into())
+            .or_default()
+            .insert("0123456789abcdef".into(), "repo".into());
+
+        let report = cargo_home_report(&home, &meta, 2).unwrap();
+        let bytes: Vec<_> = report.components.iter().map(|u| u.bytes).collect();
+        assert_eq!(bytes, [30, 0, 5, 0, 7]);
+        assert_eq!(report.registries[OsStr::new(registry)].bytes, 35);
+        let largest: Vec<_> = report
+            .largest
+            .iter()
+            .map(|e| (e.path.file_name().unwrap().to_owned(), e.used))
+            .collect();
+        assert_eq!(
+            largest,
+            [
+                ("bar-0.1.0.crate".into(), false),
+                ("foo-0.1.0.crate".into(), true)
+            ]
+        );
+
+        let report = cargo_home_report(&home, &meta, 3).unwrap();
+        assert!(report.largest[2].used);
+    }
+}
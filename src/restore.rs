@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// The name of the file in each run's temp directory listing where each directory was moved from.
+pub const MOVES_FILE_NAME: &str = "moves.jsonl";
+
+#[derive(Serialize, Deserialize)]
+struct MoveRecord {
+    /// Where the directory was moved from.
+    original: PathBuf,
+    /// The name of the directory in the run's temp directory.
+    name: String,
+}
+
+/// A record of the directories moved into a run's temp directory, so they can be restored.
+pub struct MoveLog {
+    file: File,
+    path: PathBuf,
+}
+impl MoveLog {
+    /// Creates the record in the given run's temp directory.
+    pub fn create(run_dir: &Path) -> Result<Self> {
+        let path = run_dir.join(MOVES_FILE_NAME);
+        let file = File::create(&path)
+            .with_context(|| format!("error creating move record {}", path.display()))?;
+        Ok(Self { file, path })
+    }
+
+    /// Records that a directory was moved to the given name. Each record is flushed immediately so
+    /// everything moved before a crash can still be restored.
+    pub fn record(&mut self, original: &Path, name: &str) -> Result<()> {
+        let record = MoveRecord {
+            original: original.into(),
+            name: name.into(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|_| self.file.flush())
+            .with_context(|| format!("error writing move record {}", self.path.display()))
+    }
+}
+
+/// What happened to each directory when restoring a run.
+#[derive(Default, Debug)]
+pub struct RestoreReport {
+    pub restored: Vec<PathBuf>,
+    /// Directories which weren't restored because something now exists at their original path.
+    pub conflicts: Vec<PathBuf>,
+    /// Directories which are no longer in the temp directory.
+    pub missing: Vec<PathBuf>,
+    pub errors: Vec<(PathBuf, io::Error)>,
+}
+
+/// Moves every directory recorded in a run's temp directory back to where it was. Files aren't
+/// moved when cleaning, so they can't be restored. With `dry_run` nothing is moved, but the report
+/// still lists what would be restored.
+pub fn restore(run_dir: &Path, dry_run: bool) -> Result<RestoreReport> {
+    let path = run_dir.join(MOVES_FILE_NAME);
+    let file = File::open(&path)
+        .with_context(|| format!("error reading move record {}", path.display()))?;
+    let lines = BufReader::new(file)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("error reading move record {}", path.display()))?;
+    let mut report = RestoreReport::default();
+    for (i, line) in lines.iter().enumerate() {
+        let record: MoveRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            // A run interrupted while writing a record can leave a partial last line.
+            Err(_) if i + 1 == lines.len() => break,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("error parsing move record {}", path.display()))
+            }
+        };
+        let moved = run_dir.join(&record.name);
+        if record.original.symlink_metadata().is_ok() {
+            report.conflicts.push(record.original);
+            continue;
+        }
+        if moved.symlink_metadata().is_err() {
+            report.missing.push(record.original);
+            continue;
+        }
+        if dry_run {
+            report.restored.push(record.original);
+            continue;
+        }
+        let result = match record.original.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|_| fs::rename(&moved, &record.original));
+        match result {
+            Ok(()) => report.restored.push(record.original),
+            Err(e) => report.errors.push((record.original, e)),
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{restore, MoveLog};
+    use cargo_ci_precache::test_util::scratch_dir;
+    use std::fs;
+
+    #[test]
+    fn restore_moved() {
+        let dir = scratch_dir("restore_test");
+        let run_dir = dir.join("temp/1");
+        fs::create_dir_all(&run_dir).unwrap();
+        let originals: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| dir.join("home/git/checkouts").join(name))
+            .collect();
+
+        let mut log = MoveLog::create(&run_dir).unwrap();
+        for (i, original) in originals.iter().enumerate() {
+            fs::create_dir_all(run_dir.join(i.to_string())).unwrap();
+            fs::write(run_dir.join(i.to_string()).join("file"), "x").unwrap();
+            log.record(original, &i.to_string()).unwrap();
+        }
+        // `b` has been recreated since the run, and `c` is gone from the temp directory.
+        fs::create_dir_all(&originals[1]).unwrap();
+        fs::remove_dir_all(run_dir.join("2")).unwrap();
+
+        let report = restore(&run_dir, true).unwrap();
+        assert_eq!(report.restored, &originals[..1]);
+        assert!(!originals[0].exists());
+
+        let report = restore(&run_dir, false).unwrap();
+        assert_eq!(report.restored, &originals[..1]);
+        assert_eq!(report.conflicts, &originals[1..2]);
+        assert_eq!(report.missing, &originals[2..]);
+        assert!(report.errors.is_empty());
+        assert!(originals[0].join("file").is_file());
+        assert!(!run_dir.join("0").exists());
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+// Canonicalizes the longest existing ancestor of the path and appends the remaining components.
+fn canonicalize_lossy(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(p) = existing.canonicalize() {
+            return rest.iter().rev().fold(p, |p, c| p.join(c));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return path.into(),
+        }
+    }
+}
+
+fn is_root(path: &Path) -> bool {
+    path.parent().is_none()
+}
+
+fn check_temp(temp: Option<&Path>, dirs: &[&Path], problems: &mut Vec<String>) {
+    if let Some(temp) = temp {
+        let temp = canonicalize_lossy(temp);
+        for dir in dirs {
+            if temp.starts_with(canonicalize_lossy(dir)) {
+                problems.push(format!(
+                    "temp directory {} is inside {}, which is being cleaned",
+                    temp.display(),
+                    dir.display()
+                ));
+            }
+        }
+    }
+}
+
+/// Checks that cleaning the target directory won't delete anything outside of it. Returns a list
+/// of problems found.
+pub fn check_target_safety(
+    target_dir: &Path,
+    cargo_home: &Path,
+    temp: Option<&Path>,
+) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let target = canonicalize_lossy(target_dir);
+    let cargo_home = canonicalize_lossy(cargo_home);
+
+    if is_root(&target) {
+        problems.push(format!(
+            "target directory {} is the filesystem root",
+            target.display()
+        ));
+    }
+    if let Some(home) = home::home_dir() {
+        if target == canonicalize_lossy(&home) {
+            problems.push(format!(
+                "target directory {} is the home directory",
+                target.display()
+            ));
+        }
+    }
+    if cargo_home.starts_with(&target) {
+        problems.push(format!(
+            "target directory {} contains the cargo home {}",
+            target.display(),
+            cargo_home.display()
+        ));
+    }
+    check_temp(temp, &[&target_dir.join("debug")], &mut problems);
+
+    Ok(problems)
+}
+
+/// Checks that cleaning the cargo cache won't delete anything outside of the cargo home. Returns
+/// a list of problems found.
+pub fn check_cargo_cache_safety(cargo_home: &Path, temp: Option<&Path>) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let canonical_home = canonicalize_lossy(cargo_home);
+
+    if is_root(&canonical_home) {
+        problems.push(format!(
+            "cargo home {} is the filesystem root",
+            canonical_home.display()
+        ));
+    }
+
+    let dirs = [
+        cargo_home.join("git").join("db"),
+        cargo_home.join("git").join("checkouts"),
+        cargo_home.join("registry").join("cache"),
+        cargo_home.join("registry").join("src"),
+    ];
+    for dir in &dirs {
+        let canonical = canonicalize_lossy(dir);
+        if !canonical.starts_with(&canonical_home) {
+            problems.push(format!(
+                "{} resolves to {}, which is outside of the cargo home",
+                dir.display(),
+                canonical.display()
+            ));
+        }
+    }
+    check_temp(
+        temp,
+        &dirs.iter().map(PathBuf::as_path).collect::<Vec<_>>(),
+        &mut problems,
+    );
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod test {
+    use super::canonicalize_lossy;
+    use std::path::PathBuf;
+
+    #[test]
+    fn canonicalize_missing() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(
+            canonicalize_lossy(&dir.join("src").join("..").join("missing").join("dir")),
+            dir.canonicalize().unwrap().join("missing").join("dir")
+        );
+    }
+}
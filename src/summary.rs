@@ -0,0 +1,491 @@
+use anyhow::{Context, Error, Result};
+use cargo_ci_precache::{
+    format_size, write_atomic, DiskUsage, Effectiveness, ItemAction, ItemKind, ItemRecord, Totals,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const FORMAT: u32 = 1;
+
+/// The items deleted, or which would be deleted with a dry run, and the items kept despite being
+/// unused.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CategoryTotals {
+    pub deleted: Totals,
+    pub kept: Totals,
+    /// Items which couldn't be deleted because of a permission error, e.g. files owned by another
+    /// user. These aren't included in `deleted`.
+    #[serde(default)]
+    pub not_permitted: Totals,
+}
+
+/// The time taken by each phase of a run in seconds. Phases which weren't reached are `None`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Timings {
+    pub metadata: Option<f64>,
+    pub plan: Option<f64>,
+    pub delete: Option<f64>,
+    pub total: f64,
+}
+
+/// The change in the size of a directory between two runs.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UsageDelta {
+    pub files: i64,
+    pub bytes: i64,
+}
+
+/// The differences between a run and a previous run.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    /// The change in size of each directory which was kept. Directories in only one of the runs
+    /// are compared against an empty directory.
+    pub retained: BTreeMap<String, UsageDelta>,
+    /// Packages referenced by this run, but not the previous run.
+    pub added_packages: Vec<String>,
+    /// Packages referenced by the previous run, but not this run.
+    pub removed_packages: Vec<String>,
+}
+
+/// A description of a single run, written by `--summary-json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunSummary {
+    pub version: String,
+    /// The version of the summary's format. Only summaries with the same format are compared.
+    #[serde(default)]
+    pub format: u32,
+    /// Whether the run finished without any errors.
+    pub ok: bool,
+    pub mode: String,
+    /// The command line arguments, excluding the program name.
+    pub args: Vec<String>,
+    pub dry_run: bool,
+    pub cargo_home: Option<PathBuf>,
+    pub target_dir: Option<PathBuf>,
+    pub categories: BTreeMap<ItemKind, CategoryTotals>,
+    /// The size of each directory cleaned, relative to the cargo home or target directory, after
+    /// cleaning. Nothing is deleted by a dry run, so this is the size before cleaning.
+    #[serde(default)]
+    pub retained: BTreeMap<String, DiskUsage>,
+    /// `{name} {version}` for every package in the global cargo cache referenced by the run.
+    #[serde(default)]
+    pub packages: BTreeSet<String>,
+    pub errors: Vec<String>,
+    /// Items which couldn't be deleted because of a permission error. These aren't errors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_permitted: Vec<PathBuf>,
+    pub timings: Timings,
+    /// The comparison with a previous summary, when requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comparison: Option<Comparison>,
+    /// How much of the restored cache was used, with `--stats-effectiveness`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effectiveness: Option<Effectiveness>,
+    /// The log file written by the run, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+    /// The number of lockfiles the kept packages were read from, with `--lockfiles`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockfiles: Option<usize>,
+}
+impl RunSummary {
+    pub fn new(mode: &str, args: Vec<String>, dry_run: bool) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").into(),
+            format: FORMAT,
+            ok: true,
+            mode: mode.into(),
+            args,
+            dry_run,
+            cargo_home: None,
+            target_dir: None,
+            categories: BTreeMap::new(),
+            retained: BTreeMap::new(),
+            packages: BTreeSet::new(),
+            errors: Vec::new(),
+            not_permitted: Vec::new(),
+            timings: Timings::default(),
+            comparison: None,
+            effectiveness: None,
+            log_file: None,
+            lockfiles: None,
+        }
+    }
+
+    /// Reads a summary written by a previous run.
+    pub fn read(path: &Path) -> Result<Self> {
+        let data =
+            fs::read(path).with_context(|| format!("error reading summary {}", path.display()))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("error parsing summary {}", path.display()))
+    }
+
+    /// Compares this run with a previous run. Fails if the previous run used a different mode or
+    /// summary format.
+    pub fn compare(&self, previous: &Self) -> Result<Comparison> {
+        if previous.mode != self.mode {
+            return Err(Error::msg(format!(
+                "the previous run used mode {}, not {}",
+                previous.mode, self.mode
+            )));
+        }
+        if previous.format != self.format {
+            return Err(Error::msg(format!(
+                "the previous summary has format version {}, expected {}",
+                previous.format, self.format
+            )));
+        }
+        let dirs: BTreeSet<_> = self
+            .retained
+            .keys()
+            .chain(previous.retained.keys())
+            .collect();
+        let retained = dirs
+            .into_iter()
+            .map(|dir| {
+                let current = self.retained.get(dir).copied().unwrap_or_default();
+                let previous = previous.retained.get(dir).copied().unwrap_or_default();
+                let delta = UsageDelta {
+                    files: current.files as i64 - previous.files as i64,
+                    bytes: current.bytes as i64 - previous.bytes as i64,
+                };
+                (dir.clone(), delta)
+            })
+            .collect();
+        Ok(Comparison {
+            retained,
+            added_packages: self
+                .packages
+                .difference(&previous.packages)
+                .cloned()
+                .collect(),
+            removed_packages: previous
+                .packages
+                .difference(&self.packages)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Adds each item to the totals for its category.
+    pub fn add_records(&mut self, records: &[ItemRecord]) {
+        for record in records {
+            let totals = self.categories.entry(record.info.kind).or_default();
+            let totals = match record.action {
+                ItemAction::Delete | ItemAction::WouldDelete => &mut totals.deleted,
+                ItemAction::Kept => &mut totals.kept,
+            };
+            *totals += Totals {
+                count: 1,
+                bytes: record.bytes,
+            };
+        }
+    }
+
+    /// Moves an item which couldn't be deleted because of a permission error out of the deleted
+    /// totals.
+    pub fn add_not_permitted(&mut self, path: &Path, kind: ItemKind, bytes: u64) {
+        let totals = self.categories.entry(kind).or_default();
+        totals.deleted.count = totals.deleted.count.saturating_sub(1);
+        totals.deleted.bytes = totals.deleted.bytes.saturating_sub(bytes);
+        totals.not_permitted += Totals { count: 1, bytes };
+        self.not_permitted.push(path.into());
+    }
+
+    /// Renders the summary as markdown, e.g. for a GitHub Actions step summary.
+    pub fn to_markdown(&self) -> String {
+        let mut s = String::new();
+        let status = if self.ok { "ok" } else { "failed" };
+        let dry_run = if self.dry_run { ", dry run" } else { "" };
+        let _ = writeln!(s, "### cargo-ci-precache {}\n", self.mode);
+        let _ = writeln!(s, "Status: {}{}\n", status, dry_run);
+        if let Some(count) = self.lockfiles {
+            let _ = writeln!(s, "Lockfiles used: {}\n", count);
+        }
+
+        if !self.categories.is_empty() {
+            let deleted = if self.dry_run {
+                "Would delete"
+            } else {
+                "Deleted"
+            };
+            let _ = writeln!(s, "| Kind | {} | Size | Kept | Size |", deleted);
+            let _ = writeln!(s, "| --- | ---: | ---: | ---: | ---: |");
+            for (kind, totals) in &self.categories {
+                let _ = writeln!(
+                    s,
+                    "| {} | {} | {} | {} | {} |",
+                    serde_json::to_value(kind)
+                        .ok()
+                        .and_then(|v| v.as_str().map(String::from))
+                        .unwrap_or_default(),
+                    totals.deleted.count,
+                    format_size(totals.deleted.bytes),
+                    totals.kept.count,
+                    format_size(totals.kept.bytes),
+                );
+            }
+            s.push('\n');
+        }
+
+        if let Some(comparison) = &self.comparison {
+            let _ = writeln!(s, "| Directory | Size change | File change |");
+            let _ = writeln!(s, "| --- | ---: | ---: |");
+            for (dir, delta) in &comparison.retained {
+                let sign = if delta.bytes < 0 { '-' } else { '+' };
+                let _ = writeln!(
+                    s,
+                    "| {} | {}{} | {:+} |",
+                    dir,
+                    sign,
+                    format_size(delta.bytes.unsigned_abs()),
+                    delta.files
+                );
+            }
+            let _ = writeln!(
+                s,
+                "\n{} new packages, {} packages no longer referenced\n",
+                comparison.added_packages.len(),
+                comparison.removed_packages.len()
+            );
+        }
+
+        if let Some(stats) = &self.effectiveness {
+            let _ = writeln!(s, "Cache effectiveness: {}\n", stats.describe());
+        }
+
+        if !self.not_permitted.is_empty() {
+            let bytes = self
+                .categories
+                .values()
+                .map(|t| t.not_permitted.bytes)
+                .sum();
+            let _ = writeln!(
+                s,
+                "Skipped, not owned or permitted: {} items, {}\n",
+                self.not_permitted.len(),
+                format_size(bytes)
+            );
+        }
+
+        if !self.errors.is_empty() {
+            let _ = writeln!(s, "**Errors**\n");
+            for e in &self.errors {
+                // Each error has to stay on a single line to remain part of the list.
+                let _ = writeln!(s, "- {}", e.lines().collect::<Vec<_>>().join(" "));
+            }
+            s.push('\n');
+        }
+
+        if let Some(path) = &self.log_file {
+            let _ = writeln!(s, "Log: `{}`\n", path.display());
+        }
+        s
+    }
+
+    /// Writes the summary as JSON. A temporary file is written first so the summary is either
+    /// complete or missing.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("error writing summary {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CategoryTotals, Comparison, RunSummary, UsageDelta};
+    use cargo_ci_precache::{
+        DiskUsage, Effectiveness, ItemAction, ItemInfo, ItemKind, ItemRecord, Totals,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn category_totals() {
+        let record = |kind, action, bytes| ItemRecord {
+            path: String::new(),
+            action,
+            info: ItemInfo {
+                kind,
+                name: None,
+                hash: None,
+            },
+            bytes,
+        };
+        let mut summary = RunSummary::new("cargo-cache", Vec::new(), false);
+        summary.add_records(&[
+            record(ItemKind::RegistryCrate, ItemAction::Delete, 10),
+            record(ItemKind::RegistryCrate, ItemAction::Delete, 20),
+            record(ItemKind::RegistryCrate, ItemAction::Kept, 5),
+            record(ItemKind::GitDb, ItemAction::WouldDelete, 7),
+        ]);
+        let crates = summary.categories[&ItemKind::RegistryCrate];
+        assert_eq!(
+            crates.deleted,
+            Totals {
+                count: 2,
+                bytes: 30
+            }
+        );
+        assert_eq!(crates.kept, Totals { count: 1, bytes: 5 });
+        assert_eq!(
+            summary.categories[&ItemKind::GitDb].deleted,
+            Totals { count: 1, bytes: 7 }
+        );
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["categories"]["registry-crate"]["deleted"]["bytes"], 30);
+        assert_eq!(json["ok"], true);
+        assert_eq!(json["timings"]["plan"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn compare_runs() {
+        let usage = |files, bytes| DiskUsage { files, bytes };
+        let mut previous = RunSummary::new("cargo-cache", Vec::new(), false);
+        previous
+            .retained
+            .insert("registry/cache".into(), usage(10, 1000));
+        previous.retained.insert("git/db".into(), usage(1, 50));
+        previous.packages.insert("foo 0.1.0".into());
+        previous.packages.insert("bar 0.1.0".into());
+
+        let mut current = RunSummary::new("cargo-cache", Vec::new(), false);
+        current
+            .retained
+            .insert("registry/cache".into(), usage(12, 900));
+        current.packages.insert("foo 0.1.0".into());
+        current.packages.insert("foo 0.2.0".into());
+
+        let comparison = current.compare(&previous).unwrap();
+        assert_eq!(
+            comparison.retained["registry/cache"],
+            UsageDelta {
+                files: 2,
+                bytes: -100
+            }
+        );
+        assert_eq!(
+            comparison.retained["git/db"],
+            UsageDelta {
+                files: -1,
+                bytes: -50
+            }
+        );
+        assert_eq!(comparison.added_packages, ["foo 0.2.0"]);
+        assert_eq!(comparison.removed_packages, ["bar 0.1.0"]);
+
+        // Runs with a different mode or format aren't compared.
+        let target = RunSummary::new("target", Vec::new(), false);
+        assert!(target.compare(&previous).is_err());
+        let old = RunSummary {
+            format: 0,
+            ..previous.clone()
+        };
+        assert!(current.compare(&old).is_err());
+
+        // Summaries must round trip so they can be compared by later runs.
+        current.comparison = Some(comparison);
+        let json = serde_json::to_string(&current).unwrap();
+        assert_eq!(serde_json::from_str::<RunSummary>(&json).unwrap(), current);
+    }
+
+    #[test]
+    fn markdown() {
+        let mut summary = RunSummary::new("target", Vec::new(), true);
+        summary.categories.insert(
+            ItemKind::DepArtifact,
+            CategoryTotals {
+                deleted: Totals {
+                    count: 3,
+                    bytes: 2048,
+                },
+                kept: Totals::default(),
+                not_permitted: Totals::default(),
+            },
+        );
+        summary
+            .errors
+            .push("error removing a\npermission denied".into());
+        summary.ok = false;
+        assert_eq!(
+            summary.to_markdown(),
+            "### cargo-ci-precache target\n\
+             \n\
+             Status: failed, dry run\n\
+             \n\
+             | Kind | Would delete | Size | Kept | Size |\n\
+             | --- | ---: | ---: | ---: | ---: |\n\
+             | dep-artifact | 3 | 2.0 KiB | 0 | 0 B |\n\
+             \n\
+             **Errors**\n\
+             \n\
+             - error removing a permission denied\n\
+             \n"
+        );
+
+        let mut summary = RunSummary::new("cargo-cache", Vec::new(), false);
+        let mut comparison = Comparison::default();
+        comparison.retained.insert(
+            "git/db".into(),
+            UsageDelta {
+                files: -2,
+                bytes: -1024,
+            },
+        );
+        comparison.added_packages.push("foo 0.1.0".into());
+        summary.comparison = Some(comparison);
+        summary.log_file = Some("run.log".into());
+        summary.lockfiles = Some(2);
+        summary.effectiveness = Some(Effectiveness {
+            hits: 3,
+            misses: 1,
+            restored: Totals {
+                count: 4,
+                bytes: 4096,
+            },
+            dead: Totals {
+                count: 1,
+                bytes: 1024,
+            },
+        });
+        summary.add_not_permitted(Path::new("/home/.cargo/git/db/a"), ItemKind::GitDb, 1024);
+        assert_eq!(
+            summary.categories[&ItemKind::GitDb].not_permitted,
+            Totals {
+                count: 1,
+                bytes: 1024
+            }
+        );
+        assert_eq!(
+            summary.to_markdown(),
+            "### cargo-ci-precache cargo-cache\n\
+             \n\
+             Status: ok\n\
+             \n\
+             Lockfiles used: 2\n\
+             \n\
+             | Kind | Deleted | Size | Kept | Size |\n\
+             | --- | ---: | ---: | ---: | ---: |\n\
+             | git-db | 0 | 0 B | 0 | 0 B |\n\
+             \n\
+             | Directory | Size change | File change |\n\
+             | --- | ---: | ---: |\n\
+             | git/db | -1.0 KiB | -2 |\n\
+             \n\
+             1 new packages, 0 packages no longer referenced\n\
+             \n\
+             Cache effectiveness: 3 hits, 1 misses (75% hit ratio), 1.0 KiB of 4.0 KiB restored \
+             is unused (25%)\n\
+             \n\
+             Skipped, not owned or permitted: 1 items, 1.0 KiB\n\
+             \n\
+             Log: `run.log`\n\
+             \n"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Helpers for checking what the tool removes from real projects, available with the `test-util`
+//! feature.
+//!
+//! A `FixtureProject` is a small crate built with cargo. Build it, change its manifest, build it
+//! again, then check which crates `clear_target` removes from its target directory:
+//!
+//! ```no_run
+//! use cargo_ci_precache::test_util::FixtureProject;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut project = FixtureProject::new("fixture", "target/fixtures/fixture");
+//! project.dependency("cfg-if", r#""=0.1.9""#).create()?;
+//! project.build()?;
+//! project.assert_removals(&[]);
+//!
+//! project.dependency("cfg-if", r#""=0.1.10""#).write_manifest()?;
+//! project.build()?;
+//! project.assert_removals(&[("cfg_if", 1)]);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Metadata hashes change with each version of rustc, so removals are checked by the number of
+//! distinct hashes removed for each crate rather than by the hashes themselves. A crate with a
+//! build script has more than one, e.g. one for the build script and one for the crate.
+use crate::{artifact::parse_artifact_stem, clear_target, remove_tree, Metadata, MetadataCommand};
+use anyhow::{Context, Error, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A crate with an empty `lib.rs` which is built with cargo. Incremental compilation is disabled
+/// so only the units cleaned by `clear_target` are in its target directory.
+pub struct FixtureProject {
+    name: String,
+    dir: PathBuf,
+    manifest: String,
+    /// name -> inline TOML table or version requirement.
+    dependencies: BTreeMap<String, String>,
+}
+impl FixtureProject {
+    /// A project for the package `name` in `dir`, with no dependencies. Nothing is written until
+    /// `create` is called.
+    pub fn new(name: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+        let name = name.into();
+        Self {
+            manifest: format!(
+                "[package]\nname = \"{}\"\nversion = \"0.0.0\"\nedition = \"2018\"\n",
+                name
+            ),
+            name,
+            dir: dir.into(),
+            dependencies: BTreeMap::new(),
+        }
+    }
+
+    /// The directory the project is written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Replaces the manifest. The package must be named the same as the project. Dependencies
+    /// added with `dependency` are added to it.
+    pub fn manifest(&mut self, manifest: impl Into<String>) -> &mut Self {
+        self.manifest = manifest.into();
+        self
+    }
+
+    /// Adds a dependency, or replaces one with the same name. The spec is written as TOML, e.g.
+    /// `"=1.0.0"` including the quotes, or `{ version = "1", features = ["std"] }`.
+    pub fn dependency(&mut self, name: impl Into<String>, spec: impl Into<String>) -> &mut Self {
+        self.dependencies.insert(name.into(), spec.into());
+        self
+    }
+
+    /// Removes a dependency added with `dependency`.
+    pub fn remove_dependency(&mut self, name: &str) -> &mut Self {
+        self.dependencies.remove(name);
+        self
+    }
+
+    /// Writes the project, removing anything already in its directory.
+    pub fn create(&self) -> Result<()> {
+        if self.dir.exists() {
+            remove_tree(&self.dir)
+                .with_context(|| format!("error removing {}", self.dir.display()))?;
+        }
+        let src_dir = self.dir.join("src");
+        let config_dir = self.dir.join(".cargo");
+        fs::create_dir_all(&src_dir)
+            .and_then(|_| fs::write(src_dir.join("lib.rs"), ""))
+            .and_then(|_| fs::create_dir_all(&config_dir))
+            .and_then(|_| fs::write(config_dir.join("config"), "[build]\nincremental = false\n"))
+            .with_context(|| format!("error creating project {}", self.dir.display()))?;
+        self.write_manifest()
+    }
+
+    /// Writes the manifest along with its dependencies, e.g. after they've been changed.
+    pub fn write_manifest(&self) -> Result<()> {
+        let mut manifest: toml::Value = toml::from_str(&self.manifest)
+            .with_context(|| format!("error parsing the manifest of {}", self.name))?;
+        if !self.dependencies.is_empty() {
+            let table = manifest
+                .as_table_mut()
+                .ok_or_else(|| Error::msg("the manifest isn't a table"))?
+                .entry("dependencies")
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| Error::msg("`dependencies` isn't a table"))?;
+            for (name, spec) in &self.dependencies {
+                let value: toml::Value = toml::from_str(&format!("spec = {}", spec))
+                    .with_context(|| format!("error parsing the dependency on {}", name))?;
+                table.insert(name.clone(), value["spec"].clone());
+            }
+        }
+        let path = self.dir.join("Cargo.toml");
+        fs::write(&path, toml::to_string(&manifest)?)
+            .with_context(|| format!("error writing {}", path.display()))
+    }
+
+    /// Runs `cargo build`.
+    pub fn build(&self) -> Result<()> {
+        let status = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+            .current_dir(&self.dir)
+            .arg("build")
+            .output()
+            .context("error running cargo build")?
+            .status;
+        if !status.success() {
+            return Err(Error::msg(format!(
+                "error running cargo build in {}, exit code {:?}",
+                self.dir.display(),
+                status.code()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Runs `cargo metadata` for the project.
+    pub fn metadata(&self) -> Result<Metadata> {
+        MetadataCommand::new().current_dir(&self.dir).exec()
+    }
+
+    /// Gets every item `clear_target` would delete from the target directory.
+    pub fn target_removals(&self) -> Result<Vec<PathBuf>> {
+        let mut items = Vec::new();
+        clear_target(self.metadata()?, &home::cargo_home()?, &mut |path| {
+            items.push(PathBuf::from(path))
+        })?;
+        Ok(items)
+    }
+
+    /// Gets the metadata hashes removed for each crate other than the project itself. Crate names
+    /// use `_` rather than `-`, as they do in the target directory.
+    pub fn removed_crates(&self) -> Result<Removals> {
+        let own_name = self.name.replace('-', "_");
+        let mut crates = BTreeMap::<_, BTreeSet<_>>::new();
+        for item in self.target_removals()? {
+            if let Some(artifact) = parse_artifact_stem(item.file_name().unwrap_or_default()) {
+                if let (Some(hash), true) = (artifact.meta_hash, artifact.crate_name != own_name) {
+                    crates.entry(artifact.crate_name).or_default().insert(hash);
+                }
+            }
+        }
+        Ok(Removals(crates))
+    }
+
+    /// Checks that exactly the given crates are removed, each with the given number of metadata
+    /// hashes. Panics listing every difference otherwise.
+    pub fn assert_removals(&self, expected: &[(&str, usize)]) {
+        let removals = self.removed_crates().unwrap();
+        let msg = removals.differences(expected);
+        if !msg.is_empty() {
+            panic!("{}", msg);
+        }
+    }
+}
+
+/// Creates an empty directory named `name` in this crate's target directory for a test to work
+/// in, removing anything left by a previous run. Panics if it can't be created.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join(name);
+    if dir.symlink_metadata().is_ok() {
+        remove_tree(&dir).unwrap_or_else(|e| panic!("error removing {}: {}", dir.display(), e));
+    }
+    fs::create_dir_all(&dir).unwrap_or_else(|e| panic!("error creating {}: {}", dir.display(), e));
+    dir
+}
+
+/// The metadata hashes removed for each crate, keyed by crate name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Removals(pub BTreeMap<String, BTreeSet<String>>);
+impl Removals {
+    /// Describes how the removals differ from the expected crates and number of hashes, one
+    /// difference per line. Empty if they match.
+    pub fn differences(&self, expected: &[(&str, usize)]) -> String {
+        let mut msg = String::new();
+        let unexpected: Vec<_> = self
+            .0
+            .keys()
+            .filter(|name| !expected.iter().any(|(e, _)| e == name))
+            .map(String::as_str)
+            .collect();
+        if !unexpected.is_empty() {
+            writeln!(msg, "unexpected crate removals: {}", unexpected.join(", ")).unwrap();
+        }
+        for &(name, count) in expected {
+            let found = self.0.get(name).map_or(0, BTreeSet::len);
+            if found != count {
+                writeln!(
+                    msg,
+                    "wrong number of versions removed for {}, found {}, expected {}",
+                    name, found, count
+                )
+                .unwrap();
+            }
+        }
+        msg
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{scratch_dir, FixtureProject, Removals};
+    use std::fs;
+
+    #[test]
+    fn write_manifest() {
+        let dir = scratch_dir("test_util_test");
+        let mut project = FixtureProject::new("fixture", &dir);
+        project
+            .dependency("cfg-if", r#""=0.1.9""#)
+            .dependency(
+                "itoa",
+                r#"{ version = "=0.4.6", default-features = false }"#,
+            )
+            .create()
+            .unwrap();
+        let manifest: toml::Value =
+            toml::from_str(&fs::read_to_string(dir.join("Cargo.toml")).unwrap()).unwrap();
+        assert_eq!(manifest["package"]["name"].as_str(), Some("fixture"));
+        assert_eq!(manifest["dependencies"]["cfg-if"].as_str(), Some("=0.1.9"));
+        assert_eq!(
+            manifest["dependencies"]["itoa"]["default-features"].as_bool(),
+            Some(false)
+        );
+        assert!(dir.join("src/lib.rs").exists());
+
+        project.remove_dependency("itoa").write_manifest().unwrap();
+        let manifest: toml::Value =
+            toml::from_str(&fs::read_to_string(dir.join("Cargo.toml")).unwrap()).unwrap();
+        assert!(manifest["dependencies"].get("itoa").is_none());
+    }
+
+    #[test]
+    fn removal_differences() {
+        let mut removals = Removals::default();
+        removals
+            .0
+            .entry("cfg_if".into())
+            .or_default()
+            .insert("a".into());
+        removals
+            .0
+            .entry("log".into())
+            .or_default()
+            .insert("b".into());
+        assert_eq!(removals.differences(&[("cfg_if", 1), ("log", 1)]), "");
+        let msg = removals.differences(&[("cfg_if", 2)]);
+        assert!(msg.contains("unexpected crate removals: log"), "{}", msg);
+        assert!(msg.contains("for cfg_if, found 1, expected 2"), "{}", msg);
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io, ops::AddAssign, path::Path, time::SystemTime};
+
+/// The number of files and bytes used by a file or directory tree.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub files: u64,
+    pub bytes: u64,
+}
+impl AddAssign for DiskUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Formats a number of bytes using binary units, e.g. `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Measures a file or directory tree. Symlinks are counted as files and are not followed. Items
+/// which no longer exist count as zero.
+pub fn disk_usage(path: &Path) -> io::Result<DiskUsage> {
+    let meta = match path.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(DiskUsage::default()),
+        Err(e) => return Err(e),
+    };
+
+    if !meta.is_dir() {
+        return Ok(DiskUsage {
+            files: 1,
+            bytes: meta.len(),
+        });
+    }
+
+    let mut usage = DiskUsage::default();
+    for e in fs::read_dir(path)? {
+        usage += disk_usage(&e?.path())?;
+    }
+    Ok(usage)
+}
+
+/// Gets the most recent modification time of anything in a file or directory tree. Symlinks are
+/// not followed. Returns `None` if the item no longer exists.
+pub fn last_modified(path: &Path) -> io::Result<Option<SystemTime>> {
+    let meta = match path.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut time = meta.modified()?;
+    if meta.is_dir() {
+        for e in fs::read_dir(path)? {
+            if let Some(t) = last_modified(&e?.path())? {
+                time = time.max(t);
+            }
+        }
+    }
+    Ok(Some(time))
+}
+
+// Sets the modification time of a file or directory, for tests which need old items.
+#[cfg(test)]
+pub(crate) fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_WRITE_ATTRIBUTES
+        options.access_mode(0x100);
+        // FILE_FLAG_BACKUP_SEMANTICS, needed to open a directory.
+        options.custom_flags(0x0200_0000);
+    }
+    #[cfg(not(windows))]
+    options.read(true);
+    options.open(path)?.set_modified(time)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{disk_usage, last_modified, DiskUsage};
+    use crate::test_util::scratch_dir;
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn measure_tree() {
+        let dir = scratch_dir("measure_tree");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/x"), b"12345").unwrap();
+        fs::write(dir.join("a/b/y"), b"123").unwrap();
+
+        assert_eq!(disk_usage(&dir).unwrap(), DiskUsage { files: 2, bytes: 8 });
+        assert_eq!(
+            disk_usage(&dir.join("missing")).unwrap(),
+            DiskUsage::default()
+        );
+
+        let before = SystemTime::now() - Duration::from_secs(60);
+        assert!(last_modified(&dir).unwrap().unwrap() > before);
+        assert_eq!(last_modified(&dir.join("missing")).unwrap(), None);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{cmp::Ordering, fmt};
+
+// A dot separated part of a pre-release or build version. Numeric identifiers are ordered before
+// alphanumeric ones.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    // Leading zeros aren't allowed.
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) || (s.len() > 1 && s.starts_with('0'))
+    {
+        None
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_identifiers(s: &str, numeric: bool) -> Option<Vec<Identifier>> {
+    s.split('.')
+        .map(|id| {
+            if id.is_empty() || !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                None
+            } else if numeric && id.bytes().all(|b| b.is_ascii_digit()) {
+                parse_number(id).map(Identifier::Numeric)
+            } else {
+                Some(Identifier::Alphanumeric(id.into()))
+            }
+        })
+        .collect()
+}
+
+/// A semantic version, e.g. `1.0.0-rc.1+build.5`, ordered by semver precedence. Build metadata
+/// only breaks ties so the order is total.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Version {
+    numbers: [u64; 3],
+    pre: Vec<Identifier>,
+    build: Vec<Identifier>,
+    text: String,
+}
+impl Version {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (rest, build) = match s.split_once('+') {
+            Some((rest, build)) => (rest, parse_identifiers(build, false)?),
+            None => (s, Vec::new()),
+        };
+        let (numbers, pre) = match rest.split_once('-') {
+            Some((numbers, pre)) => (numbers, parse_identifiers(pre, true)?),
+            None => (rest, Vec::new()),
+        };
+        let mut parts = numbers.split('.');
+        let mut number = || parts.next().and_then(parse_number);
+        let numbers = [number()?, number()?, number()?];
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            numbers,
+            pre,
+            build,
+            text: s.into(),
+        })
+    }
+}
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.numbers
+            .cmp(&other.numbers)
+            // A pre-release comes before the release.
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre.cmp(&other.pre),
+            })
+            .then_with(|| self.build.cmp(&other.build))
+    }
+}
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Version;
+
+    #[test]
+    fn parse() {
+        for s in &[
+            "1.0.0",
+            "0.1.10",
+            "1.0.0-rc-1",
+            "1.0.0-alpha.1+build.5",
+            "1.0.0+001",
+        ] {
+            assert_eq!(Version::parse(s).unwrap().to_string(), *s);
+        }
+        for s in &[
+            "1.0",
+            "1.0.0.0",
+            "01.0.0",
+            "1.0.0-",
+            "1.0.0-01",
+            "1.0.0+",
+            "1.0.0-a..b",
+            "a.b.c",
+        ] {
+            assert!(Version::parse(s).is_none(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let versions: Vec<_> = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1-rc.1",
+            "1.2.0",
+            "1.10.0",
+        ]
+        .iter()
+        .map(|s| Version::parse(s).unwrap())
+        .collect();
+        for pair in versions.windows(2) {
+            assert!(pair[0] < pair[1], "{} < {}", pair[0], pair[1]);
+        }
+    }
+}
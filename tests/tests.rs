@@ -1,248 +1,437 @@
-use anyhow::Context;
+use cargo_ci_precache::test_util::FixtureProject;
 use std::{
-    collections::{HashMap, HashSet},
-    env,
-    fmt::Write,
+    ffi::OsStr,
     fs,
     path::{Path, PathBuf},
-    process::Command,
 };
 
-fn cargo_build(target: &Path) {
-    let res = Command::new(option_env!("CARGO").unwrap_or("cargo"))
-        .current_dir(&target)
-        .arg("build")
-        .output()
-        .context("error running cargo build")
-        .unwrap()
-        .status;
-    if !res.success() {
-        panic!("error running cargo build, exit code {:?}", res.code());
-    }
-}
-
-fn gather_items(target_dir: &Path) -> Vec<PathBuf> {
-    let meta = cargo_ci_precache::MetadataCommand::new()
-        .current_dir(target_dir)
-        .exec()
-        .unwrap();
-    let mut items = Vec::new();
-    cargo_ci_precache::clear_target(meta, &mut |path| items.push(PathBuf::from(path))).unwrap();
-    items
-}
-
-fn split_name_hash(s: &str) -> Option<(String, &str)> {
-    let mut iter = s.rsplitn(2, '-');
-    let (hash, name) = (iter.next()?, iter.next()?);
-    Some((
-        name.strip_prefix("lib").unwrap_or(name).replace("-", "_"),
-        hash,
-    ))
-}
-
-fn make_list<T: AsRef<str>>(mut iter: impl Iterator<Item = T>) -> String {
-    let mut res = String::new();
-    if let Some(s) = iter.next() {
-        res.push_str(s.as_ref());
-    }
-    for s in iter {
-        res.push_str(", ");
-        res.push_str(s.as_ref())
-    }
-    res
-}
-
-macro_rules! map {
-    ($(($key:expr, $value:expr)),*) => {{
-        #[allow(unused_mut)]
-        let mut map = HashMap::new();
-        $(map.insert($key, $value);)*
-        map
-    }}
-}
-
-struct Args {
-    /// Name of the test project from the manifest file
-    project_name: &'static str,
-    /// Subdirectory in the target directory to use for this test.
-    target_name: &'static str,
-    /// The manifest file for the first build.
-    manifest: &'static [u8],
-    /// The manifest file for the second build.
-    manifest_update: &'static [u8],
-    /// The set of crates which are expected to be removed after the second build, and the number
-    /// of unique metadata hashes expected for each one.
-    ///
-    /// Note that because the metadata hash changes with each version of rustc, these values can't
-    /// be hardcoded.
-    /// Also note that a crate with a build script will generate two different metadata hashes, one
-    /// for the build script, and one for the crate.
-    expected_removals: HashMap<&'static str, usize>,
-}
-impl Args {
-    fn run_test(&self) {
-        // Technically wrong, works for this crate.
-        let mut target_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        target_dir.push("target");
-        target_dir.push(self.target_name);
-        let target_dir = target_dir;
-        let manifest_path = target_dir.join("Cargo.toml");
-        let src_path = target_dir.join("src");
-        let config_path = target_dir.join(".cargo");
-
-        // Make sure the target folder is empty before starting the test.
-        rm_rf::ensure_removed(&target_dir).unwrap();
-
-        // Create the directory structure in the target folder.
-        fs::create_dir_all(&target_dir).unwrap();
-        fs::write(&manifest_path, self.manifest).unwrap();
-        fs::create_dir(&src_path).unwrap();
-        fs::write(src_path.join("lib.rs"), b"").unwrap();
-        fs::create_dir(&config_path).unwrap();
-        fs::write(
-            config_path.join("config"),
-            b"[build]\nincremental = false\n",
-        )
-        .unwrap();
-
-        // First build. There should be no items to remove other than the local crate.
-        cargo_build(&target_dir);
-        for item in gather_items(&target_dir) {
-            let name = item.file_name().unwrap().to_str().unwrap();
-            let name = name.strip_prefix("lib").unwrap_or(name);
-            if !(name.starts_with(self.project_name) || name == "examples" || name == "incremental")
-            {
-                panic!("unexpected crate removal on first build: {}", name);
-            }
-        }
-
-        // Update the manifest file and rebuild.
-        fs::write(&manifest_path, self.manifest_update).unwrap();
-        cargo_build(&target_dir);
-
-        let mut unexpected_removals = HashSet::<String>::new();
-        let mut removed_crates = HashMap::<_, HashSet<String>>::new();
-        for item in gather_items(&target_dir) {
-            let file_name = item.file_stem().unwrap().to_str().unwrap();
-            let (name, hash) = match split_name_hash(file_name) {
-                Some(x) => x,
+// Gets `{name}-{version}` for each registry package in a lockfile.
+fn locked_registry_packages(lockfile: &str) -> Vec<String> {
+    let mut packages = Vec::new();
+    for package in lockfile.split("[[package]]").skip(1) {
+        let mut name = None;
+        let mut version = None;
+        let mut registry = false;
+        for line in package.lines() {
+            let (key, value) = match line.split_once(" = ") {
+                Some((key, value)) => (key, value.trim_matches('"')),
                 None => continue,
             };
-
-            if self.expected_removals.contains_key(name.as_str()) {
-                removed_crates.entry(name).or_default().insert(hash.into());
-            } else if name != self.project_name {
-                unexpected_removals.insert(file_name.into());
+            match key {
+                "name" => name = Some(value),
+                "version" => version = Some(value),
+                "source" => registry = value.starts_with("registry+"),
+                _ => (),
             }
         }
-
-        // Build a useful error message.
-        let mut msg = String::new();
-
-        // List of all unexpected crates being removed.
-        if !unexpected_removals.is_empty() {
-            writeln!(
-                msg,
-                "unexpected crate removals: {}",
-                make_list(unexpected_removals.iter())
-            )
-            .unwrap();
+        if let (Some(name), Some(version), true) = (name, version, registry) {
+            packages.push(format!("{}-{}", name, version));
         }
+    }
+    packages
+}
 
-        // List of all crates which are expected to be removed, but there are the wrong number of
-        // metadata hashes for that crate being removed.
-        msg.extend(self.expected_removals.iter().filter_map(|(&name, &count)| {
-            match removed_crates.get(name) {
-                Some(hash) if hash.len() == count => None,
-                Some(hash) => Some(format!(
-                    "Wrong number of versions removed for {}, found {}, expected {}\n",
-                    name,
-                    hash.len(),
-                    count,
-                )),
-                None => Some(format!(
-                    "Wrong number of versions removed for {}, found {}, expected {}\n",
-                    name, count, 0,
-                )),
-            }
-        }));
+// Creates and builds a project in a subdirectory of the target directory.
+fn build_fixture(name: &str, target_name: &str, manifest: &str) -> FixtureProject {
+    // Technically wrong, works for this crate.
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join(target_name);
+    let mut project = FixtureProject::new(name, dir);
+    project.manifest(manifest).create().unwrap();
+    project.build().unwrap();
+    project
+}
 
-        // If the message is still empty then everything is good.
-        if !msg.is_empty() {
-            panic!("{}", msg)
-        }
-    }
+// Builds a project, then updates its manifest and rebuilds it. Only the given crates should be
+// removed, each with the given number of metadata hashes.
+fn run_update_test(
+    name: &str,
+    target_name: &str,
+    manifest: &str,
+    manifest_update: &str,
+    expected_removals: &[(&str, usize)],
+) {
+    // First build. There should be no items to remove other than the local crate.
+    let mut project = build_fixture(name, target_name, manifest);
+    project.assert_removals(&[]);
+
+    // Update the manifest file and rebuild.
+    project.manifest(manifest_update).write_manifest().unwrap();
+    project.build().unwrap();
+    project.assert_removals(expected_removals);
 }
 
-macro_rules! args {
+macro_rules! update_test {
     ($project:literal => $dir:literal {
         $($dep:literal $count:literal,)*
     }) => {
-        Args {
-            project_name: $project,
-            target_name: $dir,
-            manifest: include_bytes!(concat!($project, "/Cargo.toml")),
-            manifest_update: include_bytes!(concat!($project, "/Cargo.toml.update")),
-            expected_removals: map!($(($dep, $count)),*),
-        }
+        run_update_test(
+            $project,
+            $dir,
+            include_str!(concat!($project, "/Cargo.toml")),
+            include_str!(concat!($project, "/Cargo.toml.update")),
+            &[$(($dep, $count)),*],
+        )
     };
 }
 
 #[test]
 fn one_dep_update() {
-    args!("single_dep" => "single_dep" {
+    update_test!("single_dep" => "single_dep" {
         "cfg_if" 1,
     })
-    .run_test()
 }
 
 #[test]
 fn two_deps_one_update() {
-    args!("two_deps" => "two_deps" {
+    update_test!("two_deps" => "two_deps" {
         "cfg_if" 1,
     })
-    .run_test()
 }
 
 #[test]
 fn one_dep_feature_change() {
-    args!("feature_change" => "feature_change" {
+    update_test!("feature_change" => "feature_change" {
         "itoa" 1,
     })
-    .run_test()
 }
 
 #[test]
 fn nested_dep_propagate() {
-    args!("nested_dep" => "nested_dep" {
+    update_test!("nested_dep" => "nested_dep" {
         "cfg_if" 1,
         "log" 1,
     })
-    .run_test()
 }
 
 #[test]
 fn build_script_update() {
-    args!("build_script" => "build_script" {
+    update_test!("build_script" => "build_script" {
         "bitflags" 3,
     })
-    .run_test()
+}
+
+#[test]
+fn target_from_other_workspace() {
+    let project = build_fixture(
+        "single_dep",
+        "other_workspace",
+        include_str!("single_dep/Cargo.toml"),
+    );
+    let other = build_fixture(
+        "two_deps",
+        "other_workspace2",
+        include_str!("two_deps/Cargo.toml"),
+    );
+
+    let mut meta = project.metadata().unwrap();
+    assert!(cargo_ci_precache::check_target(&meta).unwrap().is_match());
+
+    meta.set_target_directory(other.dir().join("target"));
+    let evidence = cargo_ci_precache::check_target(&meta).unwrap();
+    assert!(evidence.units > 0);
+    assert!(!evidence.is_match(), "{}", evidence);
+}
+
+// A path dependency whose library includes a file generated by its build script has both package
+// and target relative paths in its dep-info. Only the sources of workspace members are found.
+#[test]
+fn member_sources_from_dep_info() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/member_sources");
+    let mut project = FixtureProject::new("outer", &dir);
+    project
+        .dependency("inner-dep", r#"{ path = "inner" }"#)
+        .create()
+        .unwrap();
+    let files = [
+        (
+            "inner/Cargo.toml",
+            "[package]\nname = \"inner-dep\"\nversion = \"0.1.0\"\nedition = \"2018\"\n",
+        ),
+        (
+            "inner/build.rs",
+            "fn main() {\n    let out = std::env::var(\"OUT_DIR\").unwrap();\n    \
+             std::fs::write(format!(\"{}/gen.rs\", out), \"pub fn f() {}\").unwrap();\n}\n",
+        ),
+        (
+            "inner/src/lib.rs",
+            "include!(concat!(env!(\"OUT_DIR\"), \"/gen.rs\"));\n",
+        ),
+    ];
+    for (path, contents) in &files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    project.build().unwrap();
+    let meta = project.metadata().unwrap();
+    let target_dir = dir.join("target/debug");
+
+    // Remove the `.d` files so only cargo's own dep-info files are used.
+    let mut dirs: Vec<_> = fs::read_dir(target_dir.join("build"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    dirs.push(target_dir.join("deps"));
+    for dir in &dirs {
+        for path in fs::read_dir(dir).unwrap() {
+            let path = path.unwrap().path();
+            if path.extension() == Some(OsStr::new("d")) {
+                fs::remove_file(path).unwrap();
+            }
+        }
+    }
+    let generated = fs::read_dir(target_dir.join(".fingerprint"))
+        .unwrap()
+        .filter_map(|unit| fs::read(unit.unwrap().path().join("dep-lib-inner_dep")).ok())
+        .any(|info| info.windows(6).any(|w| w == b"gen.rs"));
+    assert!(generated);
+
+    let evidence = cargo_ci_precache::check_target(&meta).unwrap();
+    assert!(evidence.is_match(), "{}", evidence);
+    assert_eq!(
+        evidence.member_sources,
+        [
+            dir.join("inner").join("build.rs"),
+            dir.join("inner").join("src/lib.rs"),
+            dir.join("src/lib.rs"),
+        ]
+    );
 }
 
 // Tests for the testing code.
 #[test]
 #[should_panic]
 fn one_dep_update_wrong_count() {
-    args!("single_dep" => "single_dep_wrong_count" {
+    update_test!("single_dep" => "single_dep_wrong_count" {
         "cfg_if" 2,
     })
-    .run_test()
 }
 
 #[test]
 #[should_panic]
 fn one_dep_update_missing_removal() {
-    args!("single_dep" => "single_dep_missing_removal" {
+    update_test!("single_dep" => "single_dep_missing_removal" {
+    })
+}
+
+// Checks none of the registry packages in the project's lockfile are in the list of items.
+fn assert_locked_kept(project_dir: &Path, items: &[PathBuf], suffix: &str) {
+    let lockfile = fs::read_to_string(project_dir.join("Cargo.lock")).unwrap();
+    let packages = locked_registry_packages(&lockfile);
+    assert!(!packages.is_empty());
+    for package in packages {
+        let name = format!("{}{}", package, suffix);
+        assert!(
+            !items.iter().any(|p| p.file_name() == Some(name.as_ref())),
+            "in use package listed for deletion: {}",
+            name
+        );
+    }
+}
+
+#[test]
+fn cargo_cache_keeps_locked() {
+    let project = build_fixture(
+        "two_deps",
+        "cargo_cache_keeps_locked",
+        include_str!("two_deps/Cargo.toml"),
+    );
+
+    let meta = project.metadata().unwrap();
+    let mut items = Vec::new();
+    cargo_ci_precache::clear_cargo_cache(meta, &Default::default(), &mut |path| {
+        items.push(PathBuf::from(path))
+    })
+    .unwrap();
+    assert_locked_kept(project.dir(), &items, ".crate");
+}
+
+#[test]
+fn cargo_cache_from_lockfiles() {
+    let project = build_fixture(
+        "two_deps",
+        "cargo_cache_from_lockfiles",
+        include_str!("two_deps/Cargo.toml"),
+    );
+    fs::write(project.dir().join("Cargo.lock.bad"), b"not a lockfile").unwrap();
+
+    let paths = cargo_ci_precache::find_lockfiles(project.dir().to_str().unwrap()).unwrap();
+    assert_eq!(paths, [project.dir().join("Cargo.lock")]);
+    let paths = [
+        project.dir().join("Cargo.lock"),
+        project.dir().join("Cargo.lock.bad"),
+    ];
+    let mut errors = 0;
+    let meta = cargo_ci_precache::metadata_from_lockfiles(&paths, &mut |_, _| errors += 1);
+    assert_eq!(errors, 1);
+
+    let mut items = Vec::new();
+    cargo_ci_precache::clear_cargo_cache(meta, &Default::default(), &mut |path| {
+        items.push(PathBuf::from(path))
+    })
+    .unwrap();
+    assert_locked_kept(project.dir(), &items, ".crate");
+}
+
+#[test]
+fn cargo_cache_keep_versions() {
+    let project = build_fixture(
+        "two_deps",
+        "cargo_cache_keep_versions",
+        include_str!("two_deps/Cargo.toml"),
+    );
+    let (cargo_home, files) = copy_locked_crates(project.dir());
+    // Unused versions of the locked crates, and of a crate which isn't used at all.
+    let registry = files[0].parent().unwrap();
+    let unused = [
+        "itoa-0.3.4",
+        "itoa-0.4.4",
+        "itoa-0.4.5",
+        "itoa-0.4.10",
+        "cfg-if-0.1.8",
+        "foo-1.0.0",
+    ];
+    for package in &unused {
+        fs::write(registry.join(format!("{}.crate", package)), "").unwrap();
+    }
+
+    let gather = |keep_versions| {
+        let meta = project.metadata().unwrap();
+        let options = cargo_ci_precache::CacheOptions {
+            keep_versions,
+            cargo_home: Some(cargo_home.clone()),
+            ..Default::default()
+        };
+        let mut items = Vec::new();
+        cargo_ci_precache::clear_cargo_cache(meta, &options, &mut |path| {
+            items.push(path.file_stem().unwrap().to_str().unwrap().to_owned());
+        })
+        .unwrap();
+        items.sort();
+        items
+    };
+    let mut all = unused.to_vec();
+    all.sort_unstable();
+    assert_eq!(gather(0), all);
+    // Versions are compared as semver, so 0.4.10 is the newest.
+    assert_eq!(gather(1), ["itoa-0.3.4", "itoa-0.4.4", "itoa-0.4.5"]);
+    assert_eq!(gather(2), ["itoa-0.3.4", "itoa-0.4.4"]);
+    assert_eq!(gather(4), Vec::<String>::new());
+}
+
+// Copies the `.crate` file of each registry package in the project's lockfile from the cargo home
+// into a new cargo home in the project's directory, so they can be modified. Returns the new cargo
+// home and the copied files.
+fn copy_locked_crates(project_dir: &Path) -> (PathBuf, Vec<PathBuf>) {
+    let lockfile = fs::read_to_string(project_dir.join("Cargo.lock")).unwrap();
+    let cache_dir = cargo_ci_precache::CacheOptions::default()
+        .cargo_home()
+        .unwrap()
+        .join("registry/cache");
+    let cargo_home = project_dir.join("cargo_home");
+    let _ = fs::remove_dir_all(&cargo_home);
+    let mut files = Vec::new();
+    for package in locked_registry_packages(&lockfile) {
+        let name = format!("{}.crate", package);
+        let registry = fs::read_dir(&cache_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|dir| dir.join(&name).is_file())
+            .unwrap();
+        let dir = cargo_home
+            .join("registry/cache")
+            .join(registry.file_name().unwrap());
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy(registry.join(&name), dir.join(&name)).unwrap();
+        files.push(dir.join(&name));
+    }
+    (cargo_home, files)
+}
+
+#[test]
+fn verify_checksums_keeps_valid() {
+    let project = build_fixture(
+        "two_deps",
+        "verify_checksums_keeps_valid",
+        include_str!("two_deps/Cargo.toml"),
+    );
+
+    let meta = project.metadata().unwrap();
+    let mut items = Vec::new();
+    cargo_ci_precache::verify_crate_checksums(&meta, &Default::default(), &mut |path| {
+        items.push(PathBuf::from(path))
+    })
+    .unwrap();
+    assert!(
+        items.is_empty(),
+        "valid files listed for deletion: {:?}",
+        items
+    );
+}
+
+#[test]
+fn verify_checksums_lists_corrupt() {
+    let project = build_fixture(
+        "two_deps",
+        "verify_checksums_lists_corrupt",
+        include_str!("two_deps/Cargo.toml"),
+    );
+    let (cargo_home, files) = copy_locked_crates(project.dir());
+    assert_eq!(files.len(), 2);
+    // One file is truncated, and the other has a byte changed.
+    let data = fs::read(&files[0]).unwrap();
+    fs::write(&files[0], &data[..data.len() / 2]).unwrap();
+    let mut data = fs::read(&files[1]).unwrap();
+    data[0] ^= 1;
+    fs::write(&files[1], data).unwrap();
+
+    let meta = project.metadata().unwrap();
+    let options = cargo_ci_precache::CacheOptions {
+        cargo_home: Some(cargo_home),
+        ..Default::default()
+    };
+    let mut items = Vec::new();
+    cargo_ci_precache::verify_crate_checksums(&meta, &options, &mut |path| {
+        items.push(PathBuf::from(path))
+    })
+    .unwrap();
+    items.sort();
+    let mut expected = files;
+    expected.sort();
+    assert_eq!(items, expected);
+}
+
+#[test]
+fn orphaned_src_keeps_locked() {
+    let project = build_fixture(
+        "two_deps",
+        "orphaned_src_keeps_locked",
+        include_str!("two_deps/Cargo.toml"),
+    );
+
+    let mut items = Vec::new();
+    cargo_ci_precache::clear_orphaned_src(&Default::default(), &mut |path| {
+        items.push(PathBuf::from(path))
+    })
+    .unwrap();
+    assert_locked_kept(project.dir(), &items, "");
+}
+
+#[test]
+fn registry_src_keeps_locked() {
+    let project = build_fixture(
+        "two_deps",
+        "registry_src_keeps_locked",
+        include_str!("two_deps/Cargo.toml"),
+    );
+
+    let meta = project.metadata().unwrap();
+    let mut items = Vec::new();
+    cargo_ci_precache::clear_registry_src(&meta, &Default::default(), &mut |path| {
+        items.push(PathBuf::from(path))
     })
-    .run_test()
+    .unwrap();
+    assert_locked_kept(project.dir(), &items, "");
 }